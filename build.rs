@@ -41,14 +41,18 @@ fn build() -> io::Result<()> {
         "src/proto/compact_formats.rs",
     )?;
 
-    // Build the gRPC types and client.
+    // Build the gRPC types, client and server. The server is only used by the mock lightwalletd.
     tonic_build::configure()
-        .build_server(false)
+        .build_server(true)
         .build_client(true)
         // .client_mod_attribute(
         //     "cash.z.wallet.sdk.rpc",
         //     r#"#[cfg(feature = "lightwalletd-tonic")]"#,
         // )
+        .server_mod_attribute(
+            "cash.z.wallet.sdk.rpc",
+            r#"#[cfg(feature = "mock-server")]"#,
+        )
         .type_attribute(".cash.z.wallet.sdk.rpc.ChainMetadata", "#[derive(Debug)]")
         .extern_path(
             ".cash.z.wallet.sdk.rpc.ChainMetadata",
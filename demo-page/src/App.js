@@ -1,4 +1,4 @@
-import { useState, useEffect } from "react";
+import { useState, useEffect, useRef } from "react";
 import "./App.css";
 import initWasm, { trial_decryption_bench, generate_proof_bench, sync_commitment_tree_bench, initThreadPool, BenchParams } from "../wasm-pkg/parallel";
 
@@ -23,6 +23,8 @@ export function App() {
     let [nThreads, setNThreads] = useState(navigator.hardwareConcurrency || 1);
     let [startBlock, setStartBlock] = useState(TIP - 36000);
     let [endBlock, setEndBlock] = useState(TIP);
+    let [syncToTip, setSyncToTip] = useState(true);
+    let [lastBlocks, setLastBlocks] = useState(36000);
     let [batchSize, setBatchSize] = useState(1000);
     let [network, setNetwork] = useState("mainnet");
     let [shieldedPool, setShieldedPool] = useState("both");
@@ -30,6 +32,9 @@ export function App() {
     let [spamFilterLimit, setSpamFilterLimit] = useState(50);
     let [witnesses, setWitnesses] = useState(10);
     let [proofGenerationSpends, setProofGenerationSpends] = useState(1);
+    let [report, setReport] = useState(null);
+    let [progress, setProgress] = useState(null);
+    let abortController = useRef(null);
 
     // Event Handlers
     function onNetworkUpdate(network) {
@@ -46,7 +51,7 @@ export function App() {
     }
 
     function current_params() {
-        return new BenchParams(
+        let params = new BenchParams(
             network,
             shieldedPool,
             lightwalletdProxy,
@@ -54,18 +59,27 @@ export function App() {
             endBlock,
             batchSize,
         );
+        abortController.current = new AbortController();
+        params = params
+            .withProgress(p => setProgress(p))
+            .withAbortSignal(abortController.current.signal);
+        // the range is resolved against the server's chain tip when the benchmark starts
+        return syncToTip ? params.withLastBlocks(lastBlocks) : params;
     }
 
     async function runTrialDecryption() {
-        await trial_decryption_bench(current_params(), spamFilterLimit);
+        const result = await trial_decryption_bench(current_params(), spamFilterLimit);
+        setReport(result.report.toJSON());
     }
 
     async function runTreeStateSync() {
-        sync_commitment_tree_bench(current_params());
+        const result = await sync_commitment_tree_bench(current_params());
+        setReport(result.toJSON());
     }
 
     async function runProofGeneration() {
-        generate_proof_bench(current_params(), proofGenerationSpends)
+        const result = generate_proof_bench(current_params(), proofGenerationSpends);
+        setReport(result.toJSON());
     }
 
     async function setupWorkers() {
@@ -77,7 +91,7 @@ export function App() {
         <div>
             <h1>ZCash Web - Browser Benchmarks</h1>
 
-            Open the browser console to see results of benchmarks
+            The progress of the running benchmark and the report from the last one run are shown at the bottom of the page. Open the browser console for more detail.
 
             <h2>Multi-thread Setup</h2>
                 <p>THIS MUST BE SET EXACTLY ONCE BEFORE ANY TESTS CAN BE RUN.</p>
@@ -110,13 +124,21 @@ export function App() {
                     lightwalletd URL (must have grpc-web proxy):
                     <input type="text" value={lightwalletdProxy} onChange={(e) => setLightwalletdProxy(e.target.value)} />
                 </label>
+                <label>
+                    Sync to chain tip:
+                    <input type="checkbox" checked={syncToTip} onChange={(e) => setSyncToTip(e.target.checked)} />
+                </label>
+                <label>
+                    Blocks before tip:
+                    <input type="number" value={lastBlocks} disabled={!syncToTip} onChange={(e) => setLastBlocks(Number(e.target.value))} />
+                </label>
                 <label>
                     Start Block:
-                    <input type="number" value={startBlock} onChange={(e) => setStartBlock(e.target.value)} />
+                    <input type="number" value={startBlock} disabled={syncToTip} onChange={(e) => setStartBlock(e.target.value)} />
                 </label>
                 <label>
                     End Block:
-                    <input type="number" value={endBlock} onChange={(e) => setEndBlock(e.target.value)} />
+                    <input type="number" value={endBlock} disabled={syncToTip} onChange={(e) => setEndBlock(e.target.value)} />
                 </label>
                 <span >{`${endBlock - startBlock} blocks. Approximately ${Math.round((endBlock - startBlock) * 1.2 / 60 / 24) } days on Zcash mainnet`}</span>
                 <br/>
@@ -160,6 +182,28 @@ export function App() {
                 </label>
                 <button onClick={runProofGeneration}>Start</button>
             </div>
+
+            <hr />
+
+            <div>
+                <h2>Progress</h2>
+                {progress ? (
+                    <p>
+                        <progress value={progress.completed} max={progress.total} />
+                        {` ${progress.phase}: ${progress.completed}/${progress.total} (${progress.percent.toFixed(1)}%)`}
+                        {progress.height ? ` at height ${progress.height}` : ""}
+                        {`, ${Math.round(progress.elapsed_ms / 1000)}s elapsed`}
+                    </p>
+                ) : "No benchmark is running"}
+                <button onClick={() => abortController.current?.abort()}>Cancel</button>
+            </div>
+
+            <hr />
+
+            <div>
+                <h2>Last Result</h2>
+                <pre>{report ? JSON.stringify(report, null, 2) : "No benchmark has been run yet"}</pre>
+            </div>
         </div>
     );
 }
@@ -2,6 +2,15 @@ use std::str::FromStr;
 
 /// Global params shared between the benchmarks
 use wasm_bindgen::prelude::*;
+use zcash_primitives::consensus;
+
+use crate::block_source::{check_network, BenchBlockSource, BlockSource, MemoryBlockSource};
+use crate::cancel::CancellationToken;
+use crate::console_log;
+use crate::error::SyncError;
+use crate::fixture::BlockFixture;
+use crate::progress::ProgressCallback;
+use crate::witness_placement::WitnessPlacement;
 
 #[wasm_bindgen(getter_with_clone)]
 #[derive(Clone, Debug)]
@@ -11,7 +20,36 @@ pub struct BenchParams {
     pub lightwalletd_url: String,
     pub start_block: u32,
     pub end_block: u32,
+    /// Number of blocks downloaded and processed together. Must be at least 1
     pub block_batch_size: u32,
+    /// Number of block range streams to download over concurrently. Must be at least 1
+    pub n_download_streams: u32,
+    /// How to reconnect when the block stream fails
+    pub retry_policy: RetryPolicy,
+    /// Number of batches that can be queued between each stage of the sync pipeline
+    pub pipeline_queue_depth: u32,
+    /// Checkpoint the commitment trees after every block whose height is a multiple of this. Must be
+    /// at least 1
+    pub checkpoint_interval: u32,
+    /// Where the tree-sync benchmark places the commitments it maintains witnesses for
+    #[wasm_bindgen(skip)]
+    pub witness_placement: WitnessPlacement,
+    /// If set, blocks and tree states are replayed from this decoded fixture instead of lightwalletd
+    #[wasm_bindgen(skip)]
+    pub fixture: Option<MemoryBlockSource>,
+    /// If set, `start_block` and `end_block` are replaced by this range once the tip is known
+    #[wasm_bindgen(skip)]
+    pub relative_range: Option<RelativeRange>,
+    /// If set, called with a progress object after each batch the benchmarks process
+    #[wasm_bindgen(skip)]
+    pub progress: Option<js_sys::Function>,
+    /// If set, the benchmarks stop early and return a partial report when it aborts
+    #[wasm_bindgen(skip)]
+    pub abort_signal: Option<web_sys::AbortSignal>,
+    /// If set, the wallet sync continues from the state saved under this key and saves its state
+    /// there when done
+    #[wasm_bindgen(skip)]
+    pub state_key: Option<String>,
 }
 
 #[wasm_bindgen]
@@ -32,6 +70,293 @@ impl BenchParams {
             start_block,
             end_block,
             block_batch_size,
+            n_download_streams: 1,
+            retry_policy: RetryPolicy::default(),
+            pipeline_queue_depth: 4,
+            checkpoint_interval: 1,
+            witness_placement: WitnessPlacement::Contiguous,
+            fixture: None,
+            relative_range: None,
+            progress: None,
+            abort_signal: None,
+            state_key: None,
+        }
+    }
+
+    /// Sync the most recent `n_blocks` up to the block source's tip instead of the fixed range
+    #[wasm_bindgen(js_name = withLastBlocks)]
+    pub fn with_last_blocks(mut self, n_blocks: u32) -> BenchParams {
+        self.relative_range = Some(RelativeRange::LastBlocks(n_blocks));
+        self
+    }
+
+    /// Sync from a wallet's birthday height up to the block source's tip instead of the fixed range
+    #[wasm_bindgen(js_name = withBirthday)]
+    pub fn with_birthday(mut self, birthday: u32) -> BenchParams {
+        self.relative_range = Some(RelativeRange::FromHeight(birthday));
+        self
+    }
+
+    /// Use a custom policy for reconnecting to the block source after transport errors
+    #[wasm_bindgen(js_name = withRetryPolicy)]
+    pub fn with_retry_policy(mut self, retry_policy: &RetryPolicy) -> BenchParams {
+        self.retry_policy = *retry_policy;
+        self
+    }
+
+    /// Witness commitments chosen uniformly at random from the range in the tree-sync benchmark,
+    /// instead of the first ones
+    #[wasm_bindgen(js_name = withUniformWitnesses)]
+    pub fn with_uniform_witnesses(mut self, seed: u64) -> BenchParams {
+        self.witness_placement = WitnessPlacement::Uniform { seed };
+        self
+    }
+
+    /// Witness `clusters` runs of adjacent commitments starting at random points in the range in the
+    /// tree-sync benchmark, instead of the first commitments
+    #[wasm_bindgen(js_name = withClusteredWitnesses)]
+    pub fn with_clustered_witnesses(mut self, clusters: u32, seed: u64) -> BenchParams {
+        self.witness_placement = WitnessPlacement::Clustered { clusters, seed };
+        self
+    }
+
+    /// Witness the commitments at the given positions of each tree in the tree-sync benchmark,
+    /// e.g. those of a real wallet's notes. The number of witnesses passed to the benchmark is ignored
+    #[wasm_bindgen(js_name = withWitnessPositions)]
+    pub fn with_witness_positions(mut self, positions: Vec<u64>) -> BenchParams {
+        self.witness_placement = WitnessPlacement::Positions(positions);
+        self
+    }
+
+    /// Replay blocks and tree states from a recorded fixture instead of querying lightwalletd.
+    /// Fails if the fixture can't be decoded
+    #[wasm_bindgen(js_name = withFixture)]
+    pub fn with_fixture(mut self, fixture: &BlockFixture) -> Result<BenchParams, JsError> {
+        self.fixture = Some(fixture.to_block_source().map_err(SyncError::from)?);
+        Ok(self)
+    }
+
+    /// Call `callback` with `{ phase, completed, total, remaining, percent, elapsed_ms, height }`
+    /// as the benchmarks make progress
+    #[wasm_bindgen(js_name = withProgress)]
+    pub fn with_progress(mut self, callback: js_sys::Function) -> BenchParams {
+        self.progress = Some(callback);
+        self
+    }
+
+    /// Stop the sync benchmarks when `signal` aborts. They return a report covering the blocks processed
+    /// up to that point, with `cancelled` set. Proving blocks the thread it runs on so can't be aborted
+    #[wasm_bindgen(js_name = withAbortSignal)]
+    pub fn with_abort_signal(mut self, signal: web_sys::AbortSignal) -> BenchParams {
+        self.abort_signal = Some(signal);
+        self
+    }
+
+    /// Continue the wallet sync from the state saved under `key` (IndexedDB in the browser), starting
+    /// at `start_block` if nothing has been saved yet, and save the state reached for the next run
+    #[wasm_bindgen(js_name = withSavedState)]
+    pub fn with_saved_state(mut self, key: String) -> BenchParams {
+        self.state_key = Some(key);
+        self
+    }
+}
+
+impl BenchParams {
+    /// How the benchmarks should download and process blocks for these params
+    pub fn sync_options(&self) -> SyncOptions {
+        SyncOptions {
+            n_download_streams: self.n_download_streams,
+            retry_policy: self.retry_policy,
+            queue_depth: self.pipeline_queue_depth,
+            network: (&self.network).into(),
+            progress: self.progress.clone().map(ProgressCallback::from_js),
+            cancel: self
+                .abort_signal
+                .as_ref()
+                .map(CancellationToken::from_abort_signal)
+                .unwrap_or_default(),
+            checkpoint_interval: self.checkpoint_interval,
+            witness_placement: self.witness_placement.clone(),
+        }
+    }
+
+    /// Check the block source is on the configured network, then resolve any relative range
+    /// against its latest block. Should be called before the params are used to sync.
+    pub async fn resolve(mut self) -> Result<BenchParams, SyncError> {
+        if self.block_batch_size == 0 {
+            return Err(SyncError::InvalidOptions(
+                "the block batch size must be at least 1".into(),
+            ));
+        }
+        self.sync_options().check()?;
+        let mut source = self.block_source()?;
+        check_network(&mut source, &self.network).await?;
+        if let Some(range) = self.relative_range.take() {
+            let tip = source.latest_height().await?;
+            let (start_block, end_block) = range.resolve(tip)?;
+            console_log!(
+                "Resolved {:?} against tip {} to blocks [{}, {}]",
+                range,
+                tip,
+                start_block,
+                end_block
+            );
+            self.start_block = start_block;
+            self.end_block = end_block;
+        }
+        Ok(self)
+    }
+
+    /// The source the benchmarks should retrieve blocks from for these params. Fails if there is no
+    /// fixture and the lightwalletd URL is invalid
+    pub fn block_source(&self) -> Result<BenchBlockSource, SyncError> {
+        Ok(match &self.fixture {
+            Some(fixture) => BenchBlockSource::Replay(fixture.clone()),
+            None => BenchBlockSource::Lightwalletd(crate::new_compact_streamer_client(
+                &self.lightwalletd_url,
+            )?),
+        })
+    }
+}
+
+/// A block range given relative to the chain tip
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RelativeRange {
+    /// The most recent blocks, ending at the tip
+    LastBlocks(u32),
+    /// From the given height (e.g. a wallet birthday) to the tip
+    FromHeight(u32),
+}
+
+impl RelativeRange {
+    /// The inclusive (start, end) heights of the range for the given tip.
+    /// Fails if the range would hold no blocks.
+    pub fn resolve(&self, tip: u32) -> Result<(u32, u32), SyncError> {
+        match *self {
+            RelativeRange::LastBlocks(0) => Err(SyncError::InvalidOptions(
+                "the range must hold at least 1 block".into(),
+            )),
+            RelativeRange::LastBlocks(n_blocks) => Ok((tip.saturating_sub(n_blocks) + 1, tip)),
+            RelativeRange::FromHeight(height) if height > tip => {
+                Err(SyncError::InvalidOptions(format!(
+                    "the range starts at block {}, after the tip {}",
+                    height, tip
+                )))
+            }
+            RelativeRange::FromHeight(height) => Ok((height, tip)),
+        }
+    }
+}
+
+/// How blocks are downloaded from a block source and passed through the sync pipeline
+#[derive(Clone, Debug)]
+pub struct SyncOptions {
+    /// Number of sub-ranges to stream concurrently. 1 streams the whole range over a single connection.
+    /// Must be at least 1
+    pub n_download_streams: u32,
+    pub retry_policy: RetryPolicy,
+    /// Number of batches that can be queued between each stage of the sync pipeline
+    pub queue_depth: u32,
+    /// Consensus parameters for the network being synced. Determine the upgrade activation heights
+    pub network: consensus::Network,
+    /// Called after each batch is processed
+    pub progress: Option<ProgressCallback>,
+    /// Checked between batches and during trial decryption to stop the sync early
+    pub cancel: CancellationToken,
+    /// The commitment trees are checkpointed after every block whose height is a multiple of this,
+    /// and after the last block of each batch, so they can be rewound on a reorg
+    pub checkpoint_interval: u32,
+    /// Where the commitment tree sync places the commitments it maintains witnesses for
+    pub witness_placement: WitnessPlacement,
+}
+
+impl SyncOptions {
+    /// Fail if the options can't be synced with
+    pub fn check(&self) -> Result<(), SyncError> {
+        if self.n_download_streams == 0 {
+            return Err(SyncError::InvalidOptions(
+                "at least 1 download stream is needed".into(),
+            ));
+        }
+        if self.checkpoint_interval == 0 {
+            return Err(SyncError::InvalidOptions(
+                "the checkpoint interval must be at least 1".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            n_download_streams: 1,
+            retry_policy: RetryPolicy::default(),
+            queue_depth: 4,
+            network: consensus::Network::MainNetwork,
+            progress: None,
+            cancel: CancellationToken::default(),
+            checkpoint_interval: 1,
+            witness_placement: WitnessPlacement::default(),
+        }
+    }
+}
+
+/// Exponential backoff used when reconnecting to a block source after a transport error
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Consecutive failed attempts allowed before giving up. Reset whenever a block is received
+    pub max_retries: u32,
+    pub initial_backoff_ms: u32,
+    pub max_backoff_ms: u32,
+    pub backoff_multiplier: f64,
+}
+
+#[wasm_bindgen]
+impl RetryPolicy {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        max_retries: u32,
+        initial_backoff_ms: u32,
+        max_backoff_ms: u32,
+        backoff_multiplier: f64,
+    ) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_backoff_ms,
+            max_backoff_ms,
+            backoff_multiplier,
+        }
+    }
+
+    /// A policy that fails on the first error
+    pub fn never() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before making the given retry attempt (starting from 1)
+    pub fn backoff_ms(&self, attempt: u32) -> u32 {
+        let backoff = self.initial_backoff_ms as f64
+            * self
+                .backoff_multiplier
+                .powi(attempt.saturating_sub(1) as i32);
+        backoff.min(self.max_backoff_ms as f64) as u32
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            backoff_multiplier: 2.0,
         }
     }
 }
@@ -67,6 +392,37 @@ impl ShieldedPool {
             ShieldedPool::Both => true,
         }
     }
+
+    /// The first height at which the pool (or for both, the earliest of them) exists on the network
+    pub fn activation_height(&self, params: &impl consensus::Parameters) -> u32 {
+        let upgrade = match self {
+            ShieldedPool::Orchard => consensus::NetworkUpgrade::Nu5,
+            ShieldedPool::Sapling | ShieldedPool::Both => consensus::NetworkUpgrade::Sapling,
+        };
+        params
+            .activation_height(upgrade)
+            .expect("Sapling and NU5 are active on mainnet and testnet")
+            .into()
+    }
+}
+
+impl From<&Network> for consensus::Network {
+    fn from(network: &Network) -> Self {
+        match network {
+            Network::Mainnet => consensus::Network::MainNetwork,
+            Network::Testnet => consensus::Network::TestNetwork,
+        }
+    }
+}
+
+impl Network {
+    /// The chain name lightwalletd reports for this network
+    pub fn chain_name(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "main",
+            Network::Testnet => "test",
+        }
+    }
 }
 
 impl FromStr for Network {
@@ -0,0 +1,128 @@
+/**
+ * A structured record of a benchmark run so results can be read by the demo page, the test harness
+ * and the CLI instead of being scraped from the console.
+ *
+ * Pipelined phases overlap, so the phase timings can add up to more than the total.
+ */
+use wasm_bindgen::prelude::*;
+
+use crate::block_range_stream::BatchContents;
+use crate::cancel::CancellationToken;
+use crate::pipeline::PipelineStats;
+use crate::platform;
+
+/// Time spent in each phase of a benchmark in milliseconds. Phases a benchmark doesn't have are 0.
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct PhaseTimings {
+    /// Decoding viewing keys or building the proving key
+    pub key_setup_ms: f64,
+    pub download_ms: f64,
+    /// Converting the downloaded blocks into actions and outputs ready to process
+    pub decode_ms: f64,
+    pub decrypt_ms: f64,
+    pub tree_insert_ms: f64,
+    /// Computing the tree root at the end of the range and comparing it to lightwalletd's
+    pub root_check_ms: f64,
+    /// Computing the Merkle path of every marked commitment and checking it against lightwalletd's anchor
+    pub witness_ms: f64,
+    /// Truncating the commitment trees back to a checkpoint before a simulated reorg
+    pub rewind_ms: f64,
+    /// Inserting the blocks after the fork point again once the trees are rewound
+    pub reapply_ms: f64,
+    pub prove_ms: f64,
+    /// Wall clock time of the whole benchmark
+    pub total_ms: f64,
+}
+
+/// Counts for one shielded pool
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct PoolCounts {
+    /// Actions or outputs processed, excluding those in skipped transactions
+    pub outputs: u64,
+    /// Notes that decrypted with the wallet's keys
+    pub notes: u64,
+    /// Transactions skipped by the spam filter
+    pub skipped_spam_txs: u64,
+    /// Witnesses computed for marked commitments and checked against the anchor
+    pub witnesses: u64,
+}
+
+/// The result of a benchmark. In JS call `toJSON()` (or pass it to `JSON.stringify`) to get a plain object.
+#[wasm_bindgen]
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct BenchReport {
+    #[wasm_bindgen(skip)]
+    pub phases: PhaseTimings,
+    #[wasm_bindgen(skip)]
+    pub orchard: PoolCounts,
+    #[wasm_bindgen(skip)]
+    pub sapling: PoolCounts,
+    pub blocks: u64,
+    /// Protobuf encoded size of the blocks received
+    pub bytes_received: u64,
+    /// Height of the last block fully processed
+    #[wasm_bindgen(skip)]
+    pub last_height: Option<u32>,
+    /// Whether the benchmark was cancelled before the end of its range.
+    /// If so, the report only covers the blocks up to `last_height`
+    pub cancelled: bool,
+    /// Number of chain reorganisations the sync rolled back from. The blocks after each fork point
+    /// are counted again when they are reprocessed
+    pub reorgs: u32,
+    /// Highest memory use seen, in bytes. In the browser this is the size of the wasm memory,
+    /// which never shrinks. Natively it is the peak resident set size where the OS reports it.
+    #[wasm_bindgen(skip)]
+    pub peak_memory_bytes: Option<u64>,
+    /// Per stage stats of the sync pipeline, for the benchmarks that use one
+    #[wasm_bindgen(skip)]
+    pub pipeline: Option<PipelineStats>,
+}
+
+#[wasm_bindgen]
+impl BenchReport {
+    #[wasm_bindgen(js_name = toJSON)]
+    pub fn to_json(&self) -> Result<JsValue, JsError> {
+        Ok(serde_wasm_bindgen::to_value(self)?)
+    }
+}
+
+impl BenchReport {
+    /// Count the blocks and outputs in a batch as it is processed
+    pub(crate) fn add_batch(&mut self, contents: &BatchContents) {
+        self.blocks += contents.blocks as u64;
+        self.bytes_received += contents.bytes;
+        if contents.blocks > 0 {
+            self.last_height = Some(contents.end_height);
+        }
+        self.orchard.outputs += contents.actions.len() as u64;
+        self.sapling.outputs += contents.outputs.len() as u64;
+        self.orchard.skipped_spam_txs += contents.skipped_orchard_txs as u64;
+        self.sapling.skipped_spam_txs += contents.skipped_sapling_txs as u64;
+    }
+
+    /// Mark the report as cancelled if the token was cancelled before the sync reached `end_height`
+    pub(crate) fn check_cancelled(&mut self, cancel: &CancellationToken, end_height: u32) {
+        self.cancelled = cancel.is_cancelled() && self.last_height < Some(end_height);
+    }
+
+    /// Take the download, decode, decrypt and insert timings from the stages of the same name
+    pub(crate) fn add_pipeline(&mut self, pipeline: PipelineStats) {
+        for stage in &pipeline.stages {
+            let phase = match stage.name {
+                "download" => &mut self.phases.download_ms,
+                "convert" => &mut self.phases.decode_ms,
+                "decrypt" => &mut self.phases.decrypt_ms,
+                "insert" => &mut self.phases.tree_insert_ms,
+                _ => continue,
+            };
+            *phase += stage.busy_ms;
+        }
+        self.pipeline = Some(pipeline);
+    }
+
+    /// Record the total time since `start_ms` and the memory high water mark
+    pub(crate) fn finish(&mut self, start_ms: f64) {
+        self.phases.total_ms = platform::now_ms() - start_ms;
+        self.peak_memory_bytes = platform::peak_memory_bytes();
+    }
+}
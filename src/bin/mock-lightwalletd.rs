@@ -0,0 +1,36 @@
+//! Serve a fixture directory as a grpc-web lightwalletd so the benchmarks can run offline.
+//!
+//! Usage: mock-lightwalletd <fixture-dir> [listen-addr] [<fork-dir> <fork-after-blocks>]
+//!
+//! The fixture directory must contain `blocks.bin` and `tree_states.bin` as written by the
+//! block recorder, and may contain `sapling_subtree_roots.bin` and `orchard_subtree_roots.bin`.
+//!
+//! If a fork fixture is given, the mock switches to serving it once it has streamed
+//! `fork-after-blocks` blocks, simulating a reorg onto that chain.
+
+use std::net::SocketAddr;
+
+use zcash_wasm_benchmark::{serve, FixtureStore};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:9067";
+const USAGE: &str =
+    "Usage: mock-lightwalletd <fixture-dir> [listen-addr] [<fork-dir> <fork-after-blocks>]";
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let dir = args.next().ok_or_else(|| anyhow::anyhow!(USAGE))?;
+    let addr: SocketAddr = args.next().as_deref().unwrap_or(DEFAULT_ADDR).parse()?;
+
+    let mut store = FixtureStore::open(&dir)?;
+    if let Some(fork_dir) = args.next() {
+        let after_blocks = args.next().ok_or_else(|| anyhow::anyhow!(USAGE))?.parse()?;
+        store = store.with_fork(FixtureStore::open(&fork_dir)?, after_blocks);
+        println!(
+            "Switching to fork {} after serving {} blocks",
+            fork_dir, after_blocks
+        );
+    }
+    println!("Serving fixture {} on http://{}", dir, addr);
+    serve(store, addr).await
+}
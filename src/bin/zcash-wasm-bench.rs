@@ -0,0 +1,497 @@
+//! Run the benchmarks natively to get a baseline to compare the browser numbers against.
+//!
+//! Usage: zcash-wasm-bench <command> [options]
+//!
+//! Commands:
+//!   download            Download the blocks in the range and discard them
+//!   trial-decryption    Trial decrypt every output in the range
+//!   tree-sync           Sync the note commitment trees over the range
+//!   wallet-sync         Trial decrypt and sync the trees in a single pass
+//!   spend-before-sync   Trial decrypt, then build the trees from subtree roots and the shards
+//!                       holding notes
+//!   reorg               Sync the trees, rewind them by --depth blocks and reapply the blocks after
+//!                       the fork point
+//!   proving             Prove an Orchard bundle
+//!   record <dir>        Save the blocks and tree states in the range as a fixture directory
+//!
+//! Options:
+//!   --network <mainnet|testnet>        (default mainnet)
+//!   --pool <orchard|sapling|both>      (default orchard)
+//!   --url <url>                        lightwalletd gRPC endpoint (default http://127.0.0.1:9067)
+//!   --fixture <dir>                    replay a fixture directory instead of using lightwalletd
+//!   --start <height> --end <height>    block range to sync
+//!   --last <n>                         sync the last n blocks up to the tip instead
+//!   --birthday <height>                sync from the given height up to the tip instead
+//!   --batch-size <n>                   (default 10000)
+//!   --streams <n>                      number of concurrent download streams (default 1)
+//!   --queue-depth <n>                  batches queued between pipeline stages (default 4)
+//!   --threads <n>                      size of the rayon pool (default number of cores)
+//!   --spam-filter <n>                  skip txs with more outputs than this (default no limit)
+//!   --witnesses <n>                    witnesses to maintain during tree-sync (default 0)
+//!   --witness-placement <placement>    where tree-sync places them: contiguous, uniform,
+//!                                      clustered:<runs> or positions:<file> to witness the tree
+//!                                      positions listed in the file (default contiguous)
+//!   --witness-seed <n>                 seed for the uniform and clustered placements (default 0)
+//!   --checkpoint-interval <n>          checkpoint the trees every n blocks (default 1)
+//!   --depth <n>                        blocks to rewind in reorg (default 10)
+//!   --spends <n>                       spends to prove (default 1)
+//!   --view-key <key>                   UFVK, Sapling extended FVK or hex Orchard FVK to decrypt with
+//!   --state <file>                     wallet-sync continues from the state saved in this file, if
+//!                                      any, and saves the state it reaches there
+//!
+//! Only plaintext gRPC is supported, so point `--url` at a local lightwalletd or the mock
+//! lightwalletd rather than a public TLS endpoint.
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::BufWriter;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+
+    use anyhow::{anyhow, bail, Context};
+    use zcash_wasm_benchmark::*;
+
+    const USAGE: &str = "Usage: zcash-wasm-bench <download|trial-decryption|tree-sync|wallet-sync|spend-before-sync|reorg|proving|record <dir>> [options]";
+    const DEFAULT_URL: &str = "http://127.0.0.1:9067";
+
+    enum Command {
+        Download,
+        TrialDecryption {
+            spam_filter_limit: u32,
+            view_key: Option<String>,
+        },
+        TreeSync {
+            n_witnesses: u32,
+        },
+        WalletSync {
+            view_key: Option<String>,
+            state_file: Option<PathBuf>,
+        },
+        SpendBeforeSync {
+            view_key: Option<String>,
+        },
+        Reorg {
+            depth: u32,
+        },
+        Proving {
+            n_spends: u32,
+        },
+        Record {
+            dir: PathBuf,
+        },
+    }
+
+    /// The `--name value` options given on the command line. Each is removed as it is read
+    /// so any left over once the command has been parsed are unknown.
+    struct Options(HashMap<String, String>);
+
+    impl Options {
+        fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+            let mut options = HashMap::new();
+            while let Some(arg) = args.next() {
+                let name = arg
+                    .strip_prefix("--")
+                    .ok_or_else(|| anyhow!("Unexpected argument {}\n{}", arg, USAGE))?;
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow!("Missing value for --{}", name))?;
+                options.insert(name.to_string(), value);
+            }
+            Ok(Options(options))
+        }
+
+        fn take(&mut self, name: &str) -> Option<String> {
+            self.0.remove(name)
+        }
+
+        fn take_parsed<T: FromStr>(&mut self, name: &str) -> anyhow::Result<Option<T>>
+        where
+            T::Err: std::fmt::Display,
+        {
+            self.take(name)
+                .map(|v| {
+                    v.parse()
+                        .map_err(|e| anyhow!("Invalid value for --{}: {}", name, e))
+                })
+                .transpose()
+        }
+
+        fn take_or<T: FromStr>(&mut self, name: &str, default: T) -> anyhow::Result<T>
+        where
+            T::Err: std::fmt::Display,
+        {
+            Ok(self.take_parsed(name)?.unwrap_or(default))
+        }
+
+        /// Build the same params the web page would from the range and sync options
+        fn bench_params(&mut self) -> anyhow::Result<BenchParams> {
+            let network = self.take("network").unwrap_or_else(|| "mainnet".into());
+            let pool = self.take("pool").unwrap_or_else(|| "orchard".into());
+            Network::from_str(&network).map_err(|e| anyhow!(e))?;
+            ShieldedPool::from_str(&pool).map_err(|e| anyhow!(e))?;
+
+            let mut params = BenchParams::new(
+                network,
+                pool,
+                self.take("url").unwrap_or_else(|| DEFAULT_URL.into()),
+                self.take_or("start", 0)?,
+                self.take_or("end", 0)?,
+                self.take_or("batch-size", 10_000)?,
+            );
+            params.n_download_streams = self.take_or("streams", 1)?;
+            params.pipeline_queue_depth = self.take_or("queue-depth", 4)?;
+            params.checkpoint_interval = self.take_or("checkpoint-interval", 1)?;
+            if params.checkpoint_interval == 0 {
+                bail!("--checkpoint-interval must be at least 1");
+            }
+            params.witness_placement = self.witness_placement()?;
+            if let Some(dir) = self.take("fixture") {
+                let fixture = BlockFixture::read_dir(&dir)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|fixture| fixture.to_block_source())
+                    .with_context(|| format!("Failed to read fixture {}", dir))?;
+                params.fixture = Some(fixture);
+            }
+            if let Some(n) = self.take_parsed("last")? {
+                params = params.with_last_blocks(n);
+            }
+            if let Some(birthday) = self.take_parsed("birthday")? {
+                params = params.with_birthday(birthday);
+            }
+            if params.relative_range.is_none() && params.start_block > params.end_block {
+                bail!("--start must not be after --end");
+            }
+            Ok(params)
+        }
+
+        fn witness_placement(&mut self) -> anyhow::Result<WitnessPlacement> {
+            let seed = self.take_or("witness-seed", 0)?;
+            let placement = self
+                .take("witness-placement")
+                .unwrap_or_else(|| "contiguous".into());
+            let (kind, arg) = placement
+                .split_once(':')
+                .map_or((placement.as_str(), None), |(kind, arg)| (kind, Some(arg)));
+            Ok(match (kind, arg) {
+                ("contiguous", None) => WitnessPlacement::Contiguous,
+                ("uniform", None) => WitnessPlacement::Uniform { seed },
+                ("clustered", Some(clusters)) => WitnessPlacement::Clustered {
+                    clusters: clusters
+                        .parse()
+                        .map_err(|e| anyhow!("Invalid value for --witness-placement: {}", e))?,
+                    seed,
+                },
+                ("positions", Some(file)) => WitnessPlacement::Positions(
+                    std::fs::read_to_string(file)
+                        .with_context(|| format!("Failed to read witness positions {}", file))?
+                        .split(|c: char| c.is_whitespace() || c == ',')
+                        .filter(|p| !p.is_empty())
+                        .map(|p| p.parse())
+                        .collect::<Result<_, _>>()
+                        .with_context(|| format!("Invalid witness position in {}", file))?,
+                ),
+                _ => bail!("Invalid value for --witness-placement: {}", placement),
+            })
+        }
+    }
+
+    fn parse_args(
+        mut args: impl Iterator<Item = String>,
+    ) -> anyhow::Result<(Command, BenchParams, Option<usize>)> {
+        let command = args.next().ok_or_else(|| anyhow!(USAGE))?;
+        let record_dir = if command == "record" {
+            Some(args.next().ok_or_else(|| anyhow!(USAGE))?)
+        } else {
+            None
+        };
+        let mut options = Options::parse(args)?;
+        let threads = options.take_parsed("threads")?;
+        let command = match command.as_str() {
+            "download" => Command::Download,
+            "trial-decryption" => Command::TrialDecryption {
+                spam_filter_limit: options.take_or("spam-filter", u32::MAX)?,
+                view_key: options.take("view-key"),
+            },
+            "tree-sync" => Command::TreeSync {
+                n_witnesses: options.take_or("witnesses", 0)?,
+            },
+            "wallet-sync" => Command::WalletSync {
+                view_key: options.take("view-key"),
+                state_file: options.take("state").map(PathBuf::from),
+            },
+            "spend-before-sync" => Command::SpendBeforeSync {
+                view_key: options.take("view-key"),
+            },
+            "reorg" => Command::Reorg {
+                depth: options.take_or("depth", 10)?,
+            },
+            "proving" => Command::Proving {
+                n_spends: options.take_or("spends", 1)?,
+            },
+            "record" => Command::Record {
+                dir: record_dir.unwrap().into(),
+            },
+            command => bail!("Unknown command {}\n{}", command, USAGE),
+        };
+        let params = options.bench_params()?;
+        if let Some(name) = options.0.keys().next() {
+            bail!("Unknown option --{}", name);
+        }
+        Ok((command, params, threads))
+    }
+
+    fn wallet_keys(network: &Network, view_key: Option<String>) -> anyhow::Result<WalletKeys> {
+        match view_key {
+            Some(key) => WalletKeys::decode(network, &key),
+            None => Ok(WalletKeys::dummy(network)),
+        }
+    }
+
+    /// A store for the directory holding `path`, and the key of the file within it
+    fn state_file_store(path: &Path) -> anyhow::Result<(FileStateStore, String)> {
+        let key = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Invalid state file {}", path.display()))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Ok((FileStateStore::new(dir), key.to_string()))
+    }
+
+    pub fn main() -> anyhow::Result<()> {
+        let (command, params, threads) = parse_args(std::env::args().skip(1))?;
+        if let Some(threads) = threads {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build_global()?;
+        }
+        println!("Using {} rayon threads", rayon::current_num_threads());
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(run(command, params))
+    }
+
+    async fn run(command: Command, params: BenchParams) -> anyhow::Result<()> {
+        if let Command::Proving { n_spends } = command {
+            print_report(&prove_orchard_bundle(n_spends, Some(print_progress())));
+            return Ok(());
+        }
+
+        let params = params.resolve().await?;
+        let source = params.block_source()?;
+        let options = SyncOptions {
+            progress: Some(print_progress()),
+            ..params.sync_options()
+        };
+        let report = match command {
+            Command::Download => {
+                download_blocks(
+                    source,
+                    params.start_block,
+                    params.end_block,
+                    params.block_batch_size,
+                    options,
+                )
+                .await?
+            }
+            Command::TrialDecryption {
+                spam_filter_limit,
+                view_key,
+            } => {
+                let summary = trial_decrypt_range(
+                    source,
+                    wallet_keys(&params.network, view_key)?,
+                    params.pool,
+                    params.start_block,
+                    params.end_block,
+                    params.block_batch_size,
+                    spam_filter_limit,
+                    options,
+                )
+                .await?;
+                println!("Spendable balance: {}", summary.spendable_balance);
+                summary.report
+            }
+            Command::TreeSync { n_witnesses } => {
+                let summary = sync_commitment_tree(
+                    source,
+                    params.pool,
+                    params.start_block,
+                    params.end_block,
+                    params.block_batch_size,
+                    n_witnesses,
+                    options,
+                )
+                .await?;
+                println!(
+                    "Tree sizes: {} Orchard, {} Sapling",
+                    summary.orchard_tree_size, summary.sapling_tree_size
+                );
+                summary.report
+            }
+            Command::WalletSync {
+                view_key,
+                state_file,
+            } => {
+                let keys = wallet_keys(&params.network, view_key)?;
+                let summary = match state_file {
+                    Some(path) => {
+                        let (mut store, key) = state_file_store(&path)?;
+                        let mut source = source;
+                        let bootstrap_height = params
+                            .start_block
+                            .checked_sub(1)
+                            .ok_or_else(|| anyhow!("--start must be after the genesis block"))?;
+                        let state = load_or_bootstrap(
+                            &mut store,
+                            &key,
+                            &mut source,
+                            params.pool,
+                            bootstrap_height,
+                        )
+                        .await?;
+                        let summary = resume_wallet_sync(
+                            source,
+                            keys,
+                            state,
+                            params.end_block,
+                            params.block_batch_size,
+                            options,
+                        )
+                        .await?;
+                        store.save(&key, &summary.state.to_bytes()).await?;
+                        println!(
+                            "Saved state at height {} to {}",
+                            summary.state.last_height,
+                            path.display()
+                        );
+                        summary
+                    }
+                    None => {
+                        wallet_sync_range(
+                            source,
+                            keys,
+                            params.pool,
+                            params.start_block,
+                            params.end_block,
+                            params.block_batch_size,
+                            options,
+                        )
+                        .await?
+                    }
+                };
+                println!("Spendable balance: {}", summary.spendable_balance);
+                summary.report
+            }
+            Command::SpendBeforeSync { view_key } => {
+                let summary = spend_before_sync(
+                    source,
+                    wallet_keys(&params.network, view_key)?,
+                    params.pool,
+                    params.start_block,
+                    params.end_block,
+                    params.block_batch_size,
+                    options,
+                )
+                .await?;
+                println!(
+                    "Filled Orchard shards {:?} and Sapling shards {:?} from {} blocks",
+                    summary.orchard_shards_filled,
+                    summary.sapling_shards_filled,
+                    summary.fill_blocks
+                );
+                println!("Spendable balance: {}", summary.spendable_balance);
+                summary.report
+            }
+            Command::Reorg { depth } => {
+                let summary = sync_with_reorg(
+                    source,
+                    params.pool,
+                    params.start_block,
+                    params.end_block,
+                    params.block_batch_size,
+                    depth,
+                    options,
+                )
+                .await?;
+                println!(
+                    "Rewound to block {} in {:.1}ms and reapplied in {:.1}ms",
+                    summary.fork_height,
+                    summary.report.phases.rewind_ms,
+                    summary.report.phases.reapply_ms
+                );
+                summary.report
+            }
+            Command::Record { dir } => {
+                let start = now_ms();
+                std::fs::create_dir_all(&dir)?;
+                record_block_range(
+                    source,
+                    params.start_block,
+                    params.end_block,
+                    BufWriter::new(File::create(dir.join(BLOCKS_FILE))?),
+                    BufWriter::new(File::create(dir.join(TREE_STATES_FILE))?),
+                )
+                .await?;
+                println!(
+                    "Recorded fixture to {} in {}ms",
+                    dir.display(),
+                    (now_ms() - start).round()
+                );
+                return Ok(());
+            }
+            Command::Proving { .. } => unreachable!(),
+        };
+        print_report(&report);
+        Ok(())
+    }
+
+    /// Progress goes to stderr so it can be separated from the report
+    fn print_progress() -> ProgressCallback {
+        ProgressCallback::new(|p| {
+            let height = p
+                .height
+                .map(|h| format!(" height {}", h))
+                .unwrap_or_default();
+            eprintln!(
+                "{:?}: {}/{} ({:.1}%){} after {}ms",
+                p.phase,
+                p.completed,
+                p.total,
+                p.percent,
+                height,
+                p.elapsed_ms.round()
+            );
+        })
+    }
+
+    fn print_report(report: &BenchReport) {
+        let BenchReport {
+            phases,
+            orchard,
+            sapling,
+            blocks,
+            bytes_received,
+            peak_memory_bytes,
+            ..
+        } = report;
+        println!("{:#?}", phases);
+        println!("Orchard: {:?}", orchard);
+        println!("Sapling: {:?}", sapling);
+        println!("Blocks: {} ({} bytes)", blocks, bytes_received);
+        if let Some(bytes) = peak_memory_bytes {
+            println!("Peak memory: {}MiB", bytes / (1024 * 1024));
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() -> anyhow::Result<()> {
+    native::main()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {}
@@ -0,0 +1,74 @@
+use futures_util::StreamExt;
+use prost::Message;
+use wasm_bindgen::prelude::*;
+
+use crate::bench_params::{BenchParams, SyncOptions};
+use crate::bench_report::BenchReport;
+use crate::block_range_stream::download_block_batches;
+use crate::block_source::BlockSource;
+use crate::error::SyncError;
+use crate::{console_log, now_ms};
+
+/// Download all blocks in the range without decrypting or inserting anything so the cost of
+/// network transfer and deserialisation can be measured on its own.
+#[wasm_bindgen]
+pub async fn block_download_bench(params: BenchParams) -> Result<BenchReport, JsError> {
+    console_log!("Starting block download with params: {:?}", params);
+    let params = params.resolve().await?;
+
+    let source = params.block_source()?;
+    let options = params.sync_options();
+    let report = download_blocks(
+        source,
+        params.start_block,
+        params.end_block,
+        params.block_batch_size,
+        options,
+    )
+    .await?;
+    Ok(report)
+}
+
+/// Download the given range of blocks from the block source and discard them.
+pub async fn download_blocks<S: BlockSource + Clone + 'static>(
+    source: S,
+    start_block: u32,
+    end_block: u32,
+    block_batch_size: u32,
+    options: SyncOptions,
+) -> Result<BenchReport, SyncError> {
+    let start = now_ms();
+    let mut batches = download_block_batches(
+        source,
+        start_block,
+        end_block,
+        block_batch_size,
+        options.n_download_streams,
+        options.retry_policy,
+        &options.cancel,
+        options.checkpoint_interval,
+        Vec::new(),
+    );
+    let mut report = BenchReport::default();
+    while let Some(blocks) = batches.next().await {
+        let blocks = blocks?;
+        report.blocks += blocks.len() as u64;
+        report.bytes_received += blocks.iter().map(|b| b.encoded_len() as u64).sum::<u64>();
+        if let Some(last) = blocks.last() {
+            report.last_height = Some(last.height as u32);
+        }
+    }
+    report.check_cancelled(&options.cancel, end_block);
+    report.finish(start);
+    report.phases.download_ms = report.phases.total_ms;
+
+    let elapsed = report.phases.total_ms;
+    console_log!(
+        "Downloaded {} blocks over {} streams in {}ms ({} blocks/s)",
+        report.blocks,
+        options.n_download_streams,
+        elapsed,
+        (report.blocks as f64 / elapsed * 1000.0).round()
+    );
+    Ok(report)
+}
@@ -1,22 +1,32 @@
-use futures_util::{Stream, TryStreamExt};
+use futures_util::stream::{self, LocalBoxStream, TryChunksError};
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use prost::Message;
+use std::collections::VecDeque;
 use std::convert::TryInto;
 use tonic::Streaming;
 
 use orchard::note_encryption::{CompactAction, OrchardDomain};
-use sapling::note_encryption::{CompactOutputDescription, SaplingDomain, Zip212Enforcement};
+use sapling::note_encryption::{CompactOutputDescription, SaplingDomain};
+use zcash_primitives::consensus::{self, BlockHeight};
+use zcash_primitives::transaction::components::sapling::zip212_enforcement;
 
-use crate::bench_params::ShieldedPool;
-use crate::console_log;
+use crate::bench_params::{RetryPolicy, ShieldedPool};
+use crate::block_source::BlockSource;
+use crate::cancel::CancellationToken;
+use crate::commitment_tree::MAX_CHECKPOINTS;
+use crate::error::SyncError;
 use crate::proto::compact_formats::CompactBlock;
 use crate::proto::service::{BlockId, BlockRange};
-use crate::{WasmGrpcClient, PERFORMANCE};
+use crate::sync_state::block_hash;
+use crate::GrpcClient;
+use crate::{console_log, sleep_ms};
 
 /// return a stream over a range of blocks.
 pub async fn block_range_stream(
-    client: &mut WasmGrpcClient,
+    client: &mut GrpcClient,
     start: u32,
     end: u32,
-) -> Streaming<CompactBlock> {
+) -> Result<Streaming<CompactBlock>, tonic::Status> {
     let start = BlockId {
         height: start as u64,
         hash: vec![],
@@ -29,123 +39,439 @@ pub async fn block_range_stream(
         start: Some(start),
         end: Some(end),
     };
-    client.get_block_range(range).await.unwrap().into_inner()
+    Ok(client.get_block_range(range).await?.into_inner())
 }
 
-/// Return a stream over the contents of blocks, batched into chunks of `batch_size`.
-/// The stream will yield a tuple of accumulated (orchard_actions, sapling_outputs) for each batch.
-/// The pool parameter determines which contents should be returned (orchard, sapling or both)
-pub fn block_contents_batch_stream(
-    mut client: WasmGrpcClient,
-    pool: ShieldedPool,
+/// Orchard actions and Sapling outputs extracted from a batch of blocks, along with where each
+/// of them appeared in the chain
+#[derive(Default)]
+pub struct BatchContents {
+    pub actions: Vec<(OrchardDomain, CompactAction)>,
+    pub action_locations: Vec<OutputLocation>,
+    pub outputs: Vec<(SaplingDomain, CompactOutputDescription)>,
+    pub output_locations: Vec<OutputLocation>,
+    /// Hashes of the transactions in the batch, indexed by `OutputLocation::tx`
+    pub txids: Vec<Vec<u8>>,
+    /// Nullifiers of the notes spent in the batch, including by transactions skipped as spam
+    pub orchard_nullifiers: Vec<RevealedNullifier>,
+    pub sapling_nullifiers: Vec<RevealedNullifier>,
+    /// Number of blocks in the batch and their protobuf encoded size
+    pub blocks: u32,
+    pub bytes: u64,
+    /// Height and hash of the last block in the batch
+    pub end_height: u32,
+    pub end_hash: Vec<u8>,
+    /// Transactions whose actions or outputs were skipped by the spam filter
+    pub skipped_orchard_txs: u32,
+    pub skipped_sapling_txs: u32,
+    /// Where each block ends in `actions` and `outputs`, to check the trees against its chain metadata
+    pub block_ends: Vec<BlockEnd>,
+}
+
+/// The end of a block within a batch and the tree sizes its chain metadata gives for that point
+#[derive(Clone, Debug)]
+pub struct BlockEnd {
+    pub height: u32,
+    pub hash: Vec<u8>,
+    /// Number of actions and outputs in the batch up to the end of the block
+    pub actions: usize,
+    pub outputs: usize,
+    /// Size of each commitment tree after the block, if it had chain metadata
+    pub orchard_tree_size: Option<u64>,
+    pub sapling_tree_size: Option<u64>,
+}
+
+/// A nullifier revealed by a spend, and the transaction that spent it
+#[derive(Clone, Copy, Debug)]
+pub struct RevealedNullifier {
+    pub nullifier: [u8; 32],
+    pub height: u32,
+    /// Index into the batch's `txids`
+    pub tx: usize,
+}
+
+/// The location of an output or action in the chain
+#[derive(Clone, Copy, Debug)]
+pub struct OutputLocation {
+    pub height: u32,
+    /// Index into the batch's `txids`
+    pub tx: usize,
+    /// Index of the transaction within its block
+    pub tx_index: u32,
+    /// Index of the output or action within its transaction
+    pub output_index: u32,
+    /// Position of the note commitment in its pool's commitment tree, if the block had chain metadata
+    pub position: Option<u64>,
+}
+
+/// Download the blocks in [start_height, end_height] as batches of `batch_size` blocks in height order.
+///
+/// With a single stream the whole range is fetched over one connection. With more, the range is split
+/// into batch sized sub-ranges and up to `n_streams` of them are fetched concurrently, each over its own
+/// clone of the source. Completed sub-ranges are buffered until all of the ones before them are
+/// yielded, so at most `n_streams` batches are held in memory. The stream ends early once `cancel` is cancelled.
+///
+/// Every block is checked to link to the one before it by `prev_hash`, across batches and reconnects.
+/// `known_blocks` are the height and hash of blocks already synced which the trees are checkpointed
+/// at, oldest first. If any are given the first block must link to the last of them, e.g. the block a
+/// sync was rolled back to. If one doesn't, the blocks before it are yielded and the stream ends with
+/// `SyncError::Reorg`. The fork point reported is one of the known blocks or of the blocks the trees
+/// are checkpointed at every `checkpoint_interval`.
+/// A batch size, number of streams or checkpoint interval of 0 is rejected with `SyncError::InvalidOptions`.
+#[allow(clippy::too_many_arguments)]
+pub fn download_block_batches<S: BlockSource + Clone + 'static>(
+    source: S,
     start_height: u32,
     end_height: u32,
     batch_size: u32,
-    spam_filter_limit: u32,
-) -> impl Stream<
-    Item = (
-        Vec<(OrchardDomain, CompactAction)>,
-        Vec<(SaplingDomain, CompactOutputDescription)>,
-    ),
-> {
+    n_streams: u32,
+    retry_policy: RetryPolicy,
+    cancel: &CancellationToken,
+    checkpoint_interval: u32,
+    known_blocks: Vec<(u32, Vec<u8>)>,
+) -> LocalBoxStream<'static, Result<Vec<CompactBlock>, SyncError>> {
+    let invalid = match (batch_size, n_streams, checkpoint_interval) {
+        (0, _, _) => Some("the block batch size must be at least 1"),
+        (_, 0, _) => Some("at least 1 download stream is needed"),
+        (_, _, 0) => Some("the checkpoint interval must be at least 1"),
+        _ => None,
+    };
+    if let Some(reason) = invalid {
+        let error = SyncError::InvalidOptions(reason.into());
+        return stream::once(async { Err(error) }).boxed_local();
+    }
+    // stop as soon as the sync is cancelled rather than waiting for the batch being downloaded
+    let cancelled = cancel.cancelled();
+    let chain = source.clone();
+    if n_streams == 1 {
+        let batches = resumable_block_range(source, start_height, end_height, retry_policy)
+            .try_chunks(batch_size as usize)
+            .map_err(|TryChunksError(_, e)| e)
+            .take_until(cancelled);
+        return check_chain_continuity(batches, chain, known_blocks, checkpoint_interval)
+            .boxed_local();
+    }
+    let sub_ranges = (start_height..=end_height)
+        .step_by(batch_size as usize)
+        .map(move |start| (start, end_height.min(start + (batch_size - 1))));
+    let batches = stream::iter(sub_ranges)
+        .map(move |(start, end)| {
+            resumable_block_range(source.clone(), start, end, retry_policy).try_collect::<Vec<_>>()
+        })
+        .buffered(n_streams as usize)
+        .take_until(cancelled);
+    check_chain_continuity(batches, chain, known_blocks, checkpoint_interval).boxed_local()
+}
+
+/// Check each block in a stream of batches links to the one before it, and the first to the last of
+/// `known_blocks` (their heights and hashes) if any. At the first that doesn't, the blocks before it are
+/// yielded, then `SyncError::Reorg` with the last checkpointed block which is still on the source's chain.
+///
+/// Only the hashes of the blocks the trees are checkpointed at are kept to find the fork point:
+/// `known_blocks`, every block whose height is a multiple of `checkpoint_interval` and the last block
+/// of each batch. The last `MAX_CHECKPOINTS` of them are kept, as the trees can only be rewound to
+/// those.
+pub(crate) fn check_chain_continuity<S: BlockSource + 'static>(
+    batches: impl Stream<Item = Result<Vec<CompactBlock>, SyncError>> + 'static,
+    mut source: S,
+    known_blocks: Vec<(u32, Vec<u8>)>,
+    checkpoint_interval: u32,
+) -> impl Stream<Item = Result<Vec<CompactBlock>, SyncError>> {
+    async_stream::stream! {
+        // hash of the last block received, which the next one must link to
+        let mut last_hash = known_blocks.last().map(|(_, hash)| hash.clone());
+        // (height, hash) of the most recent blocks the trees are checkpointed at, oldest first
+        let mut checkpoints: VecDeque<(u32, Vec<u8>)> = known_blocks.into_iter().collect();
+        while checkpoints.len() > MAX_CHECKPOINTS {
+            checkpoints.pop_front();
+        }
+        let keep = |checkpoints: &mut VecDeque<_>, block: &CompactBlock| {
+            checkpoints.push_back((block.height as u32, block.hash.clone()));
+            if checkpoints.len() > MAX_CHECKPOINTS {
+                checkpoints.pop_front();
+            }
+        };
+        futures_util::pin_mut!(batches);
+        while let Some(batch) = batches.next().await {
+            let mut blocks = match batch {
+                Ok(blocks) => blocks,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+            let mut linked = 0;
+            for block in &blocks {
+                if matches!(&last_hash, Some(hash) if *hash != block.prev_hash) {
+                    break;
+                }
+                last_hash = Some(block.hash.clone());
+                if block.height as u32 % checkpoint_interval == 0 {
+                    keep(&mut checkpoints, block);
+                }
+                linked += 1;
+            }
+            // the last block yielded ends a batch, so the trees are checkpointed there too
+            match blocks[..linked].last() {
+                Some(last) if last.height as u32 % checkpoint_interval != 0 => {
+                    keep(&mut checkpoints, last)
+                }
+                _ => {}
+            }
+            if linked == blocks.len() {
+                yield Ok(blocks);
+                continue;
+            }
+
+            console_log!(
+                "Block {} doesn't follow the block before it, finding where the chain forked",
+                blocks[linked].height
+            );
+            blocks.truncate(linked);
+            if !blocks.is_empty() {
+                yield Ok(blocks);
+            }
+            let error = match find_fork_point(&mut source, &checkpoints).await {
+                Ok(fork_height) => SyncError::Reorg {
+                    fork_height: fork_height as u64,
+                },
+                Err(e) => e,
+            };
+            yield Err(error);
+            return;
+        }
+    }
+}
+
+/// Find the most recent of the given blocks which is still on the source's chain by comparing their
+/// hashes with the source's tree states. If none of them are, the fork is before the oldest, further
+/// back than a sync can be rolled back.
+async fn find_fork_point(
+    source: &mut impl BlockSource,
+    recent: &VecDeque<(u32, Vec<u8>)>,
+) -> Result<u32, SyncError> {
+    for (height, hash) in recent.iter().rev() {
+        if block_hash(&source.tree_state(*height).await?)? == *hash {
+            return Ok(*height);
+        }
+    }
+    Err(SyncError::NoCheckpoint {
+        height: recent
+            .front()
+            .map_or(0, |(height, _)| height.saturating_sub(1) as u64),
+    })
+}
+
+/// Return a stream over the blocks in [start_height, end_height] which checks every block is the one
+/// expected next.
+///
+/// If the source fails or the stream ends early it is reconnected from the block after the last one
+/// received, following `retry_policy`. Errors that retrying cannot resolve are yielded and end the stream.
+pub fn resumable_block_range<S: BlockSource + 'static>(
+    mut source: S,
+    start_height: u32,
+    end_height: u32,
+    retry_policy: RetryPolicy,
+) -> impl Stream<Item = Result<CompactBlock, SyncError>> {
     async_stream::stream! {
-        let overall_start = PERFORMANCE.now();
-
-        let mut blocks_processed = 0;
-        let mut actions_processed = 0;
-        let mut outputs_processed = 0;
-
-        let mut latest_synced = start_height as u64;
-
-        while latest_synced < end_height as u64 {
-            let mut chunked_block_stream =
-                block_range_stream(&mut client, latest_synced as u32, end_height)
-                    .await
-                    .try_chunks(batch_size as usize);
-            while let Ok(Some(blocks)) = chunked_block_stream.try_next().await {
-                let start = PERFORMANCE.now();
-                let blocks_len = blocks.len();
-                let range_start = blocks.first().unwrap().height;
-                let range_end = blocks.last().unwrap().height;
-
-                let (actions, outputs) = blocks.into_iter().flat_map(|b| b.vtx.into_iter()).fold(
-                    (vec![], vec![]),
-                    |(mut actions, mut outputs), tx| {
-                        let mut act = if pool.sync_orchard() {
-                            if tx.actions.len() > spam_filter_limit as usize {
-                                console_log!(
-                                    "Skipped a transaction with {} actions",
-                                    tx.actions.len()
-                                );
-                                vec![]
-                            } else {
-                                tx.actions
-                                .into_iter()
-                                .map(|action| {
-                                    let action: CompactAction = action.try_into().unwrap();
-                                    let domain = OrchardDomain::for_compact_action(&action);
-                                    (domain, action)
-                                })
-                                .collect::<Vec<_>>()
-                            }
-
-                        } else {
-                            vec![]
-                        };
-                        let mut opt = if pool.sync_sapling() {
-                            if tx.outputs.len() > spam_filter_limit as usize {
-                                console_log!(
-                                    "Skipped a transaction with {} actions",
-                                    tx.outputs.len()
-                                );
-                                vec![]
-                            } else {
-                            tx.outputs
-                                .into_iter()
-                                .map(|output| {
-                                    let output: CompactOutputDescription = output.try_into().unwrap();
-                                    (SaplingDomain::new(Zip212Enforcement::On), output)
-                                })
-                                .collect::<Vec<_>>()
-                            }
-                        } else {
-                            vec![]
-                        };
-                        actions.append(&mut act);
-                        outputs.append(&mut opt);
-                        (actions, outputs)
-                    },
-                );
-
-                blocks_processed += blocks_len;
-                actions_processed += actions.len();
-                outputs_processed += outputs.len();
-                latest_synced = range_end;
-
-                yield (actions, outputs);
-
-                console_log!(
-                    "
-Processed {} blocks in range: [{}, {}] in {}ms
-- Orchard Actions Processed: {}
-- Sapling Outputs Processed: {}
-- Total Blocks Processed: {}
-- Blocks remaining to sync: {} ({}%)
-- Total Time Elapsed: {}ms",
-                    blocks_len,
-                    range_start,
-                    range_end,
-                    PERFORMANCE.now() - start,
-                    actions_processed,
-                    outputs_processed,
-                    blocks_processed,
-                    end_height - start_height - blocks_processed as u32,
-                    ((blocks_processed as f64 / (end_height - start_height) as f64) * 100.0).round(),
-                    PERFORMANCE.now() - overall_start
-                );
+        let mut next_height = start_height as u64;
+        let mut attempt = 0;
+
+        while next_height <= end_height as u64 {
+            let error = match source.block_range(next_height as u32, end_height).await {
+                Ok(mut block_stream) => loop {
+                    let block = match block_stream.next().await {
+                        Some(Ok(block)) => block,
+                        Some(Err(e)) => break Some(SyncError::from(e)),
+                        None => break None,
+                    };
+                    if block.height != next_height {
+                        break Some(SyncError::HeightGap {
+                            expected: next_height,
+                            found: block.height,
+                        });
+                    }
+                    next_height += 1;
+                    attempt = 0;
+                    yield Ok(block);
+                },
+                Err(e) => Some(SyncError::from(e)),
+            };
+
+            if next_height > end_height as u64 {
+                break;
+            }
+            let error = error.unwrap_or_else(|| {
+                SyncError::Transport(anyhow::anyhow!(
+                    "Block stream ended at height {} before reaching {}",
+                    next_height - 1,
+                    end_height
+                ))
+            });
+            if !error.is_retryable() || attempt >= retry_policy.max_retries {
+                yield Err(error);
+                return;
+            }
+            attempt += 1;
+            let backoff = retry_policy.backoff_ms(attempt);
+            console_log!(
+                "{}. Reconnecting from block {} in {}ms (attempt {}/{})",
+                error,
+                next_height,
+                backoff,
+                attempt,
+                retry_policy.max_retries
+            );
+            sleep_ms(backoff).await;
+        }
+    }
+}
+
+/// Reject ranges starting before the selected pool activated on the network
+pub(crate) fn check_activation(
+    params: &consensus::Network,
+    pool: &ShieldedPool,
+    start_height: u32,
+) -> Result<(), SyncError> {
+    let activation = pool.activation_height(params);
+    if start_height < activation {
+        return Err(SyncError::BeforeActivation {
+            start: start_height as u64,
+            activation: activation as u64,
+            pool: pool.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// Extract the actions and outputs for the selected pools from a batch of blocks,
+/// skipping any transaction with more than `spam_filter_limit` of them.
+/// Sapling outputs are decrypted with the ZIP-212 enforcement in effect at their block's height.
+pub(crate) fn batch_contents(
+    blocks: Vec<CompactBlock>,
+    params: &consensus::Network,
+    pool: &ShieldedPool,
+    spam_filter_limit: u32,
+) -> Result<BatchContents, SyncError> {
+    let (range_start, range_end) = match (blocks.first(), blocks.last()) {
+        (Some(first), Some(last)) => (first.height, last.height),
+        _ => return Ok(BatchContents::default()),
+    };
+    let mut contents = BatchContents {
+        end_hash: blocks[blocks.len() - 1].hash.clone(),
+        ..Default::default()
+    };
+    for block in blocks {
+        let height = block.height;
+        contents.blocks += 1;
+        contents.bytes += block.encoded_len() as u64;
+        let zip212 = zip212_enforcement(params, BlockHeight::from(height as u32));
+        let malformed = |e: anyhow::Error| SyncError::MalformedField {
+            height,
+            reason: e.to_string(),
+        };
+
+        // tree positions of the first commitment in the block, counting the spam that gets skipped
+        let (mut orchard_position, mut sapling_position) = match &block.chain_metadata {
+            Some(meta) => (
+                (meta.orchard_commitment_tree_size as u64)
+                    .checked_sub(block.vtx.iter().map(|tx| tx.actions.len() as u64).sum()),
+                (meta.sapling_commitment_tree_size as u64)
+                    .checked_sub(block.vtx.iter().map(|tx| tx.outputs.len() as u64).sum()),
+            ),
+            None => (None, None),
+        };
+
+        for tx in block.vtx {
+            let tx_actions = tx.actions.len() as u64;
+            let tx_outputs = tx.outputs.len() as u64;
+            let location =
+                |tx_index: usize, output_index: usize, position: Option<u64>| OutputLocation {
+                    height: height as u32,
+                    tx: tx_index,
+                    tx_index: tx.index as u32,
+                    output_index: output_index as u32,
+                    position: position.map(|p| p + output_index as u64),
+                };
+            let tx_index = contents.txids.len();
+            let revealed = |nullifier: &[u8]| -> Result<RevealedNullifier, SyncError> {
+                Ok(RevealedNullifier {
+                    nullifier: nullifier.try_into().map_err(|_| {
+                        malformed(anyhow::anyhow!(
+                            "Nullifier must be 32 bytes, got {}",
+                            nullifier.len()
+                        ))
+                    })?,
+                    height: height as u32,
+                    tx: tx_index,
+                })
+            };
+
+            if pool.sync_orchard() {
+                for action in &tx.actions {
+                    contents
+                        .orchard_nullifiers
+                        .push(revealed(&action.nullifier)?);
+                }
+                if tx.actions.len() > spam_filter_limit as usize {
+                    console_log!("Skipped a transaction with {} actions", tx.actions.len());
+                    contents.skipped_orchard_txs += 1;
+                } else {
+                    for (i, action) in tx.actions.iter().enumerate() {
+                        let action: CompactAction = action.try_into().map_err(malformed)?;
+                        let domain = OrchardDomain::for_compact_action(&action);
+                        contents.actions.push((domain, action));
+                        contents
+                            .action_locations
+                            .push(location(tx_index, i, orchard_position));
+                    }
+                }
+            }
+            if pool.sync_sapling() {
+                for spend in &tx.spends {
+                    contents.sapling_nullifiers.push(revealed(&spend.nf)?);
+                }
+                if tx.outputs.len() > spam_filter_limit as usize {
+                    console_log!("Skipped a transaction with {} outputs", tx.outputs.len());
+                    contents.skipped_sapling_txs += 1;
+                } else {
+                    for (i, output) in tx.outputs.iter().enumerate() {
+                        let output: CompactOutputDescription =
+                            output.try_into().map_err(malformed)?;
+                        contents.outputs.push((SaplingDomain::new(zip212), output));
+                        contents
+                            .output_locations
+                            .push(location(tx_index, i, sapling_position));
+                    }
+                }
             }
-            console_log!("GRPC Stream Disconnected or Ended, will reconnect if more blocks needed");
+
+            orchard_position = orchard_position.map(|p| p + tx_actions);
+            sapling_position = sapling_position.map(|p| p + tx_outputs);
+            contents.txids.push(tx.hash);
         }
-        console_log!("Block contents stream complete");
+        contents.block_ends.push(BlockEnd {
+            height: height as u32,
+            hash: block.hash,
+            actions: contents.actions.len(),
+            outputs: contents.outputs.len(),
+            orchard_tree_size: block
+                .chain_metadata
+                .as_ref()
+                .map(|meta| meta.orchard_commitment_tree_size as u64),
+            sapling_tree_size: block
+                .chain_metadata
+                .as_ref()
+                .map(|meta| meta.sapling_commitment_tree_size as u64),
+        });
     }
+    contents.end_height = range_end as u32;
+    console_log!(
+        "Processed blocks in range: [{}, {}] ({} Orchard actions, {} Sapling outputs)",
+        range_start,
+        range_end,
+        contents.actions.len(),
+        contents.outputs.len()
+    );
+    Ok(contents)
 }
@@ -0,0 +1,454 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use futures_util::stream::{self, LocalBoxStream, StreamExt, TryStreamExt};
+
+use crate::bench_params::Network;
+use crate::block_range_stream::block_range_stream;
+use crate::error::SyncError;
+use crate::fixture::{
+    LengthDelimitedReader, BLOCKS_FILE, ORCHARD_SUBTREE_ROOTS_FILE, SAPLING_SUBTREE_ROOTS_FILE,
+    TREE_STATES_FILE,
+};
+use crate::proto::compact_formats::CompactBlock;
+use crate::proto::service::{
+    BlockId, ChainSpec, Empty, GetSubtreeRootsArg, ShieldedProtocol, SubtreeRoot, TreeState,
+};
+use crate::GrpcClient;
+
+/// A stream of compact blocks in ascending height order
+pub type BlockStream = LocalBoxStream<'static, anyhow::Result<CompactBlock>>;
+
+/// Somewhere the benchmarks can retrieve compact blocks and commitment tree states from.
+///
+/// This lets the decryption and tree sync pipelines run against a live lightwalletd,
+/// an in-memory chain or a file on disk without any changes.
+#[allow(async_fn_in_trait)]
+pub trait BlockSource {
+    /// Return a stream over the blocks in the inclusive range [start, end]
+    async fn block_range(&mut self, start: u32, end: u32) -> anyhow::Result<BlockStream>;
+
+    /// Return the commitment tree state as of the end of the block at the given height
+    async fn tree_state(&mut self, height: u32) -> anyhow::Result<TreeState>;
+
+    /// Return the height of the most recent block this source knows about
+    async fn latest_height(&mut self) -> anyhow::Result<u32>;
+
+    /// Return the roots of the completed 2^16 leaf subtrees (shards) of a pool's note commitment
+    /// tree, in order from the subtree with index `start_index`
+    async fn subtree_roots(
+        &mut self,
+        protocol: ShieldedProtocol,
+        start_index: u32,
+    ) -> anyhow::Result<Vec<SubtreeRoot>>;
+
+    /// Return the name of the chain the blocks are from, `main` or `test` as reported by lightwalletd
+    async fn chain_name(&mut self) -> anyhow::Result<String>;
+}
+
+/// Check the source serves the chain for `network` so a misconfigured server isn't silently synced
+pub async fn check_network<S: BlockSource>(
+    source: &mut S,
+    network: &Network,
+) -> Result<(), SyncError> {
+    let found = source.chain_name().await?;
+    if found != network.chain_name() {
+        return Err(SyncError::NetworkMismatch {
+            expected: network.chain_name().to_string(),
+            found,
+        });
+    }
+    Ok(())
+}
+
+/// The chain name recorded in a set of tree states
+fn tree_states_chain_name(tree_states: &BTreeMap<u64, TreeState>) -> anyhow::Result<String> {
+    tree_states
+        .values()
+        .next()
+        .map(|t| t.network.clone())
+        .ok_or_else(|| anyhow::anyhow!("No tree states to determine the chain from"))
+}
+
+impl BlockSource for GrpcClient {
+    async fn block_range(&mut self, start: u32, end: u32) -> anyhow::Result<BlockStream> {
+        Ok(block_range_stream(self, start, end)
+            .await?
+            .map_err(anyhow::Error::from)
+            .boxed_local())
+    }
+
+    async fn tree_state(&mut self, height: u32) -> anyhow::Result<TreeState> {
+        let block_id = BlockId {
+            height: height as u64,
+            hash: vec![],
+        };
+        Ok(self.get_tree_state(block_id).await?.into_inner())
+    }
+
+    async fn latest_height(&mut self) -> anyhow::Result<u32> {
+        Ok(self
+            .get_latest_block(ChainSpec {})
+            .await?
+            .into_inner()
+            .height as u32)
+    }
+
+    async fn subtree_roots(
+        &mut self,
+        protocol: ShieldedProtocol,
+        start_index: u32,
+    ) -> anyhow::Result<Vec<SubtreeRoot>> {
+        let arg = GetSubtreeRootsArg {
+            start_index,
+            shielded_protocol: protocol as i32,
+            max_entries: 0,
+        };
+        Ok(self
+            .get_subtree_roots(arg)
+            .await?
+            .into_inner()
+            .try_collect()
+            .await?)
+    }
+
+    async fn chain_name(&mut self) -> anyhow::Result<String> {
+        Ok(self
+            .get_lightd_info(Empty {})
+            .await?
+            .into_inner()
+            .chain_name)
+    }
+}
+
+/// The block sources that can be selected through `BenchParams`
+#[derive(Clone)]
+pub enum BenchBlockSource {
+    Lightwalletd(GrpcClient),
+    Replay(MemoryBlockSource),
+}
+
+impl BlockSource for BenchBlockSource {
+    async fn block_range(&mut self, start: u32, end: u32) -> anyhow::Result<BlockStream> {
+        match self {
+            BenchBlockSource::Lightwalletd(client) => client.block_range(start, end).await,
+            BenchBlockSource::Replay(source) => source.block_range(start, end).await,
+        }
+    }
+
+    async fn tree_state(&mut self, height: u32) -> anyhow::Result<TreeState> {
+        match self {
+            BenchBlockSource::Lightwalletd(client) => client.tree_state(height).await,
+            BenchBlockSource::Replay(source) => source.tree_state(height).await,
+        }
+    }
+
+    async fn latest_height(&mut self) -> anyhow::Result<u32> {
+        match self {
+            BenchBlockSource::Lightwalletd(client) => client.latest_height().await,
+            BenchBlockSource::Replay(source) => source.latest_height().await,
+        }
+    }
+
+    async fn subtree_roots(
+        &mut self,
+        protocol: ShieldedProtocol,
+        start_index: u32,
+    ) -> anyhow::Result<Vec<SubtreeRoot>> {
+        match self {
+            BenchBlockSource::Lightwalletd(client) => {
+                client.subtree_roots(protocol, start_index).await
+            }
+            BenchBlockSource::Replay(source) => source.subtree_roots(protocol, start_index).await,
+        }
+    }
+
+    async fn chain_name(&mut self) -> anyhow::Result<String> {
+        match self {
+            BenchBlockSource::Lightwalletd(client) => client.chain_name().await,
+            BenchBlockSource::Replay(source) => source.chain_name().await,
+        }
+    }
+}
+
+/// A block source backed by a list of blocks held in memory.
+/// Useful for synthetic chains or fixtures that have already been loaded.
+///
+/// Clones share the same blocks so they are cheap to hand out to concurrent downloads.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBlockSource {
+    blocks: Rc<Vec<CompactBlock>>,
+    tree_states: Rc<BTreeMap<u64, TreeState>>,
+    subtree_roots: Rc<SubtreeRoots>,
+    fork: Option<Rc<ScheduledFork<MemoryBlockSource>>>,
+}
+
+/// Another chain a source switches to part way through serving blocks, simulating a reorg.
+/// Used by `MemoryBlockSource` and the mock lightwalletd.
+#[derive(Debug)]
+pub(crate) struct ScheduledFork<C> {
+    pub(crate) chain: C,
+    /// Number of blocks served from the original chain before switching
+    after_blocks: u64,
+    served: AtomicU64,
+}
+
+impl<C> ScheduledFork<C> {
+    pub(crate) fn new(chain: C, after_blocks: u64) -> Self {
+        Self {
+            chain,
+            after_blocks,
+            served: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether enough blocks have been served to switch to the fork
+    pub(crate) fn switched(&self) -> bool {
+        self.served.load(Ordering::SeqCst) >= self.after_blocks
+    }
+
+    /// Count a block as served. Returns true if it was the last before switching to the fork
+    pub(crate) fn count_served(&self) -> bool {
+        self.served.fetch_add(1, Ordering::SeqCst) + 1 == self.after_blocks
+    }
+}
+
+impl MemoryBlockSource {
+    pub fn new(
+        mut blocks: Vec<CompactBlock>,
+        tree_states: impl IntoIterator<Item = TreeState>,
+    ) -> Self {
+        blocks.sort_by_key(|b| b.height);
+        Self {
+            blocks: Rc::new(blocks),
+            tree_states: Rc::new(tree_states.into_iter().map(|t| (t.height, t)).collect()),
+            subtree_roots: Rc::default(),
+            fork: None,
+        }
+    }
+
+    /// Switch to serving the blocks, tree states and subtree roots of `fork` once `after_blocks` blocks
+    /// have been served, as if the chain reorganised onto it. The switch can happen part way through
+    /// a block range and is shared by all clones.
+    pub fn with_fork(mut self, fork: MemoryBlockSource, after_blocks: u64) -> Self {
+        self.fork = Some(Rc::new(ScheduledFork::new(fork, after_blocks)));
+        self
+    }
+
+    /// Also serve the given subtree roots for each pool
+    pub fn with_subtree_roots(
+        mut self,
+        sapling: Vec<SubtreeRoot>,
+        orchard: Vec<SubtreeRoot>,
+    ) -> Self {
+        self.subtree_roots = Rc::new(SubtreeRoots { sapling, orchard });
+        self
+    }
+
+    pub fn blocks(&self) -> &[CompactBlock] {
+        &self.blocks
+    }
+
+    /// The chain being served, which is the fork once it has been switched to
+    fn active(&self) -> &MemoryBlockSource {
+        match &self.fork {
+            Some(fork) if fork.switched() => &fork.chain,
+            _ => self,
+        }
+    }
+
+    /// Serve the block at `height` from the active chain, counting it towards the switch to the fork
+    fn serve_block(&self, height: u32) -> Option<CompactBlock> {
+        let chain = self.active();
+        let block = chain
+            .blocks
+            .binary_search_by_key(&(height as u64), |b| b.height)
+            .ok()
+            .map(|i| chain.blocks[i].clone());
+        if let Some(fork) = &self.fork {
+            fork.count_served();
+        }
+        block
+    }
+}
+
+/// The completed subtree roots of each pool a replayed source serves. Empty if none were recorded.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SubtreeRoots {
+    sapling: Vec<SubtreeRoot>,
+    orchard: Vec<SubtreeRoot>,
+}
+
+impl SubtreeRoots {
+    pub(crate) fn from(&self, protocol: ShieldedProtocol, start_index: u32) -> Vec<SubtreeRoot> {
+        let roots = match protocol {
+            ShieldedProtocol::Sapling => &self.sapling,
+            ShieldedProtocol::Orchard => &self.orchard,
+        };
+        roots.iter().skip(start_index as usize).cloned().collect()
+    }
+}
+
+impl BlockSource for MemoryBlockSource {
+    async fn block_range(&mut self, start: u32, end: u32) -> anyhow::Result<BlockStream> {
+        if self.fork.is_some() {
+            // pick the chain as each block is served so a range can cross the switch
+            let source = self.clone();
+            let blocks = (start..=end).map_while(move |height| source.serve_block(height));
+            return Ok(stream::iter(blocks.map(Ok)).boxed_local());
+        }
+        let first = self.blocks.partition_point(|b| b.height < start as u64);
+        let last = self.blocks.partition_point(|b| b.height <= end as u64);
+        let blocks = self.blocks[first..last.max(first)].to_vec();
+        Ok(stream::iter(blocks.into_iter().map(Ok)).boxed_local())
+    }
+
+    async fn tree_state(&mut self, height: u32) -> anyhow::Result<TreeState> {
+        self.active()
+            .tree_states
+            .get(&(height as u64))
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No tree state for height {}", height))
+    }
+
+    async fn latest_height(&mut self) -> anyhow::Result<u32> {
+        self.active()
+            .blocks
+            .last()
+            .map(|b| b.height as u32)
+            .ok_or_else(|| anyhow::anyhow!("Block source is empty"))
+    }
+
+    async fn subtree_roots(
+        &mut self,
+        protocol: ShieldedProtocol,
+        start_index: u32,
+    ) -> anyhow::Result<Vec<SubtreeRoot>> {
+        Ok(self.active().subtree_roots.from(protocol, start_index))
+    }
+
+    async fn chain_name(&mut self) -> anyhow::Result<String> {
+        tree_states_chain_name(&self.active().tree_states)
+    }
+}
+
+/// A block source that reads blocks from a file of length-delimited `CompactBlock` messages
+/// in ascending height order. Tree states are read from a second file of length-delimited
+/// `TreeState` messages and held in memory.
+///
+/// Blocks are read lazily so ranges much larger than available memory can be replayed. The file is
+/// indexed when opened so each range seeks straight to its first block.
+#[derive(Clone, Debug)]
+pub struct FileBlockSource {
+    blocks_path: PathBuf,
+    /// Height and byte offset of each block in the file, shared by clones
+    block_offsets: Arc<Vec<(u64, u64)>>,
+    pub(crate) tree_states: BTreeMap<u64, TreeState>,
+    pub(crate) subtree_roots: SubtreeRoots,
+}
+
+impl FileBlockSource {
+    pub fn open(
+        blocks_path: impl AsRef<Path>,
+        tree_states_path: impl AsRef<Path>,
+    ) -> anyhow::Result<Self> {
+        let tree_states = LengthDelimitedReader::<TreeState, _>::new(File::open(tree_states_path)?)
+            .map(|t| t.map(|t| (t.height, t)))
+            .collect::<anyhow::Result<_>>()?;
+        let mut blocks = LengthDelimitedReader::<CompactBlock, _>::new(File::open(&blocks_path)?);
+        let mut block_offsets = vec![];
+        loop {
+            let offset = blocks.offset();
+            match blocks.next() {
+                Some(block) => block_offsets.push((block?.height, offset)),
+                None => break,
+            }
+        }
+        Ok(Self {
+            blocks_path: blocks_path.as_ref().to_path_buf(),
+            block_offsets: Arc::new(block_offsets),
+            tree_states,
+            subtree_roots: SubtreeRoots::default(),
+        })
+    }
+
+    /// Open a fixture directory containing the standard blocks and tree states files,
+    /// and the subtree roots files if it has them
+    pub fn open_dir(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        let mut source = Self::open(dir.join(BLOCKS_FILE), dir.join(TREE_STATES_FILE))?;
+        let read_roots = |name: &str| -> anyhow::Result<Vec<SubtreeRoot>> {
+            match File::open(dir.join(name)) {
+                Ok(file) => LengthDelimitedReader::new(file).collect(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+                Err(e) => Err(e.into()),
+            }
+        };
+        source.subtree_roots = SubtreeRoots {
+            sapling: read_roots(SAPLING_SUBTREE_ROOTS_FILE)?,
+            orchard: read_roots(ORCHARD_SUBTREE_ROOTS_FILE)?,
+        };
+        Ok(source)
+    }
+
+    /// Read the blocks from the first at or above `start` to the end of the file
+    pub(crate) fn blocks_from(
+        &self,
+        start: u64,
+    ) -> std::io::Result<LengthDelimitedReader<CompactBlock, File>> {
+        let first = self
+            .block_offsets
+            .partition_point(|(height, _)| *height < start);
+        let mut file = File::open(&self.blocks_path)?;
+        if let Some((_, offset)) = self.block_offsets.get(first) {
+            file.seek(SeekFrom::Start(*offset))?;
+        } else {
+            file.seek(SeekFrom::End(0))?;
+        }
+        Ok(LengthDelimitedReader::new(file))
+    }
+
+    /// Height of the last block in the file, if it has any
+    pub(crate) fn last_height(&self) -> Option<u64> {
+        self.block_offsets.last().map(|(height, _)| *height)
+    }
+}
+
+impl BlockSource for FileBlockSource {
+    async fn block_range(&mut self, start: u32, end: u32) -> anyhow::Result<BlockStream> {
+        let blocks = self
+            .blocks_from(start as u64)?
+            .take_while(move |b| !matches!(b, Ok(b) if b.height > end as u64));
+        Ok(stream::iter(blocks).boxed_local())
+    }
+
+    async fn tree_state(&mut self, height: u32) -> anyhow::Result<TreeState> {
+        self.tree_states
+            .get(&(height as u64))
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No tree state for height {}", height))
+    }
+
+    async fn latest_height(&mut self) -> anyhow::Result<u32> {
+        self.last_height()
+            .map(|height| height as u32)
+            .ok_or_else(|| anyhow::anyhow!("Block file is empty"))
+    }
+
+    async fn subtree_roots(
+        &mut self,
+        protocol: ShieldedProtocol,
+        start_index: u32,
+    ) -> anyhow::Result<Vec<SubtreeRoot>> {
+        Ok(self.subtree_roots.from(protocol, start_index))
+    }
+
+    async fn chain_name(&mut self) -> anyhow::Result<String> {
+        tree_states_chain_name(&self.tree_states)
+    }
+}
@@ -0,0 +1,90 @@
+/**
+ * Stopping a benchmark part way through, for example when a long sync is no longer wanted.
+ *
+ * From JS pass an `AbortSignal` to `BenchParams.withAbortSignal`. From Rust set `SyncOptions::cancel`
+ * to a `CancellationToken` and call `cancel` on a clone of it. The sync stops between batches (or part
+ * way through trial decrypting one) and returns a report covering the batches fully processed so far.
+ */
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures_channel::oneshot;
+use futures_util::future::Shared;
+use futures_util::FutureExt;
+use wasm_bindgen::prelude::*;
+
+/// A flag which can be set from any thread, or from JS through an `AbortSignal`,
+/// to ask a running benchmark to stop
+#[derive(Clone)]
+pub struct CancellationToken(Arc<Inner>);
+
+struct Inner {
+    cancelled: AtomicBool,
+    /// Dropped on cancellation, which completes `on_cancel`
+    notify: Mutex<Option<oneshot::Sender<()>>>,
+    on_cancel: Shared<oneshot::Receiver<()>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        let (notify, on_cancel) = oneshot::channel();
+        CancellationToken(Arc::new(Inner {
+            cancelled: AtomicBool::new(false),
+            notify: Mutex::new(Some(notify)),
+            on_cancel: on_cancel.shared(),
+        }))
+    }
+
+    /// Cancel the token. Can be called more than once
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.notify.lock().unwrap().take();
+    }
+
+    /// Cheap enough to check on every item of work
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Completes once the token is cancelled
+    pub fn cancelled(&self) -> impl Future<Output = ()> {
+        // holding a clone of the token keeps the sender alive, so the receiver only completes on cancel
+        let token = self.clone();
+        async move {
+            let _ = token.0.on_cancel.clone().await;
+        }
+    }
+
+    /// A token which is cancelled when the signal aborts
+    pub fn from_abort_signal(signal: &web_sys::AbortSignal) -> Self {
+        let token = CancellationToken::new();
+        if signal.aborted() {
+            token.cancel();
+        } else {
+            let on_abort = {
+                let token = token.clone();
+                Closure::once_into_js(move || token.cancel())
+            };
+            signal
+                .add_event_listener_with_callback("abort", on_abort.unchecked_ref())
+                .unwrap();
+        }
+        token
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken::new()
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CancellationToken")
+            .field(&self.is_cancelled())
+            .finish()
+    }
+}
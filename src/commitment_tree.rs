@@ -1,36 +1,43 @@
-use core::panic;
 /**
  * Defines a commitment tree for Orchard that can be used for benchmarking purposes
  */
 use std::io::Cursor;
 
-use futures_util::{pin_mut, StreamExt};
+use futures_util::StreamExt;
 use incrementalmerkletree::Hashable;
 use rayon::prelude::*;
 use sapling::note_encryption::{CompactOutputDescription, SaplingDomain};
-use shardtree::store::ShardStore;
-use tonic_web_wasm_client::Client;
+use shardtree::store::{Checkpoint, ShardStore};
 use wasm_bindgen::prelude::*;
 
-use incrementalmerkletree::{frontier::Frontier, Position, Retention};
+use incrementalmerkletree::{frontier::Frontier, Address, Level, Position, Retention};
 use orchard::note_encryption::{CompactAction, OrchardDomain};
 use orchard::tree::MerkleHashOrchard;
 use shardtree::store::memory::MemoryShardStore;
 use shardtree::ShardTree;
 use zcash_primitives::consensus::BlockHeight;
-use zcash_primitives::merkle_tree::read_frontier_v0;
+use zcash_primitives::merkle_tree::{read_frontier_v0, HashSer};
 
-use crate::bench_params::BenchParams;
-use crate::block_range_stream::block_contents_batch_stream;
-use crate::console_log;
-use crate::proto;
-use crate::WasmGrpcClient;
+use crate::bench_params::{BenchParams, ShieldedPool, SyncOptions};
+use crate::bench_report::BenchReport;
+use crate::block_range_stream::{
+    batch_contents, check_activation, download_block_batches, BatchContents, BlockEnd,
+};
+use crate::block_source::BlockSource;
+use crate::error::SyncError;
+use crate::pipeline::{spawn_rayon, Pipeline, StageStats};
+use crate::progress::{ProgressPhase, ProgressTracker};
+use crate::proto::service::{ShieldedProtocol, SubtreeRoot};
+use crate::sync_state::block_hash;
+use crate::witness_placement::WitnessPositions;
+use crate::{console_log, now_ms};
 
 pub const ORCHARD_SHARD_HEIGHT: u8 = { orchard::NOTE_COMMITMENT_TREE_DEPTH as u8 } / 2;
 pub const SAPLING_SHARD_HEIGHT: u8 = { sapling::NOTE_COMMITMENT_TREE_DEPTH } / 2;
 
-// max number of checkpoints our tree impl can cache to jump back to
-const MAX_CHECKPOINTS: usize = 1;
+// max number of checkpoints our tree impl can cache to jump back to. This bounds how deep a reorg the
+// trees can be rewound through
+pub(crate) const MAX_CHECKPOINTS: usize = 100;
 
 pub type OrchardMemoryShardStore = MemoryShardStore<orchard::tree::MerkleHashOrchard, BlockHeight>;
 pub type OrchardCommitmentTree =
@@ -45,182 +52,922 @@ pub type SaplingFrontier = Frontier<sapling::Node, { sapling::NOTE_COMMITMENT_TR
 
 /// Retrieve the tree frontier at the given start block height and then process all note commitments
 /// included in blocks between start and end.
-/// Finally checks to ensure the computed tree frontier matches the expected frontier at the end block height
+/// Finally checks to ensure the computed tree frontier matches the expected frontier at the end block height,
+/// and that the witnesses of `n_witnesses` commitments in each tree lead to the anchors at the end block.
+/// The commitments are the first of the range unless the params place them elsewhere.
 #[wasm_bindgen]
-pub async fn sync_commitment_tree_bench(params: BenchParams, n_witnesses: u32) -> f64 {
+pub async fn sync_commitment_tree_bench(
+    params: BenchParams,
+    n_witnesses: u32,
+) -> Result<BenchReport, JsError> {
+    let params = params.resolve().await?;
+    let source = params.block_source()?;
+    let options = params.sync_options();
     let BenchParams {
-        network: _,
         pool,
-        lightwalletd_url,
         start_block,
         end_block,
         block_batch_size,
+        ..
     } = params;
 
-    let mut client = WasmGrpcClient::new(Client::new(lightwalletd_url.clone()));
-    let (mut orchard_tree, mut orchard_cursor) =
-        bootstrap_orchard_tree_from_lightwalletd(&mut client, start_block - 1).await;
+    let summary = sync_commitment_tree(
+        source,
+        pool,
+        start_block,
+        end_block,
+        block_batch_size,
+        n_witnesses,
+        options,
+    )
+    .await?;
+    Ok(summary.report)
+}
 
-    let (mut sapling_tree, mut sapling_cursor) =
-        bootstrap_sapling_tree_from_lightwalletd(&mut client, start_block - 1).await;
+/// The commitment trees after a sync
+#[derive(Clone, Debug)]
+pub struct TreeSyncSummary {
+    /// Size of each commitment tree at the end of the range
+    pub orchard_tree_size: u64,
+    pub sapling_tree_size: u64,
+    pub report: BenchReport,
+}
 
-    // the end frontier should be the witness of the last added commitment
-    // this is used to check the sync matches the network
-    let end_frontier = fetch_orchard_frontier_at_height(&mut client, end_block)
-        .await
-        .unwrap();
+/// Sync the commitment trees over the given range of blocks retrieved from the block source.
+pub async fn sync_commitment_tree<S: BlockSource + Clone + 'static>(
+    mut source: S,
+    pool: ShieldedPool,
+    start_block: u32,
+    end_block: u32,
+    block_batch_size: u32,
+    n_witnesses: u32,
+    options: SyncOptions,
+) -> Result<TreeSyncSummary, SyncError> {
+    check_activation(&options.network, &pool, start_block)?;
+    let start = now_ms();
+    let state = TreeSyncState::bootstrap(&mut source, &pool, start_block - 1).await?;
 
-    let s = block_contents_batch_stream(
-        client,
-        pool,
+    // the end frontier should be the witness of the last added commitment. It's used to place the
+    // witnesses and to check the sync matches the network, and only fetched for the pools being synced
+    let (mut end_frontier, mut end_sapling_frontier) =
+        fetch_end_frontiers(&mut source, &pool, end_block).await?;
+
+    // place the witnesses among the commitments the range adds to each synced tree
+    let witnesses = WitnessPositions {
+        orchard: options.witness_placement.positions(
+            n_witnesses,
+            state.orchard_cursor.into(),
+            end_frontier.tree_size(),
+        ),
+        sapling: options.witness_placement.positions(
+            n_witnesses,
+            state.sapling_cursor.into(),
+            end_sapling_frontier.tree_size(),
+        ),
+    };
+    console_log!(
+        "Witnessing {} Orchard and {} Sapling commitments placed {:?}",
+        witnesses.orchard.len(),
+        witnesses.sapling.len(),
+        options.witness_placement
+    );
+
+    let (state, mut report) = insert_block_range(
+        source.clone(),
+        state,
+        &pool,
         start_block,
         end_block,
         block_batch_size,
-        u32::MAX,
-    );
-    pin_mut!(s);
+        &witnesses,
+        &options,
+    )
+    .await?;
+
+    // the trees only match lightwalletd's at the end of the range
+    if !report.cancelled {
+        // a reorg during the sync may have replaced the end of the range
+        if report.reorgs > 0 {
+            (end_frontier, end_sapling_frontier) =
+                fetch_end_frontiers(&mut source, &pool, end_block).await?;
+        }
+
+        let root_check_start = now_ms();
+        state.check_roots(&pool, &end_frontier, &end_sapling_frontier, end_block)?;
+        report.phases.root_check_ms = now_ms() - root_check_start;
+
+        // the paths a spend of each marked note would need
+        let witness_start = now_ms();
+        let (orchard_witnesses, sapling_witnesses) =
+            state.check_witnesses(&end_frontier, &end_sapling_frontier, end_block)?;
+        report.orchard.witnesses = orchard_witnesses;
+        report.sapling.witnesses = sapling_witnesses;
+        report.phases.witness_ms = now_ms() - witness_start;
+    }
+    report.finish(start);
+
+    Ok(TreeSyncSummary {
+        orchard_tree_size: state.orchard_cursor.into(),
+        sapling_tree_size: state.sapling_cursor.into(),
+        report,
+    })
+}
+
+/// Download the blocks in the range and append their commitments to the trees, marking the ones at
+/// the witness positions. Returns the trees and a report of the blocks processed.
+/// If the chain reorganises part way through, the trees are rewound to the fork point and the blocks
+/// after it are taken from the new chain.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn insert_block_range<S: BlockSource + Clone + 'static>(
+    source: S,
+    mut state: TreeSyncState,
+    pool: &ShieldedPool,
+    start_block: u32,
+    end_block: u32,
+    block_batch_size: u32,
+    witnesses: &WitnessPositions,
+    options: &SyncOptions,
+) -> Result<(TreeSyncState, BenchReport), SyncError> {
+    options.check()?;
+    let mut report = BenchReport::default();
+    let mut next_height = start_block;
+    loop {
+        let mut pipeline = Pipeline::new(options.queue_depth as usize);
+        let blocks = pipeline.source(
+            "download",
+            download_block_batches(
+                source.clone(),
+                next_height,
+                end_block,
+                block_batch_size,
+                options.n_download_streams,
+                options.retry_policy,
+                &options.cancel,
+                options.checkpoint_interval,
+                state.checkpoint_hashes.clone(),
+            ),
+        );
+        let network = options.network;
+        let batch_pool = pool.clone();
+        let contents = pipeline.stage("convert", blocks, move |blocks| {
+            let pool = batch_pool.clone();
+            spawn_rayon(move || batch_contents(blocks, &network, &pool, u32::MAX))
+        });
+        let mut progress = ProgressTracker::blocks(
+            ProgressPhase::TreeSync,
+            next_height,
+            end_block,
+            options.progress.clone(),
+        );
+        let cancel = options.cancel.clone();
+        let checkpoint_interval = options.checkpoint_interval;
+        let insert_pool = pool.clone();
+        let witnesses = witnesses.clone();
+        let insert = async move {
+            let mut contents = contents;
+            let mut stats = StageStats::new("insert");
+            let mut report = report;
+            let mut state = state;
+            let mut reorg = None;
+            while let Some(batch) = contents.next().await {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                let batch = match batch {
+                    Ok(batch) => batch,
+                    Err(SyncError::Reorg { fork_height }) => {
+                        reorg = Some(fork_height as u32);
+                        break;
+                    }
+                    Err(e) => return (Err(e), stats),
+                };
+                report.add_batch(&batch);
+                let BatchContents {
+                    actions,
+                    outputs,
+                    blocks,
+                    block_ends,
+                    ..
+                } = batch;
+                let pool = insert_pool.clone();
+                // mark the commitments at the witness positions to maintain witnesses for
+                let orchard_marked = witnesses.orchard_batch(state.orchard_cursor, actions.len());
+                let sapling_marked = witnesses.sapling_batch(state.sapling_cursor, outputs.len());
+                // the trees are moved onto the rayon pool and back so the other stages keep running
+                let (inserted, checked) = stats
+                    .time(spawn_rayon(move || {
+                        state.insert_batch(
+                            actions,
+                            outputs,
+                            &orchard_marked,
+                            &sapling_marked,
+                            &block_ends,
+                            checkpoint_interval,
+                        );
+                        let checked = state.check_tree_sizes(&pool, &block_ends);
+                        (state, checked)
+                    }))
+                    .await;
+                state = inserted;
+                if let Err(e) = checked {
+                    return (Err(e), stats);
+                }
+                progress.advance(blocks);
+            }
+            (Ok((state, report, reorg)), stats)
+        };
+
+        let (result, pipeline) = pipeline.run(insert).await;
+        pipeline.log();
+        let reorg;
+        (state, report, reorg) = result?;
+        report.add_pipeline(pipeline);
+
+        // roll the trees back to the fork point and continue on the new chain from there, checking
+        // it follows on from the block rewound to
+        match reorg {
+            Some(fork_height) => {
+                let rewound = state.rewind_to(fork_height)?;
+                next_height = rewound + 1;
+                report.last_height = Some(rewound);
+                report.reorgs += 1;
+                console_log!("Rewound the trees to block {} after a reorg", rewound);
+            }
+            None => break,
+        }
+    }
+    report.check_cancelled(&options.cancel, end_block);
+
+    Ok((state, report))
+}
+
+/// The trees being synced and where the next commitments will be inserted
+pub(crate) struct TreeSyncState {
+    pub(crate) orchard_tree: OrchardCommitmentTree,
+    pub(crate) orchard_cursor: Position,
+    pub(crate) sapling_tree: SaplingCommitmentTree,
+    pub(crate) sapling_cursor: Position,
+    /// Number of commitments marked for witnessing in each tree
+    pub(crate) orchard_marked: u32,
+    pub(crate) sapling_marked: u32,
+    /// Height and hash of the blocks the trees are checkpointed at, oldest first, to find where the
+    /// chain forked from them on a reorg
+    pub(crate) checkpoint_hashes: Vec<(u32, Vec<u8>)>,
+}
+
+impl TreeSyncState {
+    /// Initialise the trees of the pools being synced from the frontiers as of the end of the block
+    /// at `height`. The tree of a pool that isn't synced is left empty.
+    pub(crate) async fn bootstrap(
+        source: &mut impl BlockSource,
+        pool: &ShieldedPool,
+        height: u32,
+    ) -> Result<Self, SyncError> {
+        let (orchard_tree, orchard_cursor) = if pool.sync_orchard() {
+            bootstrap_orchard_tree_from_lightwalletd(source, height).await?
+        } else {
+            (empty_tree(height)?, Position::from(0))
+        };
+        let (sapling_tree, sapling_cursor) = if pool.sync_sapling() {
+            bootstrap_sapling_tree_from_lightwalletd(source, height).await?
+        } else {
+            (empty_tree(height)?, Position::from(0))
+        };
+        let hash = block_hash(&source.tree_state(height).await?)?;
+        Ok(TreeSyncState {
+            orchard_tree,
+            orchard_cursor,
+            sapling_tree,
+            sapling_cursor,
+            orchard_marked: 0,
+            sapling_marked: 0,
+            checkpoint_hashes: vec![(height, hash)],
+        })
+    }
 
-    let mut orchard_witnesses_tracked = 0;
-    let mut sapling_witnesses_tracked = 0;
+    /// Initialise both trees with only the roots of their completed shards. The commitments of any
+    /// shard, including the incomplete one after them, can then be filled in with `fill_shard`.
+    pub(crate) fn from_subtree_roots(
+        orchard_roots: &[SubtreeRoot],
+        sapling_roots: &[SubtreeRoot],
+    ) -> Result<Self, SyncError> {
+        let mut orchard_tree =
+            OrchardCommitmentTree::new(OrchardMemoryShardStore::empty(), MAX_CHECKPOINTS);
+        insert_subtree_roots(&mut orchard_tree, orchard_roots)?;
+        let mut sapling_tree =
+            SaplingCommitmentTree::new(SaplingMemoryShardStore::empty(), MAX_CHECKPOINTS);
+        insert_subtree_roots(&mut sapling_tree, sapling_roots)?;
+        Ok(TreeSyncState {
+            orchard_tree,
+            orchard_cursor: Position::from((orchard_roots.len() as u64) << ORCHARD_SHARD_HEIGHT),
+            sapling_tree,
+            sapling_cursor: Position::from((sapling_roots.len() as u64) << SAPLING_SHARD_HEIGHT),
+            orchard_marked: 0,
+            sapling_marked: 0,
+            checkpoint_hashes: Vec::new(),
+        })
+    }
+
+    /// Insert the commitments of a shard, or the start of one, from its first position.
+    /// The cursor moves past them if they are the last in the tree.
+    pub(crate) fn fill_shard(&mut self, shard: ShardCommitments) {
+        match shard {
+            ShardCommitments::Orchard(start, commitments) => {
+                parallel_batch_add_commitments(&mut self.orchard_tree, start, &commitments);
+                self.orchard_marked += count_marked(&commitments);
+                self.orchard_cursor = self.orchard_cursor.max(start + commitments.len() as u64);
+            }
+            ShardCommitments::Sapling(start, commitments) => {
+                parallel_batch_add_commitments(&mut self.sapling_tree, start, &commitments);
+                self.sapling_marked += count_marked(&commitments);
+                self.sapling_cursor = self.sapling_cursor.max(start + commitments.len() as u64);
+            }
+        }
+    }
 
-    while let Some((actions, outputs)) = s.next().await {
+    /// Append a batch of commitments to the trees.
+    /// The commitments at the given (ascending) indices are marked so they can be witnessed later.
+    /// Both trees are checkpointed at the end of every block whose height is a multiple of
+    /// `checkpoint_interval`, and at the end of the batch so its root can be computed even when
+    /// nothing is marked.
+    pub(crate) fn insert_batch(
+        &mut self,
+        actions: Vec<(OrchardDomain, CompactAction)>,
+        outputs: Vec<(SaplingDomain, CompactOutputDescription)>,
+        orchard_marked: &[usize],
+        sapling_marked: &[usize],
+        block_ends: &[BlockEnd],
+        checkpoint_interval: u32,
+    ) {
+        let checkpoints = block_ends
+            .iter()
+            .enumerate()
+            .filter(|(i, end)| end.height % checkpoint_interval == 0 || i + 1 == block_ends.len())
+            .map(|(_, end)| end)
+            .collect::<Vec<_>>();
+        let orchard_retentions = batch_retentions(
+            &mut self.orchard_tree,
+            self.orchard_cursor,
+            actions.len(),
+            orchard_marked,
+            checkpoints.iter().map(|end| (end.height, end.actions)),
+        );
+        let sapling_retentions = batch_retentions(
+            &mut self.sapling_tree,
+            self.sapling_cursor,
+            outputs.len(),
+            sapling_marked,
+            checkpoints.iter().map(|end| (end.height, end.outputs)),
+        );
         let (added_orchard, added_sapling) = (actions.len(), outputs.len());
 
-        // Not the most readable code but what this is saying is to mark the first n_witness actions/outputs to maintain witnesses for
         batch_insert_from_orchard_actions(
-            &mut orchard_tree,
-            orchard_cursor,
-            actions.into_iter().map(|(domain, action)| {
-                (
-                    domain,
-                    action,
-                    if orchard_witnesses_tracked < n_witnesses {
-                        orchard_witnesses_tracked += 1;
-                        Retention::Marked
-                    } else {
-                        Retention::Ephemeral
-                    },
-                )
-            }),
+            &mut self.orchard_tree,
+            self.orchard_cursor,
+            actions
+                .into_iter()
+                .zip(orchard_retentions)
+                .map(|((domain, action), retention)| (domain, action, retention)),
         );
         batch_insert_from_sapling_outputs(
-            &mut sapling_tree,
-            sapling_cursor,
-            outputs.into_iter().map(|(domain, output)| {
-                (
-                    domain,
-                    output,
-                    if sapling_witnesses_tracked < n_witnesses {
-                        sapling_witnesses_tracked += 1;
-                        Retention::Marked
-                    } else {
-                        Retention::Ephemeral
-                    },
-                )
-            }),
+            &mut self.sapling_tree,
+            self.sapling_cursor,
+            outputs
+                .into_iter()
+                .zip(sapling_retentions)
+                .map(|((domain, output), retention)| (domain, output, retention)),
         );
 
-        orchard_cursor += added_orchard as u64;
-        sapling_cursor += added_sapling as u64;
+        self.orchard_cursor += added_orchard as u64;
+        self.sapling_cursor += added_sapling as u64;
+        self.orchard_marked += orchard_marked.len() as u32;
+        self.sapling_marked += sapling_marked.len() as u32;
+        self.add_checkpoint_hashes(
+            checkpoints
+                .into_iter()
+                .map(|end| (end.height, end.hash.clone())),
+        );
     }
 
-    if orchard_witnesses_tracked > 0 {
-        assert_eq!(
-            end_frontier.root(),
-            orchard_tree.root_at_checkpoint_depth(0).unwrap()
-        );
+    /// Checkpoint both trees at their latest commitments, which are the end of the block at `height`
+    pub(crate) fn checkpoint(&mut self, height: u32, hash: Vec<u8>) -> Result<(), SyncError> {
+        let tree_error = |e| SyncError::InvalidState(format!("{:?}", e));
+        self.orchard_tree
+            .checkpoint(height.into())
+            .map_err(tree_error)?;
+        self.sapling_tree
+            .checkpoint(height.into())
+            .map_err(tree_error)?;
+        self.add_checkpoint_hashes([(height, hash)]);
+        Ok(())
+    }
+
+    /// Checkpoint both trees at the end of the block at `height`, when they held the given numbers of
+    /// commitments. For trees filled in out of order, the commitment each checkpoint falls on must
+    /// have been inserted with a checkpoint retention so it is kept to be rewound to.
+    pub(crate) fn add_checkpoint(
+        &mut self,
+        height: u32,
+        hash: Vec<u8>,
+        orchard_size: u64,
+        sapling_size: u64,
+    ) {
+        add_checkpoint(&mut self.orchard_tree, height, orchard_size);
+        add_checkpoint(&mut self.sapling_tree, height, sapling_size);
+        self.add_checkpoint_hashes([(height, hash)]);
+    }
+
+    /// Remember the hashes of blocks the trees have been checkpointed at, keeping as many as the trees
+    /// keep checkpoints
+    pub(crate) fn add_checkpoint_hashes(
+        &mut self,
+        blocks: impl IntoIterator<Item = (u32, Vec<u8>)>,
+    ) {
+        self.checkpoint_hashes.extend(blocks);
+        let excess = self.checkpoint_hashes.len().saturating_sub(MAX_CHECKPOINTS);
+        self.checkpoint_hashes.drain(..excess);
+    }
+
+    /// Rewind both trees to the latest checkpoint they share at or below `height`, removing every
+    /// commitment added after it, so the blocks after the returned checkpoint height can be reapplied.
+    /// Fails if no such checkpoint is still retained.
+    pub(crate) fn rewind_to(&mut self, height: u32) -> Result<u32, SyncError> {
+        let orchard_ids = checkpoint_ids(&self.orchard_tree);
+        let checkpoint = checkpoint_ids(&self.sapling_tree)
+            .into_iter()
+            .filter(|id| *id <= height && orchard_ids.contains(id))
+            .max()
+            .ok_or(SyncError::NoCheckpoint {
+                height: height as u64,
+            })?;
+        self.orchard_cursor = rewind_tree(&mut self.orchard_tree, checkpoint);
+        self.sapling_cursor = rewind_tree(&mut self.sapling_tree, checkpoint);
+        self.orchard_marked = self.orchard_tree.marked_positions().unwrap().len() as u32;
+        self.sapling_marked = self.sapling_tree.marked_positions().unwrap().len() as u32;
+        self.checkpoint_hashes
+            .retain(|(checkpoint_height, _)| *checkpoint_height <= checkpoint);
+        Ok(checkpoint)
+    }
+
+    /// Compute the witness of every marked commitment as of the latest leaf in its tree and fail if
+    /// any of them doesn't lead to the anchor lightwalletd reports for `height`.
+    /// Returns the number of Orchard and Sapling witnesses checked.
+    pub(crate) fn check_witnesses(
+        &self,
+        orchard_anchor: &OrchardFrontier,
+        sapling_anchor: &SaplingFrontier,
+        height: u32,
+    ) -> Result<(u64, u64), SyncError> {
+        let orchard = check_witnesses(
+            &self.orchard_tree,
+            ShieldedPool::Orchard,
+            orchard_anchor.root(),
+            height,
+        )?;
+        let sapling = check_witnesses(
+            &self.sapling_tree,
+            ShieldedPool::Sapling,
+            sapling_anchor.root(),
+            height,
+        )?;
         console_log!(
-            "✅ Computed orchard root for block {} matches lightwalletd ✅",
-            end_block
+            "✅ Witnesses for {} Orchard and {} Sapling notes match the anchors for block {} ✅",
+            orchard,
+            sapling,
+            height
         );
+        Ok((orchard, sapling))
     }
 
-    (Into::<u64>::into(orchard_cursor) + Into::<u64>::into(sapling_cursor)) as f64
+    /// Check the cursors after inserting a batch match the tree sizes in the chain metadata of its
+    /// blocks, for the pools being synced. On a mismatch the error names the first block after which
+    /// the positions diverged.
+    pub(crate) fn check_tree_sizes(
+        &self,
+        pool: &ShieldedPool,
+        block_ends: &[BlockEnd],
+    ) -> Result<(), SyncError> {
+        let (actions, outputs) = match block_ends.last() {
+            Some(end) => (end.actions as u64, end.outputs as u64),
+            None => return Ok(()),
+        };
+        let orchard_start = u64::from(self.orchard_cursor) - actions;
+        let sapling_start = u64::from(self.sapling_cursor) - outputs;
+        for end in block_ends {
+            let checks = [
+                (
+                    ShieldedPool::Orchard,
+                    pool.sync_orchard(),
+                    end.orchard_tree_size,
+                    orchard_start + end.actions as u64,
+                ),
+                (
+                    ShieldedPool::Sapling,
+                    pool.sync_sapling(),
+                    end.sapling_tree_size,
+                    sapling_start + end.outputs as u64,
+                ),
+            ];
+            for (pool, synced, expected, found) in checks {
+                match expected {
+                    Some(expected) if synced && expected != found => {
+                        return Err(SyncError::TreeSizeMismatch {
+                            pool,
+                            height: end.height as u64,
+                            expected,
+                            found,
+                        })
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail if the computed root of a synced pool's tree doesn't match the one lightwalletd reports
+    /// for `height`
+    pub(crate) fn check_roots(
+        &self,
+        pool: &ShieldedPool,
+        expected_orchard: &OrchardFrontier,
+        expected_sapling: &SaplingFrontier,
+        height: u32,
+    ) -> Result<(), SyncError> {
+        if pool.sync_orchard() {
+            check_root(
+                &self.orchard_tree,
+                ShieldedPool::Orchard,
+                expected_orchard.root(),
+                height,
+            )?;
+            console_log!(
+                "✅ Computed orchard root for block {} matches lightwalletd ✅",
+                height
+            );
+        }
+        if pool.sync_sapling() {
+            check_root(
+                &self.sapling_tree,
+                ShieldedPool::Sapling,
+                expected_sapling.root(),
+                height,
+            )?;
+            console_log!(
+                "✅ Computed sapling root for block {} matches lightwalletd ✅",
+                height
+            );
+        }
+        Ok(())
+    }
 }
 
-async fn bootstrap_orchard_tree_from_lightwalletd(
-    client: &mut WasmGrpcClient,
-    height: u32,
-) -> (OrchardCommitmentTree, Position) {
-    let mut tree = OrchardCommitmentTree::new(OrchardMemoryShardStore::empty(), MAX_CHECKPOINTS);
+/// Commitments for one shard of a pool's tree, starting at the given position
+pub(crate) enum ShardCommitments {
+    Orchard(Position, Vec<(MerkleHashOrchard, Retention<BlockHeight>)>),
+    Sapling(Position, Vec<(sapling::Node, Retention<BlockHeight>)>),
+}
 
-    // fetch frontier at the end of the previous block
-    let init_frontier = fetch_orchard_frontier_at_height(client, height)
-        .await
+/// The retention of each of `len` commitments being appended to a tree from `start`, marking those at
+/// the given indices. The tree is checkpointed after each block in `checkpoints`, given as its height
+/// and the number of the commitments added up to its end. Checkpoints that don't fall on one of the
+/// new commitments are added to the tree directly.
+fn batch_retentions<S, H, const DEPTH: u8, const SHARD_HEIGHT: u8>(
+    tree: &mut ShardTree<S, DEPTH, SHARD_HEIGHT>,
+    start: Position,
+    len: usize,
+    marked: &[usize],
+    checkpoints: impl Iterator<Item = (u32, usize)>,
+) -> Vec<Retention<BlockHeight>>
+where
+    S: ShardStore<CheckpointId = BlockHeight, H = H>,
+    S::Error: std::fmt::Debug,
+    H: Hashable + Clone + PartialEq,
+{
+    let mut retentions = (0..len)
+        .map(|i| {
+            if marked.binary_search(&i).is_ok() {
+                Retention::Marked
+            } else {
+                Retention::Ephemeral
+            }
+        })
+        .collect::<Vec<_>>();
+    for (height, count) in checkpoints {
+        let id = BlockHeight::from(height);
+        match count.checked_sub(1) {
+            // nothing has been added yet, so the tree is checkpointed at its current tip
+            None => {
+                tree.checkpoint(id).unwrap();
+            }
+            // the block added nothing, so shares its last commitment with an earlier checkpoint
+            Some(last) if matches!(retentions[last], Retention::Checkpoint { .. }) => {
+                tree.store_mut()
+                    .add_checkpoint(id, Checkpoint::at_position(start + last as u64))
+                    .unwrap();
+            }
+            Some(last) => {
+                retentions[last] = Retention::Checkpoint {
+                    id,
+                    is_marked: retentions[last].is_marked(),
+                }
+            }
+        }
+    }
+    retentions
+}
+
+/// Checkpoint a tree at the end of the block at `height`, when it held `size` commitments
+fn add_checkpoint<S, H, const DEPTH: u8, const SHARD_HEIGHT: u8>(
+    tree: &mut ShardTree<S, DEPTH, SHARD_HEIGHT>,
+    height: u32,
+    size: u64,
+) where
+    S: ShardStore<CheckpointId = BlockHeight, H = H>,
+    S::Error: std::fmt::Debug,
+    H: Hashable + Clone + PartialEq,
+{
+    let checkpoint = match size.checked_sub(1) {
+        Some(last) => Checkpoint::at_position(Position::from(last)),
+        None => Checkpoint::tree_empty(),
+    };
+    tree.store_mut()
+        .add_checkpoint(BlockHeight::from(height), checkpoint)
         .unwrap();
+}
+
+/// The heights of the checkpoints a tree still retains
+fn checkpoint_ids<S, H, const DEPTH: u8, const SHARD_HEIGHT: u8>(
+    tree: &ShardTree<S, DEPTH, SHARD_HEIGHT>,
+) -> Vec<u32>
+where
+    S: ShardStore<CheckpointId = BlockHeight, H = H>,
+    S::Error: std::fmt::Debug,
+    H: Hashable + Clone + PartialEq,
+{
+    let store = tree.store();
+    (1..=store.checkpoint_count().unwrap())
+        .map(|depth| u32::from(store.get_checkpoint_at_depth(depth).unwrap().unwrap().0))
+        .collect()
+}
 
-    if let Some(frontier) = init_frontier.take() {
-        console_log!("Frontier was found for height {}: {:?}", height, frontier);
-        let start_position = frontier.position() + 1;
-        tree.insert_frontier_nodes(
-            frontier,
-            Retention::Checkpoint {
-                id: height.into(),
-                is_marked: false,
-            },
+/// Truncate a tree to the checkpoint at `height`, keeping the checkpoint so it can be rewound to
+/// again, and return the position the next commitment goes in
+fn rewind_tree<S, H, const DEPTH: u8, const SHARD_HEIGHT: u8>(
+    tree: &mut ShardTree<S, DEPTH, SHARD_HEIGHT>,
+    height: u32,
+) -> Position
+where
+    S: ShardStore<CheckpointId = BlockHeight, H = H>,
+    S::Error: std::fmt::Debug,
+    H: Hashable + Clone + PartialEq,
+{
+    let id = BlockHeight::from(height);
+    assert!(tree.truncate_removing_checkpoint(&id).unwrap());
+    tree.checkpoint(id).unwrap();
+    tree.max_leaf_position(0)
+        .unwrap()
+        .map_or(Position::from(0), |position| position + 1)
+}
+
+fn count_marked<H>(commitments: &[(H, Retention<BlockHeight>)]) -> u32 {
+    commitments.iter().filter(|(_, r)| r.is_marked()).count() as u32
+}
+
+/// Fetch the roots of the shards of a pool's tree that were completed by the block at `height`
+pub(crate) async fn fetch_subtree_roots(
+    source: &mut impl BlockSource,
+    protocol: ShieldedProtocol,
+    height: u32,
+) -> Result<Vec<SubtreeRoot>, SyncError> {
+    let mut roots = source.subtree_roots(protocol, 0).await?;
+    // the source may be ahead of the range being synced
+    let completed = roots.partition_point(|r| r.completing_block_height <= height as u64);
+    roots.truncate(completed);
+    Ok(roots)
+}
+
+fn insert_subtree_roots<S, H, const DEPTH: u8, const SHARD_HEIGHT: u8>(
+    tree: &mut ShardTree<S, DEPTH, SHARD_HEIGHT>,
+    roots: &[SubtreeRoot],
+) -> Result<(), SyncError>
+where
+    S: ShardStore<CheckpointId = BlockHeight, H = H>,
+    S::Error: std::fmt::Debug,
+    H: Hashable + HashSer + Clone + PartialEq,
+{
+    for (index, root) in roots.iter().enumerate() {
+        let hash = H::read(root.root_hash.as_slice()).map_err(|e| SyncError::MalformedField {
+            height: root.completing_block_height,
+            reason: format!("Invalid root for subtree {}: {}", index, e),
+        })?;
+        tree.insert(
+            Address::from_parts(Level::from(SHARD_HEIGHT), index as u64),
+            hash,
         )
         .unwrap();
-        (tree, start_position)
-    } else {
-        panic!("No frontier found for height {}", height);
     }
+    Ok(())
 }
 
-async fn bootstrap_sapling_tree_from_lightwalletd(
-    client: &mut WasmGrpcClient,
+fn check_root<S, H, const DEPTH: u8, const SHARD_HEIGHT: u8>(
+    tree: &ShardTree<S, DEPTH, SHARD_HEIGHT>,
+    pool: ShieldedPool,
+    expected: H,
     height: u32,
-) -> (SaplingCommitmentTree, Position) {
-    let mut tree = SaplingCommitmentTree::new(SaplingMemoryShardStore::empty(), MAX_CHECKPOINTS);
+) -> Result<(), SyncError>
+where
+    S: ShardStore<CheckpointId = BlockHeight, H = H>,
+    S::Error: std::fmt::Debug,
+    H: Hashable + Clone + PartialEq + std::fmt::Debug,
+{
+    let root = tree
+        .root_at_checkpoint_depth(0)
+        .map_err(|e| SyncError::InvalidState(format!("{:?}", e)))?;
+    if root != expected {
+        return Err(SyncError::RootMismatch {
+            pool,
+            height: height as u64,
+            expected: format!("{:?}", expected),
+            found: format!("{:?}", root),
+        });
+    }
+    Ok(())
+}
 
+fn check_witnesses<S, H, const DEPTH: u8, const SHARD_HEIGHT: u8>(
+    tree: &ShardTree<S, DEPTH, SHARD_HEIGHT>,
+    pool: ShieldedPool,
+    anchor: H,
+    height: u32,
+) -> Result<u64, SyncError>
+where
+    S: ShardStore<CheckpointId = BlockHeight, H = H>,
+    S::Error: std::fmt::Debug,
+    H: Hashable + Clone + PartialEq + std::fmt::Debug,
+{
+    let tree_error = |e| SyncError::InvalidState(format!("{:?}", e));
+    let positions = tree.marked_positions().map_err(tree_error)?;
+    for &position in &positions {
+        let leaf = tree
+            .get_marked_leaf(position)
+            .map_err(tree_error)?
+            .ok_or_else(|| SyncError::InvalidState(format!("No marked leaf at {:?}", position)))?;
+        let witness = tree
+            .witness_at_checkpoint_depth(position, 0)
+            .map_err(tree_error)?;
+        let root = witness.root(leaf);
+        if root != anchor {
+            return Err(SyncError::WitnessMismatch {
+                pool,
+                height: height as u64,
+                position: position.into(),
+                expected: format!("{:?}", anchor),
+                found: format!("{:?}", root),
+            });
+        }
+    }
+    Ok(positions.len() as u64)
+}
+
+/// The Orchard tree as of the end of the block at `height`, and the position of the next
+/// commitment. The tree is empty if the block is before any commitments were added to it.
+async fn bootstrap_orchard_tree_from_lightwalletd(
+    source: &mut impl BlockSource,
+    height: u32,
+) -> Result<(OrchardCommitmentTree, Position), SyncError> {
     // fetch frontier at the end of the previous block
-    let init_frontier = fetch_sapling_frontier_at_height(client, height)
-        .await
-        .unwrap();
+    let init_frontier = fetch_orchard_frontier_at_height(source, height).await?;
 
-    if let Some(frontier) = init_frontier.take() {
-        console_log!("Frontier was found for height {}: {:?}", height, frontier);
-        let start_position = frontier.position() + 1;
-        tree.insert_frontier_nodes(
-            frontier,
-            Retention::Checkpoint {
-                id: height.into(),
-                is_marked: false,
-            },
-        )
-        .unwrap();
-        (tree, start_position)
-    } else {
-        panic!("No frontier found for height {}", height);
+    match init_frontier.take() {
+        Some(frontier) => {
+            console_log!("Frontier was found for height {}: {:?}", height, frontier);
+            let start_position = frontier.position() + 1;
+            let mut tree = ShardTree::new(MemoryShardStore::empty(), MAX_CHECKPOINTS);
+            tree.insert_frontier_nodes(
+                frontier,
+                Retention::Checkpoint {
+                    id: height.into(),
+                    is_marked: false,
+                },
+            )
+            .map_err(|e| SyncError::MalformedField {
+                height: height as u64,
+                reason: format!("{:?}", e),
+            })?;
+            Ok((tree, start_position))
+        }
+        None => {
+            console_log!("The Orchard tree is empty at height {}", height);
+            Ok((empty_tree(height)?, Position::from(0)))
+        }
+    }
+}
+
+/// The Sapling tree as of the end of the block at `height`, and the position of the next
+/// commitment. The tree is empty if the block is before any commitments were added to it.
+async fn bootstrap_sapling_tree_from_lightwalletd(
+    source: &mut impl BlockSource,
+    height: u32,
+) -> Result<(SaplingCommitmentTree, Position), SyncError> {
+    // fetch frontier at the end of the previous block
+    let init_frontier = fetch_sapling_frontier_at_height(source, height).await?;
+
+    match init_frontier.take() {
+        Some(frontier) => {
+            console_log!("Frontier was found for height {}: {:?}", height, frontier);
+            let start_position = frontier.position() + 1;
+            let mut tree = ShardTree::new(MemoryShardStore::empty(), MAX_CHECKPOINTS);
+            tree.insert_frontier_nodes(
+                frontier,
+                Retention::Checkpoint {
+                    id: height.into(),
+                    is_marked: false,
+                },
+            )
+            .map_err(|e| SyncError::MalformedField {
+                height: height as u64,
+                reason: format!("{:?}", e),
+            })?;
+            Ok((tree, start_position))
+        }
+        None => {
+            console_log!("The Sapling tree is empty at height {}", height);
+            Ok((empty_tree(height)?, Position::from(0)))
+        }
     }
 }
 
-async fn fetch_orchard_frontier_at_height(
-    client: &mut WasmGrpcClient,
+/// A tree with no commitments, checkpointed at `height` so it can be rewound to it like a bootstrapped one
+fn empty_tree<H, const DEPTH: u8, const SHARD_HEIGHT: u8>(
+    height: u32,
+) -> Result<ShardTree<MemoryShardStore<H, BlockHeight>, DEPTH, SHARD_HEIGHT>, SyncError>
+where
+    H: Hashable + Clone + PartialEq,
+{
+    let mut tree = ShardTree::new(MemoryShardStore::empty(), MAX_CHECKPOINTS);
+    tree.checkpoint(height.into())
+        .map_err(|e| SyncError::InvalidState(format!("{:?}", e)))?;
+    Ok(tree)
+}
+
+/// The Orchard and Sapling frontiers at the end of the block at `height`. A pool that isn't being
+/// synced gets an empty frontier rather than being fetched.
+async fn fetch_end_frontiers(
+    source: &mut impl BlockSource,
+    pool: &ShieldedPool,
     height: u32,
-) -> anyhow::Result<OrchardFrontier> {
-    let start = proto::service::BlockId {
+) -> Result<(OrchardFrontier, SaplingFrontier), SyncError> {
+    let orchard = match pool.sync_orchard() {
+        true => fetch_orchard_frontier_at_height(source, height).await?,
+        false => Frontier::empty(),
+    };
+    let sapling = match pool.sync_sapling() {
+        true => fetch_sapling_frontier_at_height(source, height).await?,
+        false => Frontier::empty(),
+    };
+    Ok((orchard, sapling))
+}
+
+pub(crate) async fn fetch_orchard_frontier_at_height(
+    source: &mut impl BlockSource,
+    height: u32,
+) -> Result<OrchardFrontier, SyncError> {
+    let pb_tree_state = source.tree_state(height).await?;
+    let malformed = |reason: String| SyncError::MalformedField {
         height: height as u64,
-        hash: vec![],
+        reason,
     };
-    let pb_tree_state = client.get_tree_state(start).await?.into_inner();
-    let tree_frontier_bytes = hex::decode(pb_tree_state.orchard_tree)?;
+    let tree_frontier_bytes =
+        hex::decode(pb_tree_state.orchard_tree).map_err(|e| malformed(e.to_string()))?;
+    // lightwalletd reports no tree at all for a pool that hasn't activated yet
+    if tree_frontier_bytes.is_empty() {
+        return Ok(Frontier::empty());
+    }
 
-    let frontier: OrchardFrontier = read_frontier_v0(Cursor::new(tree_frontier_bytes))?;
+    let frontier: OrchardFrontier =
+        read_frontier_v0(Cursor::new(tree_frontier_bytes)).map_err(|e| malformed(e.to_string()))?;
     Ok(frontier)
 }
 
-async fn fetch_sapling_frontier_at_height(
-    client: &mut WasmGrpcClient,
+pub(crate) async fn fetch_sapling_frontier_at_height(
+    source: &mut impl BlockSource,
     height: u32,
-) -> anyhow::Result<SaplingFrontier> {
-    let start = proto::service::BlockId {
+) -> Result<SaplingFrontier, SyncError> {
+    let pb_tree_state = source.tree_state(height).await?;
+    let malformed = |reason: String| SyncError::MalformedField {
         height: height as u64,
-        hash: vec![],
+        reason,
     };
-    let pb_tree_state = client.get_tree_state(start).await?.into_inner();
-    let tree_frontier_bytes = hex::decode(pb_tree_state.sapling_tree)?;
+    let tree_frontier_bytes =
+        hex::decode(pb_tree_state.sapling_tree).map_err(|e| malformed(e.to_string()))?;
+    // lightwalletd reports no tree at all for a pool that hasn't activated yet
+    if tree_frontier_bytes.is_empty() {
+        return Ok(Frontier::empty());
+    }
 
-    let frontier: SaplingFrontier = read_frontier_v0(Cursor::new(tree_frontier_bytes))?;
+    let frontier: SaplingFrontier =
+        read_frontier_v0(Cursor::new(tree_frontier_bytes)).map_err(|e| malformed(e.to_string()))?;
     Ok(frontier)
 }
 
@@ -256,10 +1003,18 @@ fn batch_insert_from_sapling_outputs(
     parallel_batch_add_commitments(tree, start_position, &commitments);
 }
 
+/// Fewest commitments worth building into a subtree of their own
+const MIN_CHUNK_SIZE: u64 = 256;
+
 /// Use rayon to parallelize adding batch of commitments to the tree by building the shards
 /// in parallel then adding them in after
 /// based on the code here (https://github.com/zcash/librustzcash/blob/b3d06ba41904965f3b8165011e14e1d13b3c7b81/zcash_client_sqlite/src/lib.rs#L730)
-fn parallel_batch_add_commitments<S, H, const DEPTH: u8, const SHARD_HEIGHT: u8>(
+///
+/// The commitments are split into power of two sized chunks aligned to their size, so no chunk
+/// straddles a shard of the tree whatever its depth and shard height. The chunk size gives each
+/// thread in the pool about one chunk, between `MIN_CHUNK_SIZE` and a whole shard.
+/// The result is the same as a sequential `ShardTree::batch_insert` of the commitments.
+pub fn parallel_batch_add_commitments<S, H, const DEPTH: u8, const SHARD_HEIGHT: u8>(
     tree: &mut ShardTree<S, DEPTH, SHARD_HEIGHT>,
     start_position: Position,
     commitments: &[(S::H, Retention<BlockHeight>)],
@@ -267,20 +1022,30 @@ fn parallel_batch_add_commitments<S, H, const DEPTH: u8, const SHARD_HEIGHT: u8>
     S: ShardStore<CheckpointId = BlockHeight, H = H>,
     H: Hashable + Send + Sync + Clone + PartialEq + Copy,
 {
-    // Create subtrees from the note commitments in parallel.
-    const CHUNK_SIZE: usize = 1024;
-
-    let subtrees = commitments
-        .par_chunks(CHUNK_SIZE)
-        .enumerate()
-        .filter_map(|(i, chunk)| {
-            let start = start_position + (i * CHUNK_SIZE) as u64;
-            let end = start + chunk.len() as u64;
+    let start = u64::from(start_position);
+    let end = start + commitments.len() as u64;
+    let chunk_size = (commitments.len() as u64)
+        .div_ceil(rayon::current_num_threads() as u64)
+        .next_power_of_two()
+        .max(MIN_CHUNK_SIZE)
+        .min(1 << SHARD_HEIGHT);
+    let mut chunks = vec![];
+    let mut position = start;
+    while position < end {
+        let chunk_end = ((position / chunk_size + 1) * chunk_size).min(end);
+        chunks.push(position..chunk_end);
+        position = chunk_end;
+    }
 
+    // Create subtrees from the note commitments in parallel.
+    let subtrees = chunks
+        .into_par_iter()
+        .filter_map(|chunk| {
+            let values = &commitments[(chunk.start - start) as usize..(chunk.end - start) as usize];
             shardtree::LocatedTree::from_iter(
-                start..end,
-                ORCHARD_SHARD_HEIGHT.into(),
-                chunk.iter().map(|(cmx, retention)| (*cmx, *retention)),
+                Position::from(chunk.start)..Position::from(chunk.end),
+                Level::from(SHARD_HEIGHT),
+                values.iter().map(|(cmx, retention)| (*cmx, *retention)),
                 // note that all leaves marked ephemeral  (all but the first added) will be pruned out
                 // once they have been used to updated any witnesses the tree is tracking
             )
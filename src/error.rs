@@ -0,0 +1,165 @@
+use std::fmt;
+
+use crate::bench_params::ShieldedPool;
+
+/// Errors that can occur while syncing blocks from a block source
+#[derive(Debug)]
+pub enum SyncError {
+    /// The block source could not be reached or the connection dropped mid-stream
+    Transport(anyhow::Error),
+    /// A message from the block source could not be decoded
+    Decode(anyhow::Error),
+    /// A block contained a field that is not a valid encoding (e.g. a non-canonical commitment)
+    MalformedField { height: u64, reason: String },
+    /// The source returned a block other than the next one expected
+    HeightGap { expected: u64, found: u64 },
+    /// The block source serves a different chain than the one the benchmark was configured for
+    NetworkMismatch { expected: String, found: String },
+    /// The range starts before the pool being synced activated on the network
+    BeforeActivation {
+        start: u64,
+        activation: u64,
+        pool: ShieldedPool,
+    },
+    /// The benchmark was given options it can't run with
+    InvalidOptions(String),
+    /// A saved sync state couldn't be loaded or doesn't fit the sync it was given to
+    InvalidState(String),
+    /// A commitment tree's size after a block doesn't match the size in the block's chain metadata
+    TreeSizeMismatch {
+        pool: ShieldedPool,
+        height: u64,
+        expected: u64,
+        found: u64,
+    },
+    /// A commitment tree's root at the end of a sync doesn't match the one the block source reports
+    RootMismatch {
+        pool: ShieldedPool,
+        height: u64,
+        expected: String,
+        found: String,
+    },
+    /// The witness of a marked commitment doesn't lead to the anchor the block source reports
+    WitnessMismatch {
+        pool: ShieldedPool,
+        height: u64,
+        position: u64,
+        expected: String,
+        found: String,
+    },
+    /// The blocks filling in a shard of a commitment tree didn't hold all of its commitments
+    IncompleteShard {
+        pool: ShieldedPool,
+        index: u64,
+        expected: u64,
+        found: u64,
+    },
+    /// The commitment trees no longer retain a checkpoint at or below the height to rewind to
+    NoCheckpoint { height: u64 },
+    /// The chain reorganised: a block didn't link to the one before it, and the source's chain no longer
+    /// has the blocks received after `fork_height`. Syncs roll back to the fork point and continue.
+    Reorg { fork_height: u64 },
+}
+
+impl SyncError {
+    /// Whether reconnecting to the source could resolve this error
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, SyncError::Transport(_))
+    }
+}
+
+impl From<anyhow::Error> for SyncError {
+    fn from(e: anyhow::Error) -> Self {
+        if e.downcast_ref::<prost::DecodeError>().is_some() {
+            SyncError::Decode(e)
+        } else {
+            SyncError::Transport(e)
+        }
+    }
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncError::Transport(e) => write!(f, "Transport error: {:#}", e),
+            SyncError::Decode(e) => write!(f, "Failed to decode message: {:#}", e),
+            SyncError::MalformedField { height, reason } => {
+                write!(f, "Malformed field in block {}: {}", height, reason)
+            }
+            SyncError::HeightGap { expected, found } => write!(
+                f,
+                "Expected block at height {} but received {}",
+                expected, found
+            ),
+            SyncError::NetworkMismatch { expected, found } => write!(
+                f,
+                "Block source is on the '{}' chain but the benchmark expects '{}'",
+                found, expected
+            ),
+            SyncError::BeforeActivation {
+                start,
+                activation,
+                pool,
+            } => write!(
+                f,
+                "Cannot sync the {:?} pool from height {} as it activated at height {}",
+                pool, start, activation
+            ),
+            SyncError::InvalidOptions(reason) => write!(f, "Invalid options: {}", reason),
+            SyncError::InvalidState(reason) => write!(f, "Invalid sync state: {}", reason),
+            SyncError::TreeSizeMismatch {
+                pool,
+                height,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{:?} tree positions diverge at block {}: the tree has {} commitments but the block's chain metadata says {}",
+                pool, height, found, expected
+            ),
+            SyncError::RootMismatch {
+                pool,
+                height,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Computed {:?} root {} for block {} doesn't match the block source's {}",
+                pool, found, height, expected
+            ),
+            SyncError::WitnessMismatch {
+                pool,
+                height,
+                position,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Witness for {:?} position {} leads to {} rather than the anchor {} of block {}",
+                pool, position, found, expected, height
+            ),
+            SyncError::IncompleteShard {
+                pool,
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Expected {} commitments in {:?} shard {} but the blocks held {}",
+                expected, pool, index, found
+            ),
+            SyncError::NoCheckpoint { height } => write!(
+                f,
+                "The commitment trees have no checkpoint at or below block {} to rewind to",
+                height
+            ),
+            SyncError::Reorg { fork_height } => write!(
+                f,
+                "The chain reorganised, replacing the blocks after {}",
+                fork_height
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
@@ -0,0 +1,333 @@
+/**
+ * Record and replay of block fixtures so benchmarks can be rerun offline over exactly the same blocks.
+ *
+ * A fixture is a pair of files (or byte buffers), each a sequence of length-delimited protobuf messages.
+ * One holds the `CompactBlock`s of a range in ascending height order and the other the `TreeState`s
+ * the benchmarks query while bootstrapping and verifying the commitment trees. Fixtures can also hold
+ * the `SubtreeRoot`s of each pool, which spend-before-sync needs.
+ */
+use std::cell::RefCell;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use futures_util::{StreamExt, TryStreamExt};
+use prost::Message;
+use wasm_bindgen::prelude::*;
+
+use crate::bench_params::BenchParams;
+use crate::block_source::{BlockSource, BlockStream, MemoryBlockSource};
+use crate::console_log;
+use crate::new_compact_streamer_client;
+use crate::proto::compact_formats::CompactBlock;
+use crate::proto::service::{ShieldedProtocol, SubtreeRoot, TreeState};
+
+/// File names used when a fixture is stored in a directory
+pub const BLOCKS_FILE: &str = "blocks.bin";
+pub const TREE_STATES_FILE: &str = "tree_states.bin";
+/// Optional fixture files containing length-delimited `SubtreeRoot`s for each pool
+pub const SAPLING_SUBTREE_ROOTS_FILE: &str = "sapling_subtree_roots.bin";
+pub const ORCHARD_SUBTREE_ROOTS_FILE: &str = "orchard_subtree_roots.bin";
+
+/// A recorded range of blocks and the tree states needed to sync over it, held in memory
+/// so it can be passed to and from JS.
+#[wasm_bindgen]
+#[derive(Clone, Debug, Default)]
+pub struct BlockFixture {
+    blocks: Vec<u8>,
+    tree_states: Vec<u8>,
+    /// Length-delimited `SubtreeRoot`s of each pool, empty if none were recorded
+    sapling_subtree_roots: Vec<u8>,
+    orchard_subtree_roots: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl BlockFixture {
+    #[wasm_bindgen(constructor)]
+    pub fn new(blocks: Vec<u8>, tree_states: Vec<u8>) -> BlockFixture {
+        BlockFixture {
+            blocks,
+            tree_states,
+            ..Default::default()
+        }
+    }
+
+    /// Also replay the given length-delimited subtree roots of each pool
+    #[wasm_bindgen(js_name = withSubtreeRoots)]
+    pub fn with_subtree_roots(mut self, sapling: Vec<u8>, orchard: Vec<u8>) -> BlockFixture {
+        self.sapling_subtree_roots = sapling;
+        self.orchard_subtree_roots = orchard;
+        self
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn blocks(&self) -> Vec<u8> {
+        self.blocks.clone()
+    }
+
+    #[wasm_bindgen(getter = treeStates)]
+    pub fn tree_states(&self) -> Vec<u8> {
+        self.tree_states.clone()
+    }
+
+    #[wasm_bindgen(getter = saplingSubtreeRoots)]
+    pub fn sapling_subtree_roots(&self) -> Vec<u8> {
+        self.sapling_subtree_roots.clone()
+    }
+
+    #[wasm_bindgen(getter = orchardSubtreeRoots)]
+    pub fn orchard_subtree_roots(&self) -> Vec<u8> {
+        self.orchard_subtree_roots.clone()
+    }
+}
+
+impl BlockFixture {
+    /// Decode the fixture into a block source that replays it
+    pub fn to_block_source(&self) -> anyhow::Result<MemoryBlockSource> {
+        let blocks = LengthDelimitedReader::<CompactBlock, _>::new(self.blocks.as_slice())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let tree_states = LengthDelimitedReader::<TreeState, _>::new(self.tree_states.as_slice())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let roots = |bytes: &[u8]| -> anyhow::Result<Vec<SubtreeRoot>> {
+            LengthDelimitedReader::new(bytes).collect()
+        };
+        Ok(
+            MemoryBlockSource::new(blocks, tree_states).with_subtree_roots(
+                roots(&self.sapling_subtree_roots)?,
+                roots(&self.orchard_subtree_roots)?,
+            ),
+        )
+    }
+
+    /// Load a fixture directory containing the standard blocks and tree states files,
+    /// and the subtree roots files if it has them
+    pub fn read_dir(dir: impl AsRef<Path>) -> std::io::Result<BlockFixture> {
+        let dir = dir.as_ref();
+        let read_optional = |name: &str| match std::fs::read(dir.join(name)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            read => read,
+        };
+        Ok(BlockFixture::new(
+            std::fs::read(dir.join(BLOCKS_FILE))?,
+            std::fs::read(dir.join(TREE_STATES_FILE))?,
+        )
+        .with_subtree_roots(
+            read_optional(SAPLING_SUBTREE_ROOTS_FILE)?,
+            read_optional(ORCHARD_SUBTREE_ROOTS_FILE)?,
+        ))
+    }
+
+    /// Save the fixture as a directory that can be loaded with `read_dir` or served by the mock lightwalletd.
+    /// The subtree roots files are only written for pools that have roots.
+    pub fn write_dir(&self, dir: impl AsRef<Path>) -> std::io::Result<()> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join(BLOCKS_FILE), &self.blocks)?;
+        std::fs::write(dir.join(TREE_STATES_FILE), &self.tree_states)?;
+        for (name, roots) in [
+            (SAPLING_SUBTREE_ROOTS_FILE, &self.sapling_subtree_roots),
+            (ORCHARD_SUBTREE_ROOTS_FILE, &self.orchard_subtree_roots),
+        ] {
+            if !roots.is_empty() {
+                std::fs::write(dir.join(name), roots)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Download the blocks and tree states for the range in the params from lightwalletd and
+/// return them as a fixture which can be saved and later replayed with `BenchParams.withFixture`
+#[wasm_bindgen]
+pub async fn record_block_fixture(params: BenchParams) -> Result<BlockFixture, JsError> {
+    console_log!("Recording block fixture with params: {:?}", params);
+
+    let client = new_compact_streamer_client(&params.lightwalletd_url)?;
+    let (blocks, tree_states) = record_block_range(
+        client,
+        params.start_block,
+        params.end_block,
+        Vec::new(),
+        Vec::new(),
+    )
+    .await
+    .map_err(|e| JsError::new(&format!("{:#}", e)))?;
+    let fixture = BlockFixture::new(blocks, tree_states);
+    console_log!(
+        "Recorded {} bytes of blocks and {} bytes of tree states",
+        fixture.blocks.len(),
+        fixture.tree_states.len()
+    );
+    Ok(fixture)
+}
+
+/// Record the blocks in [start, end] along with the tree states at `start - 1` and `end`
+/// (the ones used to bootstrap and check the commitment trees) to the given writers.
+/// The writers are returned once the recording is complete.
+pub async fn record_block_range<S: BlockSource, BW: Write + 'static, TW: Write>(
+    source: S,
+    start: u32,
+    end: u32,
+    blocks_writer: BW,
+    tree_states_writer: TW,
+) -> anyhow::Result<(BW, TW)> {
+    let mut recorder = RecordingBlockSource::new(source, blocks_writer, tree_states_writer);
+
+    recorder.tree_state(start - 1).await?;
+    recorder.tree_state(end).await?;
+
+    let mut latest_recorded = start - 1;
+    while latest_recorded < end {
+        let mut blocks = recorder.block_range(latest_recorded + 1, end).await?;
+        let mut received = 0;
+        while let Some(block) = blocks.try_next().await? {
+            latest_recorded = block.height as u32;
+            received += 1;
+        }
+        if received == 0 {
+            anyhow::bail!("Source ended at height {} before {}", latest_recorded, end);
+        }
+    }
+    recorder.flush()?;
+    recorder.into_writers()
+}
+
+/// A block source which passes everything through from an inner source while teeing every block
+/// and tree state it yields into length-delimited writers. The output can be replayed with
+/// `FileBlockSource` or `BlockFixture`.
+pub struct RecordingBlockSource<S, BW, TW> {
+    inner: S,
+    blocks_writer: Rc<RefCell<BW>>,
+    tree_states_writer: TW,
+}
+
+impl<S, BW: Write, TW: Write> RecordingBlockSource<S, BW, TW> {
+    pub fn new(inner: S, blocks_writer: BW, tree_states_writer: TW) -> Self {
+        Self {
+            inner,
+            blocks_writer: Rc::new(RefCell::new(blocks_writer)),
+            tree_states_writer,
+        }
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.blocks_writer.borrow_mut().flush()?;
+        self.tree_states_writer.flush()
+    }
+
+    /// Recover the writers. Fails if a block stream from this source is still alive.
+    pub fn into_writers(self) -> anyhow::Result<(BW, TW)> {
+        let blocks_writer = Rc::try_unwrap(self.blocks_writer)
+            .map_err(|_| anyhow::anyhow!("Block stream still in use"))?
+            .into_inner();
+        Ok((blocks_writer, self.tree_states_writer))
+    }
+}
+
+impl<S: BlockSource, BW: Write + 'static, TW: Write> BlockSource
+    for RecordingBlockSource<S, BW, TW>
+{
+    async fn block_range(&mut self, start: u32, end: u32) -> anyhow::Result<BlockStream> {
+        let writer = self.blocks_writer.clone();
+        Ok(self
+            .inner
+            .block_range(start, end)
+            .await?
+            .and_then(move |block| {
+                let written = write_length_delimited(&mut *writer.borrow_mut(), &block);
+                futures_util::future::ready(written.map(|_| block).map_err(Into::into))
+            })
+            .boxed_local())
+    }
+
+    async fn tree_state(&mut self, height: u32) -> anyhow::Result<TreeState> {
+        let tree_state = self.inner.tree_state(height).await?;
+        write_length_delimited(&mut self.tree_states_writer, &tree_state)?;
+        Ok(tree_state)
+    }
+
+    async fn latest_height(&mut self) -> anyhow::Result<u32> {
+        self.inner.latest_height().await
+    }
+
+    async fn subtree_roots(
+        &mut self,
+        protocol: ShieldedProtocol,
+        start_index: u32,
+    ) -> anyhow::Result<Vec<SubtreeRoot>> {
+        self.inner.subtree_roots(protocol, start_index).await
+    }
+
+    async fn chain_name(&mut self) -> anyhow::Result<String> {
+        self.inner.chain_name().await
+    }
+}
+
+/// Write a single message prefixed by its varint encoded length
+pub fn write_length_delimited<M: Message>(
+    writer: &mut impl Write,
+    message: &M,
+) -> std::io::Result<()> {
+    writer.write_all(&message.encode_length_delimited_to_vec())
+}
+
+/// Iterator over the length-delimited protobuf messages in a reader
+pub(crate) struct LengthDelimitedReader<M, R> {
+    reader: BufReader<R>,
+    /// Bytes read so far, which is the offset of the next message from where reading started
+    offset: u64,
+    _message: std::marker::PhantomData<M>,
+}
+
+impl<M, R: Read> LengthDelimitedReader<M, R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            offset: 0,
+            _message: std::marker::PhantomData,
+        }
+    }
+
+    /// Offset of the next message from where reading started
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Read the varint length prefix, or None if the reader is cleanly at EOF
+    fn read_len(&mut self) -> anyhow::Result<Option<usize>> {
+        let mut len = 0u64;
+        for i in 0..10 {
+            let mut byte = [0u8; 1];
+            if self.reader.read(&mut byte)? == 0 {
+                if i == 0 {
+                    return Ok(None);
+                }
+                anyhow::bail!("Unexpected end of file in message length");
+            }
+            self.offset += 1;
+            len |= ((byte[0] & 0x7f) as u64) << (7 * i);
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some(len as usize));
+            }
+        }
+        anyhow::bail!("Invalid message length varint")
+    }
+}
+
+impl<M: Message + Default, R: Read> Iterator for LengthDelimitedReader<M, R> {
+    type Item = anyhow::Result<M>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = match self.read_len() {
+            Ok(Some(len)) => len,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e)),
+        };
+        let mut buf = vec![0u8; len];
+        if let Err(e) = self.reader.read_exact(&mut buf) {
+            return Some(Err(e.into()));
+        }
+        self.offset += len as u64;
+        Some(M::decode(buf.as_slice()).map_err(Into::into))
+    }
+}
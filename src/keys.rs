@@ -0,0 +1,132 @@
+use std::convert::TryInto;
+
+use orchard::keys::{FullViewingKey, Scope};
+use sapling::zip32::DiversifiableFullViewingKey;
+use zcash_keys::encoding::decode_extended_full_viewing_key;
+use zcash_keys::keys::UnifiedFullViewingKey;
+use zcash_primitives::consensus::{self, NetworkConstants};
+
+use wasm_bindgen::JsError;
+
+use crate::bench_params::Network;
+use crate::trial_decryption::{dummy_ivk_orchard, dummy_ivk_sapling};
+
+/// The prepared incoming viewing keys a wallet sync trial decrypts with
+#[derive(Clone, Debug)]
+pub struct WalletKeys {
+    /// The network the keys belong to, used to encode the recipients of decrypted notes
+    pub network: consensus::Network,
+    pub orchard: Vec<orchard::keys::PreparedIncomingViewingKey>,
+    pub sapling: Vec<sapling::note_encryption::PreparedIncomingViewingKey>,
+    /// Keys for deriving the nullifiers of notes received by the IVK at the same index.
+    /// Empty when the keys can't detect spends (e.g. dummy keys).
+    pub orchard_nullifier_keys: Vec<FullViewingKey>,
+    pub sapling_nullifier_keys: Vec<sapling::keys::NullifierDerivingKey>,
+}
+
+impl WalletKeys {
+    /// Prepare the external and internal incoming viewing keys for each of the full viewing keys
+    pub fn from_fvks(
+        network: &Network,
+        orchard: &[FullViewingKey],
+        sapling: &[DiversifiableFullViewingKey],
+    ) -> Self {
+        let scopes = [Scope::External, Scope::Internal];
+        WalletKeys {
+            network: network.into(),
+            orchard: orchard
+                .iter()
+                .flat_map(|fvk| scopes.iter().map(move |scope| fvk.to_ivk(*scope)))
+                .map(|ivk| orchard::keys::PreparedIncomingViewingKey::new(&ivk))
+                .collect(),
+            sapling: sapling
+                .iter()
+                .flat_map(|dfvk| scopes.iter().map(move |scope| dfvk.to_ivk(*scope)))
+                .map(|ivk| sapling::note_encryption::PreparedIncomingViewingKey::new(&ivk))
+                .collect(),
+            // Orchard nullifiers only depend on nk which is shared by both scopes
+            orchard_nullifier_keys: orchard
+                .iter()
+                .flat_map(|fvk| scopes.iter().map(move |_| fvk.clone()))
+                .collect(),
+            sapling_nullifier_keys: sapling
+                .iter()
+                .flat_map(|dfvk| scopes.iter().map(move |scope| dfvk.to_nk(*scope)))
+                .collect(),
+        }
+    }
+
+    /// Decode a viewing key for the given network. Accepts any of
+    /// - a Unified Full Viewing Key (`uview...`/`uviewtest...`)
+    /// - a Sapling extended full viewing key (`zxviews...`/`zxviewtestsapling...`)
+    /// - a hex encoded 96 byte Orchard full viewing key
+    ///
+    /// Keys encoded for the other network are rejected. Pools the key has no component for get no IVKs.
+    pub fn decode(network: &Network, key: &str) -> anyhow::Result<Self> {
+        let params = consensus::Network::from(network);
+        let key = key.trim();
+
+        if key.starts_with("uview") {
+            let ufvk = UnifiedFullViewingKey::decode(&params, key)
+                .map_err(|e| anyhow::anyhow!("Invalid unified full viewing key: {}", e))?;
+            return Ok(Self::from_fvks(
+                network,
+                ufvk.orchard().cloned().as_slice(),
+                ufvk.sapling().cloned().as_slice(),
+            ));
+        }
+
+        if key.starts_with("zxview") {
+            let expected_hrp = params.hrp_sapling_extended_full_viewing_key();
+            return match decode_extended_full_viewing_key(expected_hrp, key) {
+                Ok(extfvk) => Ok(Self::from_fvks(
+                    network,
+                    &[],
+                    &[extfvk.to_diversifiable_full_viewing_key()],
+                )),
+                Err(_) if !key.starts_with(expected_hrp) => Err(anyhow::anyhow!(
+                    "Sapling extended full viewing key is not for {:?}",
+                    network
+                )),
+                Err(e) => Err(anyhow::anyhow!(
+                    "Invalid Sapling extended full viewing key: {}",
+                    e
+                )),
+            };
+        }
+
+        let bytes: [u8; 96] = hex::decode(key)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unrecognised viewing key. Expected a UFVK, Sapling extended FVK or hex Orchard FVK"
+                )
+            })?;
+        let fvk = FullViewingKey::from_bytes(&bytes)
+            .ok_or_else(|| anyhow::anyhow!("Invalid Orchard full viewing key"))?;
+        Ok(Self::from_fvks(network, &[fvk], &[]))
+    }
+
+    /// A single key per pool which isn't expected to receive any notes.
+    /// Used when the benchmark is only measuring the cost of trial decryption.
+    pub fn dummy(network: &Network) -> Self {
+        WalletKeys {
+            network: network.into(),
+            orchard: dummy_ivk_orchard(1),
+            sapling: dummy_ivk_sapling(1),
+            orchard_nullifier_keys: vec![],
+            sapling_nullifier_keys: vec![],
+        }
+    }
+
+    /// The keys to use for a benchmark given the optional viewing key passed from JS
+    pub(crate) fn for_bench(network: &Network, view_key: Option<String>) -> Result<Self, JsError> {
+        match view_key {
+            Some(key) => {
+                WalletKeys::decode(network, &key).map_err(|e| JsError::new(&format!("{:#}", e)))
+            }
+            None => Ok(WalletKeys::dummy(network)),
+        }
+    }
+}
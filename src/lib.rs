@@ -2,37 +2,83 @@ use wasm_bindgen::prelude::*;
 
 mod commitment_tree;
 mod proof_gen;
+mod reorg;
+mod spend_before_sync;
+mod sync_state;
+mod synthetic_chain;
 mod trial_decryption;
 mod types;
+mod wallet_notes;
+mod wallet_sync;
+mod witness_placement;
 
-use tonic_web_wasm_client::Client;
-mod proto;
+pub mod proto;
 
 #[cfg(feature = "parallel")]
 pub use wasm_bindgen_rayon::init_thread_pool;
 
 mod bench_params;
+mod bench_report;
+mod block_download;
 mod block_range_stream;
-pub type WasmGrpcClient =
-    crate::proto::service::compact_tx_streamer_client::CompactTxStreamerClient<
-        tonic_web_wasm_client::Client,
-    >;
+mod block_source;
+mod cancel;
+mod error;
+mod fixture;
+mod keys;
+#[cfg(feature = "mock-server")]
+mod mock_lightwalletd;
+mod pipeline;
+mod platform;
+mod progress;
+
+/// gRPC-web through the browser's fetch API
+#[cfg(target_arch = "wasm32")]
+pub type GrpcClient = crate::proto::service::compact_tx_streamer_client::CompactTxStreamerClient<
+    tonic_web_wasm_client::Client,
+>;
+/// Plain gRPC over HTTP/2 when running natively
+#[cfg(not(target_arch = "wasm32"))]
+pub type GrpcClient = crate::proto::service::compact_tx_streamer_client::CompactTxStreamerClient<
+    tonic::transport::Channel,
+>;
 
 macro_rules! console_log {
-    ($($t:tt)*) => (web_sys::console::log_1(&format!($($t)*).into()))
+    ($($t:tt)*) => ($crate::platform::log(&format!($($t)*)))
 }
 macro_rules! console_debug {
-    ($($t:tt)*) => (web_sys::console::debug_1(&format!($($t)*).into()))
+    ($($t:tt)*) => ($crate::platform::debug(&format!($($t)*)))
 }
 
 pub(crate) use console_debug;
 pub(crate) use console_log;
 
 pub use bench_params::*;
+pub use bench_report::*;
+pub use block_download::*;
+pub use block_source::*;
+pub use cancel::*;
 pub use commitment_tree::*;
+pub use error::*;
+pub use fixture::*;
+pub use keys::*;
+#[cfg(feature = "mock-server")]
+pub use mock_lightwalletd::*;
+pub use pipeline::*;
+pub use platform::now_ms;
+pub(crate) use platform::sleep_ms;
+pub use progress::*;
 pub use proof_gen::*;
+pub use reorg::*;
+pub use spend_before_sync::*;
+pub use sync_state::*;
+pub use synthetic_chain::*;
 pub use trial_decryption::*;
+pub use wallet_notes::*;
+pub use wallet_sync::*;
+pub use witness_placement::*;
 
+#[cfg(target_arch = "wasm32")]
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_name = "performance")]
@@ -50,10 +96,23 @@ pub fn set_panic_hook() {
     console_error_panic_hook::set_once();
 }
 
-pub fn new_compact_streamer_client(base_url: &str) -> WasmGrpcClient {
-    proto::service::compact_tx_streamer_client::CompactTxStreamerClient::new(Client::new(
+#[cfg(target_arch = "wasm32")]
+pub fn new_compact_streamer_client(base_url: &str) -> Result<GrpcClient, SyncError> {
+    Ok(GrpcClient::new(tonic_web_wasm_client::Client::new(
         base_url.to_string(),
-    ))
+    )))
+}
+
+/// The connection is made lazily on the first request so this doesn't need to be async.
+/// Only plaintext `http://` endpoints are supported, TLS isn't enabled for native builds.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn new_compact_streamer_client(base_url: &str) -> Result<GrpcClient, SyncError> {
+    let channel = tonic::transport::Channel::from_shared(base_url.to_string())
+        .map_err(|e| {
+            SyncError::InvalidOptions(format!("invalid lightwalletd URL {}: {}", base_url, e))
+        })?
+        .connect_lazy();
+    Ok(GrpcClient::new(channel))
 }
 
 #[wasm_bindgen(start)]
@@ -0,0 +1,342 @@
+/**
+ * A mock lightwalletd which serves blocks and tree states from a local fixture store over grpc-web.
+ *
+ * This lets the whole benchmark suite run against recorded or synthetic chains on a machine with no
+ * internet access and no grpcwebproxy. Only the RPCs the benchmarks use are implemented.
+ */
+use std::convert::TryFrom;
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures_util::Stream;
+use tonic::{Request, Response, Status};
+
+use crate::block_source::{FileBlockSource, ScheduledFork};
+use crate::fixture::LengthDelimitedReader;
+use crate::proto::compact_formats::{CompactBlock, CompactTx};
+use crate::proto::service::compact_tx_streamer_server::{
+    CompactTxStreamer, CompactTxStreamerServer,
+};
+use crate::proto::service::*;
+
+// Number of blocks buffered between the file reader and the response stream
+const BLOCK_CHANNEL_SIZE: usize = 128;
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// A directory of fixture files the mock lightwalletd serves from.
+///
+/// The fixture is opened as a `FileBlockSource`, so block ranges are streamed straight from disk
+/// without holding the whole chain in memory.
+#[derive(Debug)]
+pub struct FixtureStore {
+    source: FileBlockSource,
+    latest_block: BlockId,
+    fork: Option<Box<ScheduledFork<FixtureStore>>>,
+}
+
+impl FixtureStore {
+    pub fn open(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let source = FileBlockSource::open_dir(dir)?;
+        let latest_block = match source.last_height() {
+            Some(height) => source.blocks_from(height)?.next().transpose()?,
+            None => None,
+        }
+        .map(|block| BlockId {
+            height: block.height,
+            hash: block.hash,
+        })
+        .unwrap_or_default();
+        Ok(Self {
+            source,
+            latest_block,
+            fork: None,
+        })
+    }
+
+    /// Switch to serving `fork` once `after_blocks` blocks have been streamed, as if the chain
+    /// reorganised onto it. The switch can happen part way through a block range.
+    pub fn with_fork(mut self, fork: FixtureStore, after_blocks: u64) -> Self {
+        self.fork = Some(Box::new(ScheduledFork::new(fork, after_blocks)));
+        self
+    }
+
+    /// The fixture being served, which is the fork once it has been switched to
+    fn active(&self) -> &FixtureStore {
+        match &self.fork {
+            Some(fork) if fork.switched() => &fork.chain,
+            _ => self,
+        }
+    }
+
+    /// Count a block as served. Returns true if it was the last before switching to the fork
+    fn count_served(&self) -> bool {
+        self.fork.as_ref().map_or(false, |fork| fork.count_served())
+    }
+
+    /// The chain name reported by the fixture tree states ("main" or "test")
+    fn chain_name(&self) -> String {
+        self.source
+            .tree_states
+            .values()
+            .next()
+            .map(|t| t.network.clone())
+            .unwrap_or_else(|| "main".to_string())
+    }
+
+    fn blocks_from(&self, start: u64) -> Result<LengthDelimitedReader<CompactBlock, File>, Status> {
+        if start > self.latest_block.height {
+            return Err(Status::out_of_range(format!(
+                "No blocks from height {}",
+                start
+            )));
+        }
+        self.source
+            .blocks_from(start)
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+/// Serve the fixture store as a grpc-web lightwalletd on the given address until the process exits
+pub async fn serve(store: FixtureStore, addr: SocketAddr) -> anyhow::Result<()> {
+    let service = MockLightwalletd {
+        store: Arc::new(store),
+    };
+    tonic::transport::Server::builder()
+        .accept_http1(true)
+        .add_service(tonic_web::enable(CompactTxStreamerServer::new(service)))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct MockLightwalletd {
+    store: Arc<FixtureStore>,
+}
+
+#[tonic::async_trait]
+impl CompactTxStreamer for MockLightwalletd {
+    async fn get_latest_block(
+        &self,
+        _request: Request<ChainSpec>,
+    ) -> Result<Response<BlockId>, Status> {
+        Ok(Response::new(self.store.active().latest_block.clone()))
+    }
+
+    async fn get_block(&self, request: Request<BlockId>) -> Result<Response<CompactBlock>, Status> {
+        let height = request.into_inner().height;
+        match self.store.active().blocks_from(height)?.next() {
+            Some(Ok(block)) if block.height == height => Ok(Response::new(block)),
+            Some(Err(e)) => Err(Status::internal(e.to_string())),
+            _ => Err(Status::not_found(format!("No block at height {}", height))),
+        }
+    }
+
+    async fn get_block_nullifiers(
+        &self,
+        _request: Request<BlockId>,
+    ) -> Result<Response<CompactBlock>, Status> {
+        Err(Status::unimplemented("not served by the mock"))
+    }
+
+    type GetBlockRangeStream = ResponseStream<CompactBlock>;
+
+    async fn get_block_range(
+        &self,
+        request: Request<BlockRange>,
+    ) -> Result<Response<Self::GetBlockRangeStream>, Status> {
+        let range = request.into_inner();
+        let (start, end) = match (range.start, range.end) {
+            (Some(start), Some(end)) => (start.height, end.height),
+            _ => {
+                return Err(Status::invalid_argument(
+                    "Block range must have a start and end",
+                ))
+            }
+        };
+        let store = self.store.clone();
+        let mut blocks = store.active().blocks_from(start)?;
+
+        // Read blocks on a blocking thread and stream them out as they are decoded
+        let (tx, rx) = tokio::sync::mpsc::channel(BLOCK_CHANNEL_SIZE);
+        tokio::task::spawn_blocking(move || {
+            while let Some(block) = blocks.next() {
+                let block = block.map_err(|e| Status::internal(e.to_string()));
+                let next_height = block.as_ref().ok().map(|b| b.height + 1);
+                if matches!(&block, Ok(b) if b.height > end) || tx.blocking_send(block).is_err() {
+                    break;
+                }
+                // carry on from the fork's blocks file once it is switched to
+                if store.count_served() {
+                    match next_height.map(|height| store.active().blocks_from(height)) {
+                        Some(Ok(fork_blocks)) => blocks = fork_blocks,
+                        _ => break,
+                    }
+                }
+            }
+        });
+        let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|block| (block, rx))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    type GetBlockRangeNullifiersStream = ResponseStream<CompactBlock>;
+
+    async fn get_block_range_nullifiers(
+        &self,
+        _request: Request<BlockRange>,
+    ) -> Result<Response<Self::GetBlockRangeNullifiersStream>, Status> {
+        Err(Status::unimplemented("not served by the mock"))
+    }
+
+    async fn get_transaction(
+        &self,
+        _request: Request<TxFilter>,
+    ) -> Result<Response<RawTransaction>, Status> {
+        Err(Status::unimplemented("not served by the mock"))
+    }
+
+    async fn send_transaction(
+        &self,
+        _request: Request<RawTransaction>,
+    ) -> Result<Response<SendResponse>, Status> {
+        Err(Status::unimplemented("not served by the mock"))
+    }
+
+    type GetTaddressTxidsStream = ResponseStream<RawTransaction>;
+
+    async fn get_taddress_txids(
+        &self,
+        _request: Request<TransparentAddressBlockFilter>,
+    ) -> Result<Response<Self::GetTaddressTxidsStream>, Status> {
+        Err(Status::unimplemented("not served by the mock"))
+    }
+
+    async fn get_taddress_balance(
+        &self,
+        _request: Request<AddressList>,
+    ) -> Result<Response<Balance>, Status> {
+        Err(Status::unimplemented("not served by the mock"))
+    }
+
+    async fn get_taddress_balance_stream(
+        &self,
+        _request: Request<tonic::Streaming<Address>>,
+    ) -> Result<Response<Balance>, Status> {
+        Err(Status::unimplemented("not served by the mock"))
+    }
+
+    type GetMempoolTxStream = ResponseStream<CompactTx>;
+
+    async fn get_mempool_tx(
+        &self,
+        _request: Request<Exclude>,
+    ) -> Result<Response<Self::GetMempoolTxStream>, Status> {
+        Err(Status::unimplemented("not served by the mock"))
+    }
+
+    type GetMempoolStreamStream = ResponseStream<RawTransaction>;
+
+    async fn get_mempool_stream(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::GetMempoolStreamStream>, Status> {
+        Err(Status::unimplemented("not served by the mock"))
+    }
+
+    async fn get_tree_state(
+        &self,
+        request: Request<BlockId>,
+    ) -> Result<Response<TreeState>, Status> {
+        let height = request.into_inner().height;
+        self.store
+            .active()
+            .source
+            .tree_states
+            .get(&height)
+            .cloned()
+            .map(Response::new)
+            .ok_or_else(|| Status::not_found(format!("No tree state at height {}", height)))
+    }
+
+    async fn get_latest_tree_state(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<TreeState>, Status> {
+        self.store
+            .active()
+            .source
+            .tree_states
+            .values()
+            .next_back()
+            .cloned()
+            .map(Response::new)
+            .ok_or_else(|| Status::not_found("No tree states in fixture"))
+    }
+
+    type GetSubtreeRootsStream = ResponseStream<SubtreeRoot>;
+
+    async fn get_subtree_roots(
+        &self,
+        request: Request<GetSubtreeRootsArg>,
+    ) -> Result<Response<Self::GetSubtreeRootsStream>, Status> {
+        let arg = request.into_inner();
+        let protocol = ShieldedProtocol::try_from(arg.shielded_protocol)
+            .map_err(|_| Status::invalid_argument("Unknown shielded protocol"))?;
+        let max_entries = match arg.max_entries {
+            0 => usize::MAX,
+            n => n as usize,
+        };
+        let roots = self
+            .store
+            .active()
+            .source
+            .subtree_roots
+            .from(protocol, arg.start_index)
+            .into_iter()
+            .take(max_entries)
+            .map(Ok)
+            .collect::<Vec<_>>();
+        Ok(Response::new(Box::pin(futures_util::stream::iter(roots))))
+    }
+
+    async fn get_address_utxos(
+        &self,
+        _request: Request<GetAddressUtxosArg>,
+    ) -> Result<Response<GetAddressUtxosReplyList>, Status> {
+        Err(Status::unimplemented("not served by the mock"))
+    }
+
+    type GetAddressUtxosStreamStream = ResponseStream<GetAddressUtxosReply>;
+
+    async fn get_address_utxos_stream(
+        &self,
+        _request: Request<GetAddressUtxosArg>,
+    ) -> Result<Response<Self::GetAddressUtxosStreamStream>, Status> {
+        Err(Status::unimplemented("not served by the mock"))
+    }
+
+    async fn get_lightd_info(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<LightdInfo>, Status> {
+        let store = self.store.active();
+        Ok(Response::new(LightdInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            vendor: "zcash-wasm-benchmark mock lightwalletd".to_string(),
+            chain_name: store.chain_name(),
+            block_height: store.latest_block.height,
+            estimated_height: store.latest_block.height,
+            ..Default::default()
+        }))
+    }
+
+    async fn ping(&self, _request: Request<Duration>) -> Result<Response<PingResponse>, Status> {
+        Err(Status::unimplemented("not served by the mock"))
+    }
+}
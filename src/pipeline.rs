@@ -0,0 +1,195 @@
+/**
+ * A bounded pipeline for overlapping the stages of a sync (download, convert, decrypt, insert).
+ *
+ * Stages are async tasks joined together on the current thread and connected by bounded channels.
+ * A stage blocks when the next one's queue is full so memory use is bounded by the queue depth.
+ * CPU heavy stages should hand their work to the rayon pool with `spawn_rayon` so they
+ * run at the same time as the stages waiting on the network.
+ */
+use std::future::Future;
+
+use futures_channel::mpsc;
+use futures_util::future::{join, join_all, LocalBoxFuture};
+use futures_util::{pin_mut, FutureExt, SinkExt, Stream, StreamExt};
+
+use crate::error::SyncError;
+use crate::{console_log, now_ms};
+
+/// The output of a pipeline stage
+pub type StageReceiver<T> = mpsc::Receiver<Result<T, SyncError>>;
+
+/// How much of the pipeline's run time a stage spent working rather than waiting on its neighbours
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct StageStats {
+    pub name: &'static str,
+    /// Number of items the stage processed
+    pub items: u32,
+    /// Time spent processing items, excluding waiting for input or for space in the next queue
+    pub busy_ms: f64,
+}
+
+impl StageStats {
+    pub fn new(name: &'static str) -> Self {
+        StageStats {
+            name,
+            items: 0,
+            busy_ms: 0.0,
+        }
+    }
+
+    /// Await `f`, counting it as one item of work for this stage
+    pub async fn time<F: Future>(&mut self, f: F) -> F::Output {
+        let start = now_ms();
+        let output = f.await;
+        self.busy_ms += now_ms() - start;
+        self.items += 1;
+        output
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct PipelineStats {
+    pub total_ms: f64,
+    pub stages: Vec<StageStats>,
+}
+
+impl PipelineStats {
+    /// Fraction of the total run time each stage was busy. The stage closest to 1 is the bottleneck
+    pub fn utilisation(&self) -> impl Iterator<Item = (&'static str, f64)> + '_ {
+        self.stages
+            .iter()
+            .map(move |s| (s.name, s.busy_ms / self.total_ms))
+    }
+
+    pub fn log(&self) {
+        let stages = self
+            .stages
+            .iter()
+            .zip(self.utilisation())
+            .map(|(s, (_, utilisation))| {
+                format!(
+                    "- {}: {} items, {}ms busy ({}%)",
+                    s.name,
+                    s.items,
+                    s.busy_ms.round(),
+                    (utilisation * 100.0).round()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        console_log!(
+            "Pipeline completed in {}ms. Stage utilisation:\n{}",
+            self.total_ms.round(),
+            stages
+        );
+    }
+}
+
+pub struct Pipeline {
+    queue_depth: usize,
+    stages: Vec<LocalBoxFuture<'static, StageStats>>,
+}
+
+impl Pipeline {
+    /// Create a pipeline where up to `queue_depth` items can wait between each pair of stages
+    pub fn new(queue_depth: usize) -> Self {
+        Pipeline {
+            queue_depth,
+            stages: vec![],
+        }
+    }
+
+    /// Add the first stage of the pipeline, which pulls items from a stream.
+    /// Time spent waiting on the stream counts as busy since producing items is this stage's work.
+    pub fn source<T: 'static>(
+        &mut self,
+        name: &'static str,
+        source: impl Stream<Item = Result<T, SyncError>> + 'static,
+    ) -> StageReceiver<T> {
+        let (mut tx, rx) = mpsc::channel(self.queue_depth);
+        self.stages.push(
+            async move {
+                let mut stats = StageStats::new(name);
+                pin_mut!(source);
+                loop {
+                    let start = now_ms();
+                    let item = source.next().await;
+                    stats.busy_ms += now_ms() - start;
+                    let item = match item {
+                        Some(item) => item,
+                        None => break,
+                    };
+                    stats.items += 1;
+                    let is_err = item.is_err();
+                    if tx.send(item).await.is_err() || is_err {
+                        break;
+                    }
+                }
+                stats
+            }
+            .boxed_local(),
+        );
+        rx
+    }
+
+    /// Add a stage which applies `f` to each item from the previous stage.
+    /// Errors are passed straight through to the next stage and end this one.
+    pub fn stage<I, O, F, Fut>(
+        &mut self,
+        name: &'static str,
+        mut input: StageReceiver<I>,
+        mut f: F,
+    ) -> StageReceiver<O>
+    where
+        I: 'static,
+        O: 'static,
+        F: FnMut(I) -> Fut + 'static,
+        Fut: Future<Output = Result<O, SyncError>>,
+    {
+        let (mut tx, rx) = mpsc::channel(self.queue_depth);
+        self.stages.push(
+            async move {
+                let mut stats = StageStats::new(name);
+                while let Some(item) = input.next().await {
+                    let output = match item {
+                        Ok(item) => stats.time(f(item)).await,
+                        Err(e) => Err(e),
+                    };
+                    let is_err = output.is_err();
+                    if tx.send(output).await.is_err() || is_err {
+                        break;
+                    }
+                }
+                stats
+            }
+            .boxed_local(),
+        );
+        rx
+    }
+
+    /// Run every stage to completion alongside `sink`, which consumes the output of the last stage
+    /// and reports its own stats.
+    pub async fn run<T>(self, sink: impl Future<Output = (T, StageStats)>) -> (T, PipelineStats) {
+        let start = now_ms();
+        let ((output, sink_stats), mut stages) = join(sink, join_all(self.stages)).await;
+        stages.push(sink_stats);
+        let stats = PipelineStats {
+            total_ms: now_ms() - start,
+            stages,
+        };
+        (output, stats)
+    }
+}
+
+/// Run `f` on the rayon thread pool, leaving the current thread free to drive the other stages
+pub async fn spawn_rayon<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let (tx, rx) = futures_channel::oneshot::channel();
+    rayon::spawn(move || {
+        let _ = tx.send(f());
+    });
+    rx.await.expect("rayon task panicked")
+}
@@ -0,0 +1,89 @@
+/**
+ * The few things the benchmarks need from the environment they run in: a clock, a console, a timer
+ * and the memory in use.
+ *
+ * In the browser these are `performance.now()`, `console`, `setTimeout` and the wasm memory size.
+ * Native builds use a monotonic clock, stdout, tokio timers and the process's resident set size so
+ * the same benchmarks can be run outside the browser as a baseline.
+ */
+
+#[cfg(target_arch = "wasm32")]
+mod imp {
+    use crate::PERFORMANCE;
+
+    pub fn now_ms() -> f64 {
+        PERFORMANCE.now()
+    }
+
+    pub(crate) fn log(message: &str) {
+        web_sys::console::log_1(&message.into());
+    }
+
+    pub(crate) fn debug(message: &str) {
+        web_sys::console::debug_1(&message.into());
+    }
+
+    /// Works on both the main thread and in workers
+    pub(crate) async fn sleep_ms(ms: u32) {
+        let promise = js_sys::Promise::new(&mut |resolve, _| {
+            let global = js_sys::global();
+            let set_timeout: js_sys::Function = js_sys::Reflect::get(&global, &"setTimeout".into())
+                .unwrap()
+                .into();
+            set_timeout.call2(&global, &resolve, &ms.into()).unwrap();
+        });
+        wasm_bindgen_futures::JsFuture::from(promise).await.unwrap();
+    }
+
+    /// Wasm memory can only grow so its current size is the peak
+    pub(crate) fn peak_memory_bytes() -> Option<u64> {
+        Some(core::arch::wasm32::memory_size::<0>() as u64 * 65536)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod imp {
+    use std::sync::OnceLock;
+    use std::time::{Duration, Instant};
+
+    pub fn now_ms() -> f64 {
+        static START: OnceLock<Instant> = OnceLock::new();
+        START.get_or_init(Instant::now).elapsed().as_secs_f64() * 1000.0
+    }
+
+    pub(crate) fn log(message: &str) {
+        println!("{}", message);
+    }
+
+    /// Debug messages are noisy so they are only printed if `BENCH_DEBUG` is set,
+    /// much like the browser hiding them unless verbose logging is enabled
+    pub(crate) fn debug(message: &str) {
+        static ENABLED: OnceLock<bool> = OnceLock::new();
+        if *ENABLED.get_or_init(|| std::env::var_os("BENCH_DEBUG").is_some()) {
+            eprintln!("{}", message);
+        }
+    }
+
+    /// Must be awaited from within a tokio runtime
+    pub(crate) async fn sleep_ms(ms: u32) {
+        tokio::time::sleep(Duration::from_millis(ms as u64)).await;
+    }
+
+    /// The peak resident set size, which is only available on Linux
+    pub(crate) fn peak_memory_bytes() -> Option<u64> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let kb = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmHWM:"))?
+            .trim()
+            .strip_suffix("kB")?
+            .trim()
+            .parse::<u64>()
+            .ok()?;
+        Some(kb * 1024)
+    }
+}
+
+/// Milliseconds elapsed since an arbitrary fixed point, for measuring durations
+pub use imp::now_ms;
+pub(crate) use imp::{debug, log, peak_memory_bytes, sleep_ms};
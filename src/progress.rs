@@ -0,0 +1,133 @@
+/**
+ * Progress updates for long running benchmarks, so a UI can show a progress bar instead of
+ * the user watching the console.
+ *
+ * From JS pass a function to `BenchParams.withProgress`. It is called with a plain object after each
+ * batch of blocks (or each step of proving). From Rust set `SyncOptions::progress` to a closure.
+ */
+use std::fmt;
+use std::rc::Rc;
+
+use crate::now_ms;
+
+/// Which benchmark the progress is for
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressPhase {
+    TrialDecryption,
+    TreeSync,
+    WalletSync,
+    Proving,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Progress {
+    pub phase: ProgressPhase,
+    /// Units of work done so far and in total. Blocks for the sync phases, steps for proving
+    pub completed: u32,
+    pub total: u32,
+    pub remaining: u32,
+    pub percent: f64,
+    /// Time since the phase started
+    pub elapsed_ms: f64,
+    /// Height of the last block processed, for the sync phases
+    pub height: Option<u32>,
+}
+
+/// A function called with each progress update
+#[derive(Clone)]
+pub struct ProgressCallback(Rc<dyn Fn(&Progress)>);
+
+impl ProgressCallback {
+    pub fn new(f: impl Fn(&Progress) + 'static) -> Self {
+        ProgressCallback(Rc::new(f))
+    }
+
+    /// Wrap a JS function which is called with the progress as a plain object
+    pub fn from_js(f: js_sys::Function) -> Self {
+        ProgressCallback::new(move |progress| {
+            let progress = serde_wasm_bindgen::to_value(progress).unwrap();
+            if let Err(e) = f.call1(&wasm_bindgen::JsValue::NULL, &progress) {
+                crate::console_log!("Progress callback threw: {:?}", e);
+            }
+        })
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// Counts the work done in a phase and sends an update to the callback, if there is one,
+/// each time it advances
+pub(crate) struct ProgressTracker {
+    phase: ProgressPhase,
+    /// Height of the first block, for the sync phases
+    start_height: Option<u32>,
+    completed: u32,
+    total: u32,
+    start_ms: f64,
+    callback: Option<ProgressCallback>,
+}
+
+impl ProgressTracker {
+    /// Track a sync over the blocks in [start_height, end_height]
+    pub(crate) fn blocks(
+        phase: ProgressPhase,
+        start_height: u32,
+        end_height: u32,
+        callback: Option<ProgressCallback>,
+    ) -> Self {
+        ProgressTracker {
+            phase,
+            start_height: Some(start_height),
+            completed: 0,
+            total: (end_height + 1).saturating_sub(start_height),
+            start_ms: now_ms(),
+            callback,
+        }
+    }
+
+    /// Track a phase made of a fixed number of steps
+    pub(crate) fn steps(
+        phase: ProgressPhase,
+        total: u32,
+        callback: Option<ProgressCallback>,
+    ) -> Self {
+        ProgressTracker {
+            phase,
+            start_height: None,
+            completed: 0,
+            total,
+            start_ms: now_ms(),
+            callback,
+        }
+    }
+
+    pub(crate) fn advance(&mut self, n: u32) {
+        self.completed += n;
+        let callback = match &self.callback {
+            Some(callback) => callback,
+            None => return,
+        };
+        let percent = if self.total == 0 {
+            100.0
+        } else {
+            self.completed as f64 / self.total as f64 * 100.0
+        };
+        (callback.0)(&Progress {
+            phase: self.phase,
+            completed: self.completed,
+            total: self.total,
+            remaining: self.total.saturating_sub(self.completed),
+            percent,
+            elapsed_ms: now_ms() - self.start_ms,
+            height: self
+                .start_height
+                .filter(|_| self.completed > 0)
+                .map(|start| start + self.completed - 1),
+        });
+    }
+}
@@ -8,32 +8,46 @@ use orchard::{
 use rand::rngs::OsRng;
 
 use crate::bench_params::{BenchParams, ShieldedPool};
+use crate::bench_report::BenchReport;
+use crate::progress::{ProgressCallback, ProgressPhase, ProgressTracker};
+use crate::{console_log, now_ms};
 use wasm_bindgen::prelude::*;
-use web_sys::console;
 
 // The following code is mostly copy pasta of benchmarks from orchard repo: https://github.com/zcash/orchard/blob/main/benches/
 
 #[wasm_bindgen]
-pub fn generate_proof_bench(params: BenchParams, n_spends: u32) {
+pub fn generate_proof_bench(params: BenchParams, n_spends: u32) -> Result<BenchReport, JsError> {
     if params.pool != ShieldedPool::Orchard {
-        console::log_1(&"This benchmark is only for Orchard".into());
-        return;
+        return Err(JsError::new("This benchmark is only for Orchard"));
     }
+    let report = prove_orchard_bundle(n_spends, params.sync_options().progress);
+    console_log!("Test complete");
+    Ok(report)
+}
 
+/// Build an Orchard bundle with `n_spends` actions and prove it.
+/// Key generation and proving are timed separately in the report.
+/// Progress is reported in three steps: proving key built, bundle built and proof created.
+pub fn prove_orchard_bundle(n_spends: u32, progress: Option<ProgressCallback>) -> BenchReport {
     let rng = OsRng;
-    console::log_1(&"Starting key generation".into());
+    let mut report = BenchReport::default();
+    let mut progress = ProgressTracker::steps(ProgressPhase::Proving, 3, progress);
+    let bench_start = now_ms();
+    console_log!("Starting key generation");
 
-    console::time_with_label("Spending Key from Bytes");
+    let start = now_ms();
     let sk = SpendingKey::from_bytes([7; 32]).unwrap();
-    console::time_end_with_label("Spending Key from Bytes");
+    console_log!("Spending Key from Bytes: {}ms", now_ms() - start);
 
-    console::time_with_label("Recipient Viewing Key");
+    let start = now_ms();
     let recipient = FullViewingKey::from(&sk).address_at(0u32, Scope::External);
-    console::time_end_with_label("Recipient Viewing Key");
+    console_log!("Recipient Viewing Key: {}ms", now_ms() - start);
 
-    console::time_with_label("Create Proving Key");
+    let start = now_ms();
     let pk = ProvingKey::build();
-    console::time_end_with_label("Create Proving Key");
+    console_log!("Create Proving Key: {}ms", now_ms() - start);
+    report.phases.key_setup_ms = now_ms() - bench_start;
+    progress.advance(1);
 
     let create_bundle = |num_recipients| {
         let mut builder = Builder::new(BundleType::DEFAULT, Anchor::from_bytes([0; 32]).unwrap());
@@ -53,12 +67,20 @@ pub fn generate_proof_bench(params: BenchParams, n_spends: u32) {
     };
 
     let (bundle, instances) = create_bundle(n_spends);
-    console::log_1(&"Starting proving".into());
-    console::time_with_label(&format!("Proving with {} spends", n_spends));
+    progress.advance(1);
+    console_log!("Starting proving");
+    let start = now_ms();
     bundle
         .authorization()
         .create_proof(&pk, &instances, rng)
         .unwrap();
-    console::time_end_with_label(&format!("Proving with {} spends", n_spends));
-    console::log_1(&"Test complete".into());
+    report.phases.prove_ms = now_ms() - start;
+    progress.advance(1);
+    console_log!(
+        "Proving with {} spends: {}ms",
+        n_spends,
+        report.phases.prove_ms
+    );
+    report.finish(bench_start);
+    report
 }
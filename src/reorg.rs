@@ -0,0 +1,143 @@
+/**
+ * Simulated chain reorganisation: sync the commitment trees over a range, rewind them to the checkpoint
+ * `depth` blocks before its end as if the chain had reorganised there, then reapply the blocks after
+ * the fork point.
+ *
+ * The blocks reapplied are the same ones, so the trees must end with the roots lightwalletd reports
+ * for the end of the range. How close the fork point can get to the requested height depends on the
+ * checkpoint interval, and how deep a reorg can be rewound on the number of checkpoints retained.
+ */
+use wasm_bindgen::prelude::*;
+
+use crate::bench_params::{BenchParams, ShieldedPool, SyncOptions};
+use crate::bench_report::BenchReport;
+use crate::block_range_stream::check_activation;
+use crate::block_source::BlockSource;
+use crate::commitment_tree::{
+    fetch_orchard_frontier_at_height, fetch_sapling_frontier_at_height, insert_block_range,
+    TreeSyncState,
+};
+use crate::error::SyncError;
+use crate::witness_placement::WitnessPositions;
+use crate::{console_log, now_ms};
+
+/// Sync the commitment trees over the range in the params, then rewind them by `depth` blocks and
+/// reapply the blocks after the fork point. The report's `rewind_ms` and `reapply_ms` give the cost
+/// of the reorg.
+#[wasm_bindgen]
+pub async fn reorg_bench(params: BenchParams, depth: u32) -> Result<BenchReport, JsError> {
+    let params = params.resolve().await?;
+    let source = params.block_source()?;
+    let options = params.sync_options();
+    let BenchParams {
+        pool,
+        start_block,
+        end_block,
+        block_batch_size,
+        ..
+    } = params;
+
+    let summary = sync_with_reorg(
+        source,
+        pool,
+        start_block,
+        end_block,
+        block_batch_size,
+        depth,
+        options,
+    )
+    .await?;
+    Ok(summary.report)
+}
+
+/// The commitment trees after a sync and simulated reorg
+#[derive(Clone, Debug)]
+pub struct ReorgSummary {
+    /// Height of the checkpoint the trees were rewound to. The blocks after it were reapplied
+    pub fork_height: u32,
+    /// Size of each commitment tree at the end of the range
+    pub orchard_tree_size: u64,
+    pub sapling_tree_size: u64,
+    pub report: BenchReport,
+}
+
+/// Sync the commitment trees over the given range, rewind them to the latest checkpoint at or below
+/// `end_block - depth`, then reapply the blocks from there to the end of the range and check the roots.
+pub async fn sync_with_reorg<S: BlockSource + Clone + 'static>(
+    mut source: S,
+    pool: ShieldedPool,
+    start_block: u32,
+    end_block: u32,
+    block_batch_size: u32,
+    depth: u32,
+    options: SyncOptions,
+) -> Result<ReorgSummary, SyncError> {
+    check_activation(&options.network, &pool, start_block)?;
+    let start = now_ms();
+    let state = TreeSyncState::bootstrap(&mut source, &pool, start_block - 1).await?;
+
+    let (mut state, mut report) = insert_block_range(
+        source.clone(),
+        state,
+        &pool,
+        start_block,
+        end_block,
+        block_batch_size,
+        &WitnessPositions::default(),
+        &options,
+    )
+    .await?;
+    if report.cancelled {
+        report.finish(start);
+        return Ok(ReorgSummary {
+            fork_height: end_block,
+            orchard_tree_size: state.orchard_cursor.into(),
+            sapling_tree_size: state.sapling_cursor.into(),
+            report,
+        });
+    }
+
+    let rewind_start = now_ms();
+    let fork_height = state.rewind_to(end_block.saturating_sub(depth))?;
+    report.phases.rewind_ms = now_ms() - rewind_start;
+    console_log!(
+        "Rewound the trees to block {} for a reorg of depth {}",
+        fork_height,
+        depth
+    );
+
+    let reapply_start = now_ms();
+    if fork_height < end_block {
+        let (reapplied, reapply_report) = insert_block_range(
+            source.clone(),
+            state,
+            &pool,
+            fork_height + 1,
+            end_block,
+            block_batch_size,
+            &WitnessPositions::default(),
+            &options,
+        )
+        .await?;
+        state = reapplied;
+        report.cancelled = reapply_report.cancelled;
+        report.reorgs += reapply_report.reorgs;
+    }
+    report.phases.reapply_ms = now_ms() - reapply_start;
+
+    if !report.cancelled {
+        let end_frontier = fetch_orchard_frontier_at_height(&mut source, end_block).await?;
+        let end_sapling_frontier = fetch_sapling_frontier_at_height(&mut source, end_block).await?;
+        let root_check_start = now_ms();
+        state.check_roots(&pool, &end_frontier, &end_sapling_frontier, end_block)?;
+        report.phases.root_check_ms = now_ms() - root_check_start;
+    }
+    report.finish(start);
+
+    Ok(ReorgSummary {
+        fork_height,
+        orchard_tree_size: state.orchard_cursor.into(),
+        sapling_tree_size: state.sapling_cursor.into(),
+        report,
+    })
+}
@@ -0,0 +1,547 @@
+/**
+ * Spend-before-sync: build commitment trees that can witness every note in a range without inserting
+ * every commitment in it.
+ *
+ * The range is trial decrypted first to find the wallet's notes and their tree positions. The trees are
+ * then bootstrapped from the roots of their completed 2^16 leaf shards (lightwalletd's `GetSubtreeRoots`)
+ * and only the shards holding a note, plus the incomplete shard at the end of the range, are filled in
+ * from blocks. Shards without notes are never hashed.
+ *
+ * A reorg during the trial decryption is rolled back like in the other syncs, but one while the shards
+ * are being filled fails the sync with `SyncError::Reorg`: the shards are filled out of order from
+ * separate ranges, so there is no single point to roll them back to.
+ */
+use std::collections::{BTreeMap, BTreeSet};
+
+use futures_util::stream::{self, StreamExt};
+use incrementalmerkletree::{Position, Retention};
+use orchard::tree::MerkleHashOrchard;
+use wasm_bindgen::prelude::*;
+use zcash_primitives::consensus::{BlockHeight, Network};
+
+use crate::bench_params::{BenchParams, ShieldedPool, SyncOptions};
+use crate::bench_report::BenchReport;
+use crate::block_range_stream::{batch_contents, check_activation, download_block_batches};
+use crate::block_source::BlockSource;
+use crate::commitment_tree::{
+    fetch_orchard_frontier_at_height, fetch_sapling_frontier_at_height, fetch_subtree_roots,
+    ShardCommitments, TreeSyncState, ORCHARD_SHARD_HEIGHT, SAPLING_SHARD_HEIGHT,
+};
+use crate::error::SyncError;
+use crate::keys::WalletKeys;
+use crate::pipeline::{spawn_rayon, Pipeline, StageStats};
+use crate::progress::{ProgressPhase, ProgressTracker};
+use crate::proto::service::{ShieldedProtocol, SubtreeRoot};
+use crate::sync_state::{block_hash, SyncState};
+use crate::trial_decryption::{trial_decrypt_range, DecryptedNote};
+use crate::wallet_notes::WalletNotes;
+use crate::{console_log, now_ms};
+
+/// Trial decrypt the range in the params, then build the trees from the subtree roots lightwalletd
+/// reports, filling in only the shards that hold a decrypted note and the shard at the end of the range.
+/// If a viewing key is given (see `WalletKeys::decode`) it is used instead of a dummy key.
+#[wasm_bindgen]
+pub async fn spend_before_sync_bench(
+    params: BenchParams,
+    view_key: Option<String>,
+) -> Result<BenchReport, JsError> {
+    console_log!("Starting spend-before-sync with params: {:?}", params);
+    let params = params.resolve().await?;
+
+    let start = now_ms();
+    let keys = WalletKeys::for_bench(&params.network, view_key)?;
+    let key_setup_ms = now_ms() - start;
+    let summary = spend_before_sync(
+        params.block_source()?,
+        keys,
+        params.pool.clone(),
+        params.start_block,
+        params.end_block,
+        params.block_batch_size,
+        params.sync_options(),
+    )
+    .await?;
+    let mut report = summary.report;
+    report.phases.key_setup_ms = key_setup_ms;
+    report.phases.total_ms += key_setup_ms;
+    Ok(report)
+}
+
+/// The notes found and the shards filled in by a spend-before-sync
+#[derive(Debug)]
+pub struct SpendBeforeSyncSummary {
+    pub notes: Vec<DecryptedNote>,
+    /// Total value of the notes which were not spent by the end of the range
+    pub spendable_balance: u64,
+    /// Indices of the shards of each tree whose commitments were inserted. The other completed
+    /// shards only have their roots.
+    pub orchard_shards_filled: Vec<u64>,
+    pub sapling_shards_filled: Vec<u64>,
+    /// Number of blocks downloaded to fill the shards, which can start before the range
+    pub fill_blocks: u64,
+    /// Size of each commitment tree at the end of the range
+    pub orchard_tree_size: u64,
+    pub sapling_tree_size: u64,
+    pub report: BenchReport,
+    /// The state at the end of the range, which a wallet sync can continue from.
+    /// None if the sync was cancelled.
+    pub state: Option<SyncState>,
+}
+
+/// Trial decrypt the range, then build trees able to witness every note found in it from the subtree
+/// roots of each pool and the commitments of only the shards holding those notes.
+/// Fails with `SyncError::Reorg` if the chain reorganises while the shards are being filled.
+pub async fn spend_before_sync<S: BlockSource + Clone + 'static>(
+    mut source: S,
+    keys: WalletKeys,
+    pool: ShieldedPool,
+    start_height: u32,
+    end_height: u32,
+    batch_size: u32,
+    options: SyncOptions,
+) -> Result<SpendBeforeSyncSummary, SyncError> {
+    check_activation(&options.network, &pool, start_height)?;
+    let start = now_ms();
+
+    let decrypted = trial_decrypt_range(
+        source.clone(),
+        keys,
+        pool.clone(),
+        start_height,
+        end_height,
+        batch_size,
+        u32::MAX,
+        options.clone(),
+    )
+    .await?;
+    let mut summary = SpendBeforeSyncSummary {
+        notes: decrypted.notes,
+        spendable_balance: decrypted.spendable_balance,
+        orchard_shards_filled: vec![],
+        sapling_shards_filled: vec![],
+        fill_blocks: 0,
+        orchard_tree_size: 0,
+        sapling_tree_size: 0,
+        report: decrypted.report,
+        state: None,
+    };
+    if summary.report.cancelled {
+        summary.report.finish(start);
+        return Ok(summary);
+    }
+
+    let end_tree_state = source.tree_state(end_height).await?;
+    let end_orchard = fetch_orchard_frontier_at_height(&mut source, end_height).await?;
+    let end_sapling = fetch_sapling_frontier_at_height(&mut source, end_height).await?;
+    let mut orchard = PoolShards::new(ShieldedPool::Orchard, end_orchard.tree_size());
+    let mut sapling = PoolShards::new(ShieldedPool::Sapling, end_sapling.tree_size());
+    for note in &summary.notes {
+        let position = note.position.ok_or_else(|| SyncError::MalformedField {
+            height: note.height as u64,
+            reason: "Blocks need chain metadata to place notes in their shards".to_string(),
+        })?;
+        match note.pool {
+            ShieldedPool::Orchard => orchard.notes.insert(position),
+            _ => sapling.notes.insert(position),
+        };
+    }
+    let mut ranges = vec![];
+    if pool.sync_orchard() {
+        orchard.roots =
+            fetch_subtree_roots(&mut source, ShieldedProtocol::Orchard, end_height).await?;
+        orchard.plan_fill();
+        ranges.extend(
+            orchard
+                .block_ranges(&mut source, &options.network, end_height)
+                .await?,
+        );
+    }
+    if pool.sync_sapling() {
+        sapling.roots =
+            fetch_subtree_roots(&mut source, ShieldedProtocol::Sapling, end_height).await?;
+        sapling.plan_fill();
+        ranges.extend(
+            sapling
+                .block_ranges(&mut source, &options.network, end_height)
+                .await?,
+        );
+    }
+    // the trees are checkpointed at the end of each range filled, where they can be rewound to
+    let range_ends = ranges
+        .iter()
+        .map(|(_, end)| *end)
+        .filter(|end| *end < end_height)
+        .collect::<BTreeSet<_>>();
+    let ranges = merge_ranges(ranges);
+    console_log!(
+        "Filling Orchard shards {:?} and Sapling shards {:?} from blocks {:?}",
+        orchard.fill,
+        sapling.fill,
+        ranges
+    );
+
+    let mut pipeline = Pipeline::new(options.queue_depth as usize);
+    let blocks = pipeline.source("download", {
+        let source = source.clone();
+        let (retry_policy, n_streams, cancel, checkpoint_interval) = (
+            options.retry_policy,
+            options.n_download_streams,
+            options.cancel.clone(),
+            options.checkpoint_interval,
+        );
+        stream::iter(ranges.clone())
+            .flat_map(move |(start, end)| {
+                download_block_batches(
+                    source.clone(),
+                    start,
+                    end,
+                    batch_size,
+                    n_streams,
+                    retry_policy,
+                    &cancel,
+                    checkpoint_interval,
+                    Vec::new(),
+                )
+            })
+            .boxed_local()
+    });
+    let network = options.network;
+    let batch_pool = pool.clone();
+    let contents = pipeline.stage("convert", blocks, move |blocks| {
+        let pool = batch_pool.clone();
+        spawn_rayon(move || batch_contents(blocks, &network, &pool, u32::MAX))
+    });
+    let mut progress = ProgressTracker::steps(
+        ProgressPhase::TreeSync,
+        ranges.iter().map(|(start, end)| end + 1 - start).sum(),
+        options.progress.clone(),
+    );
+    let cancel = options.cancel.clone();
+    let collect = async move {
+        let mut contents = contents;
+        let mut stats = StageStats::new("collect");
+        let mut orchard = orchard;
+        let mut sapling = sapling;
+        let mut ends = vec![];
+        let (mut blocks, mut bytes) = (0, 0);
+        while let Some(batch) = contents.next().await {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let contents = match batch {
+                Ok(contents) => contents,
+                Err(e) => return (Err(e), stats),
+            };
+            let placed = stats
+                .time(async {
+                    contents
+                        .actions
+                        .iter()
+                        .zip(&contents.action_locations)
+                        .try_for_each(|((_, action), location)| {
+                            orchard.collect(location.position, location.height, || {
+                                MerkleHashOrchard::from_cmx(&action.cmx())
+                            })
+                        })?;
+                    contents
+                        .outputs
+                        .iter()
+                        .zip(&contents.output_locations)
+                        .try_for_each(|((_, output), location)| {
+                            sapling.collect(location.position, location.height, || {
+                                sapling::Node::from_cmu(&output.cmu)
+                            })
+                        })
+                })
+                .await;
+            if let Err(e) = placed {
+                return (Err(e), stats);
+            }
+            ends.extend(
+                contents
+                    .block_ends
+                    .iter()
+                    .filter(|end| range_ends.contains(&end.height))
+                    .cloned(),
+            );
+            blocks += contents.blocks as u64;
+            bytes += contents.bytes;
+            progress.advance(contents.blocks);
+        }
+        (Ok((orchard, sapling, ends, blocks, bytes)), stats)
+    };
+
+    let (result, pipeline) = pipeline.run(collect).await;
+    pipeline.log();
+    let (mut orchard, mut sapling, ends, fill_blocks, fill_bytes) = result?;
+    summary.fill_blocks = fill_blocks;
+    summary.report.bytes_received += fill_bytes;
+    summary.report.add_pipeline(pipeline);
+    if options.cancel.is_cancelled() {
+        summary.report.cancelled = true;
+        summary.report.finish(start);
+        return Ok(summary);
+    }
+
+    // a checkpoint needs the commitments of both trees up to it, so the ends of ranges where either
+    // tree is in a shard that only has its root are skipped
+    let mut checkpoints = vec![];
+    for end in ends {
+        // the tree of a pool that isn't synced is left empty
+        let size = |synced: bool, size: Option<u64>| if synced { size } else { Some(0) };
+        let (orchard_size, sapling_size) = match (
+            size(pool.sync_orchard(), end.orchard_tree_size),
+            size(pool.sync_sapling(), end.sapling_tree_size),
+        ) {
+            (Some(orchard_size), Some(sapling_size)) => (orchard_size, sapling_size),
+            _ => continue,
+        };
+        if orchard.can_rewind_to(orchard_size) && sapling.can_rewind_to(sapling_size) {
+            orchard.checkpoint(end.height, orchard_size);
+            sapling.checkpoint(end.height, sapling_size);
+            checkpoints.push((end.height, end.hash, orchard_size, sapling_size));
+        }
+    }
+
+    let insert_start = now_ms();
+    let mut trees = TreeSyncState::from_subtree_roots(&orchard.roots, &sapling.roots)?;
+    summary.orchard_shards_filled = orchard.fill.iter().copied().collect();
+    summary.sapling_shards_filled = sapling.fill.iter().copied().collect();
+    let shards = orchard
+        .into_commitments(ShardCommitments::Orchard)?
+        .into_iter()
+        .chain(sapling.into_commitments(ShardCommitments::Sapling)?);
+    for shard in shards {
+        trees = spawn_rayon(move || {
+            trees.fill_shard(shard);
+            trees
+        })
+        .await;
+    }
+    for (height, hash, orchard_size, sapling_size) in checkpoints {
+        trees.add_checkpoint(height, hash, orchard_size, sapling_size);
+    }
+    trees.checkpoint(end_height, block_hash(&end_tree_state)?)?;
+    summary.report.phases.tree_insert_ms += now_ms() - insert_start;
+
+    let check_start = now_ms();
+    trees.check_roots(&pool, &end_orchard, &end_sapling, end_height)?;
+    summary.report.phases.root_check_ms = now_ms() - check_start;
+
+    let witness_start = now_ms();
+    let (orchard_witnesses, sapling_witnesses) =
+        trees.check_witnesses(&end_orchard, &end_sapling, end_height)?;
+    summary.report.orchard.witnesses = orchard_witnesses;
+    summary.report.sapling.witnesses = sapling_witnesses;
+    summary.report.phases.witness_ms = now_ms() - witness_start;
+
+    summary.orchard_tree_size = trees.orchard_cursor.into();
+    summary.sapling_tree_size = trees.sapling_cursor.into();
+    summary.state = Some(SyncState {
+        pool,
+        last_height: end_height,
+        last_block_hash: block_hash(&end_tree_state)?,
+        trees,
+        notes: WalletNotes::from_notes(summary.notes.clone()),
+    });
+    summary.report.finish(start);
+    console_log!(
+        "Spend-before-sync complete. Filled {} Orchard and {} Sapling shards from {} blocks",
+        summary.orchard_shards_filled.len(),
+        summary.sapling_shards_filled.len(),
+        summary.fill_blocks
+    );
+    Ok(summary)
+}
+
+/// The shards of one pool's tree to fill in and the commitments collected for them
+struct PoolShards<H> {
+    pool: ShieldedPool,
+    /// Size of the tree at the end of the range
+    tree_size: u64,
+    /// Roots of the shards completed by the end of the range
+    roots: Vec<SubtreeRoot>,
+    /// Positions of the wallet's notes, which are marked
+    notes: BTreeSet<u64>,
+    /// Indices of the shards holding a note, and of the incomplete shard at the end of the range
+    fill: BTreeSet<u64>,
+    commitments: BTreeMap<u64, Vec<(H, Retention<BlockHeight>)>>,
+}
+
+impl<H> PoolShards<H> {
+    fn new(pool: ShieldedPool, tree_size: u64) -> Self {
+        PoolShards {
+            pool,
+            tree_size,
+            roots: vec![],
+            notes: BTreeSet::new(),
+            fill: BTreeSet::new(),
+            commitments: BTreeMap::new(),
+        }
+    }
+
+    fn shard_height(&self) -> u8 {
+        match self.pool {
+            ShieldedPool::Orchard => ORCHARD_SHARD_HEIGHT,
+            _ => SAPLING_SHARD_HEIGHT,
+        }
+    }
+
+    /// Whether the tree can be rewound to when it held `size` commitments, which needs the last of
+    /// them to be in a shard being filled rather than one that only has its root
+    fn can_rewind_to(&self, size: u64) -> bool {
+        match size.checked_sub(1) {
+            Some(last) => self.fill.contains(&(last >> self.shard_height())),
+            None => true,
+        }
+    }
+
+    /// Keep the last of the first `size` commitments as a checkpoint at `height` so the tree can be
+    /// rewound to it
+    fn checkpoint(&mut self, height: u32, size: u64) {
+        let last = match size.checked_sub(1) {
+            Some(last) => last,
+            None => return,
+        };
+        let shard_height = self.shard_height();
+        let offset = (last & ((1 << shard_height) - 1)) as usize;
+        let commitment = self
+            .commitments
+            .get_mut(&(last >> shard_height))
+            .and_then(|commitments| commitments.get_mut(offset));
+        match commitment {
+            // a block without commitments shares its last one with the checkpoint before it
+            Some((_, Retention::Checkpoint { .. })) | None => {}
+            Some((_, retention)) => {
+                *retention = Retention::Checkpoint {
+                    id: height.into(),
+                    is_marked: retention.is_marked(),
+                }
+            }
+        }
+    }
+
+    /// Choose the shards to fill once the roots and notes are known
+    fn plan_fill(&mut self) {
+        let shard_height = self.shard_height();
+        self.fill = self.notes.iter().map(|p| p >> shard_height).collect();
+        let completed = self.roots.len() as u64;
+        if self.tree_size > completed << shard_height {
+            self.fill.insert(completed);
+        }
+    }
+
+    /// The blocks holding the commitments of each shard to fill.
+    ///
+    /// A shard starts in the block that completed the one before it, unless that block ended exactly
+    /// on the shard boundary, and ends in the block that completed it or at the end of the range.
+    async fn block_ranges(
+        &self,
+        source: &mut impl BlockSource,
+        network: &Network,
+        end_height: u32,
+    ) -> Result<Vec<(u32, u32)>, SyncError> {
+        let shard_height = self.shard_height();
+        let mut ranges = vec![];
+        for &index in &self.fill {
+            let start = match index.checked_sub(1) {
+                None => self.pool.activation_height(network),
+                Some(previous) => {
+                    let height = self.roots[previous as usize].completing_block_height as u32;
+                    let size = match self.pool {
+                        ShieldedPool::Orchard => fetch_orchard_frontier_at_height(source, height)
+                            .await?
+                            .tree_size(),
+                        _ => fetch_sapling_frontier_at_height(source, height)
+                            .await?
+                            .tree_size(),
+                    };
+                    if size == index << shard_height {
+                        height + 1
+                    } else {
+                        height
+                    }
+                }
+            };
+            let end = self
+                .roots
+                .get(index as usize)
+                .map_or(end_height, |root| root.completing_block_height as u32);
+            ranges.push((start, end));
+        }
+        Ok(ranges)
+    }
+
+    /// Keep the commitment at `position` if it is in a shard being filled, marking it if it is a note
+    fn collect(
+        &mut self,
+        position: Option<u64>,
+        height: u32,
+        node: impl FnOnce() -> H,
+    ) -> Result<(), SyncError> {
+        let position = position.ok_or_else(|| SyncError::MalformedField {
+            height: height as u64,
+            reason: "Blocks need chain metadata to place commitments in their shards".to_string(),
+        })?;
+        let shard_height = self.shard_height();
+        let index = position >> shard_height;
+        if !self.fill.contains(&index) {
+            return Ok(());
+        }
+        let retention = if self.notes.contains(&position) {
+            Retention::Marked
+        } else {
+            Retention::Ephemeral
+        };
+        let commitments = self.commitments.entry(index).or_default();
+        // blocks can be downloaded for more than one shard so a position may already be collected
+        if (index << shard_height) + commitments.len() as u64 == position {
+            commitments.push((node(), retention));
+        }
+        Ok(())
+    }
+
+    /// Check every shard was filled from its first position up to its size, and return the commitments
+    fn into_commitments(
+        self,
+        shard: impl Fn(Position, Vec<(H, Retention<BlockHeight>)>) -> ShardCommitments,
+    ) -> Result<Vec<ShardCommitments>, SyncError> {
+        let shard_height = self.shard_height();
+        let PoolShards {
+            pool,
+            tree_size,
+            fill,
+            mut commitments,
+            ..
+        } = self;
+        fill.iter()
+            .map(|&index| {
+                let start = index << shard_height;
+                let expected = (tree_size - start).min(1 << shard_height);
+                let filled = commitments.remove(&index).unwrap_or_default();
+                if filled.len() as u64 != expected {
+                    return Err(SyncError::IncompleteShard {
+                        pool: pool.clone(),
+                        index,
+                        expected,
+                        found: filled.len() as u64,
+                    });
+                }
+                Ok(shard(Position::from(start), filled))
+            })
+            .collect()
+    }
+}
+
+/// Sort the block ranges and merge any that overlap or are adjacent
+fn merge_ranges(mut ranges: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u32, u32)> = vec![];
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
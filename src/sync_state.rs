@@ -0,0 +1,589 @@
+/**
+ * Wallet sync state that is saved between runs so a later run only has to sync the blocks added since.
+ *
+ * A `SyncState` holds the last block synced, the note commitment trees (their shards, cap and
+ * checkpoints) and the notes found so far. The set of unspent nullifiers is rebuilt from the notes when
+ * the state is loaded. States are serialised to a versioned binary format and kept in a `StateStore`:
+ * in memory, in files natively or in IndexedDB in the browser.
+ */
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use incrementalmerkletree::{Address, Hashable, Level, Position};
+use shardtree::store::memory::MemoryShardStore;
+use shardtree::store::{Checkpoint, ShardStore, TreeState};
+use shardtree::{LocatedTree, Node, PrunableTree, RetentionFlags, ShardTree, Tree};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use zcash_encoding::{CompactSize, Optional, Vector};
+use zcash_primitives::consensus::BlockHeight;
+use zcash_primitives::merkle_tree::HashSer;
+
+use crate::bench_params::ShieldedPool;
+use crate::block_source::BlockSource;
+use crate::commitment_tree::{TreeSyncState, MAX_CHECKPOINTS};
+use crate::console_log;
+use crate::error::SyncError;
+use crate::proto::service;
+use crate::trial_decryption::{DecryptedNote, NoteSpend};
+use crate::wallet_notes::WalletNotes;
+
+/// Identifies a serialised sync state
+const MAGIC: &[u8; 4] = b"ZWBS";
+/// Bumped whenever the format changes. States in an older format are rejected rather than migrated.
+const STATE_VERSION: u8 = 2;
+
+/// Everything needed to continue a wallet sync from the last block synced
+pub struct SyncState {
+    /// The pools whose trees and notes are kept
+    pub pool: ShieldedPool,
+    pub last_height: u32,
+    /// Hash of the block at `last_height` in the internal byte order used by compact blocks
+    pub last_block_hash: Vec<u8>,
+    pub(crate) trees: TreeSyncState,
+    pub(crate) notes: WalletNotes,
+}
+
+impl std::fmt::Debug for SyncState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SyncState")
+            .field("pool", &self.pool)
+            .field("last_height", &self.last_height)
+            .field("last_block_hash", &hex::encode(&self.last_block_hash))
+            .field("orchard_tree_size", &self.orchard_tree_size())
+            .field("sapling_tree_size", &self.sapling_tree_size())
+            .field("notes", &self.notes().len())
+            .finish()
+    }
+}
+
+impl SyncState {
+    /// A new state as of the end of the block at `height`, with the trees initialised from the
+    /// frontiers the source reports for that block and no notes
+    pub async fn bootstrap(
+        source: &mut impl BlockSource,
+        pool: ShieldedPool,
+        height: u32,
+    ) -> Result<SyncState, SyncError> {
+        let trees = TreeSyncState::bootstrap(source, &pool, height).await?;
+        let (_, last_block_hash) = trees.checkpoint_hashes[0].clone();
+        Ok(SyncState {
+            pool,
+            last_height: height,
+            last_block_hash,
+            trees,
+            notes: WalletNotes::default(),
+        })
+    }
+
+    pub fn notes(&self) -> &[DecryptedNote] {
+        self.notes.notes()
+    }
+
+    pub fn spendable_balance(&self) -> u64 {
+        self.notes.spendable_balance()
+    }
+
+    pub fn orchard_tree_size(&self) -> u64 {
+        self.trees.orchard_cursor.into()
+    }
+
+    pub fn sapling_tree_size(&self) -> u64 {
+        self.trees.sapling_cursor.into()
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_u8(STATE_VERSION)?;
+        write_pool(&mut writer, &self.pool)?;
+        writer.write_u32::<LittleEndian>(self.last_height)?;
+        Vector::write(&mut writer, &self.last_block_hash, |w, b| w.write_u8(*b))?;
+
+        let trees = &self.trees;
+        writer.write_u64::<LittleEndian>(trees.orchard_cursor.into())?;
+        writer.write_u32::<LittleEndian>(trees.orchard_marked)?;
+        write_tree(&mut writer, &trees.orchard_tree)?;
+        writer.write_u64::<LittleEndian>(trees.sapling_cursor.into())?;
+        writer.write_u32::<LittleEndian>(trees.sapling_marked)?;
+        write_tree(&mut writer, &trees.sapling_tree)?;
+        Vector::write(
+            &mut writer,
+            &trees.checkpoint_hashes,
+            |w, (height, hash)| {
+                w.write_u32::<LittleEndian>(*height)?;
+                Vector::write(w, hash, |w, b| w.write_u8(*b))
+            },
+        )?;
+
+        Vector::write(&mut writer, self.notes.notes(), |w, note| {
+            write_note(w, note)
+        })
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<SyncState> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("Not a sync state".into()));
+        }
+        let version = reader.read_u8()?;
+        if version != STATE_VERSION {
+            return Err(invalid_data(format!(
+                "Sync state version {} is not supported, expected {}",
+                version, STATE_VERSION
+            )));
+        }
+        let pool = read_pool(&mut reader)?;
+        let last_height = reader.read_u32::<LittleEndian>()?;
+        let last_block_hash = Vector::read(&mut reader, |r| r.read_u8())?;
+
+        let orchard_cursor = Position::from(reader.read_u64::<LittleEndian>()?);
+        let orchard_marked = reader.read_u32::<LittleEndian>()?;
+        let orchard_tree = read_tree(&mut reader)?;
+        let sapling_cursor = Position::from(reader.read_u64::<LittleEndian>()?);
+        let sapling_marked = reader.read_u32::<LittleEndian>()?;
+        let sapling_tree = read_tree(&mut reader)?;
+        let checkpoint_hashes = Vector::read(&mut reader, |r| {
+            Ok((
+                r.read_u32::<LittleEndian>()?,
+                Vector::read(r, |r| r.read_u8())?,
+            ))
+        })?;
+
+        let notes = Vector::read(&mut reader, |r| read_note(r))?;
+        Ok(SyncState {
+            pool,
+            last_height,
+            last_block_hash,
+            trees: TreeSyncState {
+                orchard_tree,
+                orchard_cursor,
+                sapling_tree,
+                sapling_cursor,
+                orchard_marked,
+                sapling_marked,
+                checkpoint_hashes,
+            },
+            notes: WalletNotes::from_notes(notes),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes).expect("writing to a vec can't fail");
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<SyncState> {
+        SyncState::read(bytes)
+    }
+}
+
+/// Load the state saved under `key`, or bootstrap a new one as of the end of the block at `height`
+/// if nothing has been saved yet
+pub async fn load_or_bootstrap(
+    store: &mut impl StateStore,
+    key: &str,
+    source: &mut impl BlockSource,
+    pool: ShieldedPool,
+    height: u32,
+) -> Result<SyncState, SyncError> {
+    let bytes = store
+        .load(key)
+        .await
+        .map_err(|e| SyncError::InvalidState(format!("Failed to load '{}': {:#}", key, e)))?;
+    match bytes {
+        Some(bytes) => {
+            let state = SyncState::from_bytes(&bytes)
+                .map_err(|e| SyncError::InvalidState(format!("Failed to read '{}': {}", key, e)))?;
+            if state.pool != pool {
+                return Err(SyncError::InvalidState(format!(
+                    "'{}' was synced for the {:?} pool, not {:?}",
+                    key, state.pool, pool
+                )));
+            }
+            console_log!("Loaded saved sync state {:?}", state);
+            Ok(state)
+        }
+        None => SyncState::bootstrap(source, pool, height).await,
+    }
+}
+
+/// Where the benchmarks save state by default: IndexedDB in the browser, the `sync-state`
+/// directory natively
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn default_state_store() -> anyhow::Result<IndexedDbStateStore> {
+    IndexedDbStateStore::open("zcash-wasm-bench").await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn default_state_store() -> anyhow::Result<FileStateStore> {
+    Ok(FileStateStore::new("sync-state"))
+}
+
+/// The hash of the block a tree state is for, in the internal byte order
+pub(crate) fn block_hash(tree_state: &service::TreeState) -> Result<Vec<u8>, SyncError> {
+    let mut hash = hex::decode(&tree_state.hash).map_err(|e| SyncError::MalformedField {
+        height: tree_state.height,
+        reason: format!("Invalid block hash: {}", e),
+    })?;
+    // lightwalletd gives the hash in display order
+    hash.reverse();
+    Ok(hash)
+}
+
+/// Somewhere serialised sync states can be saved, each under its own key
+#[allow(async_fn_in_trait)]
+pub trait StateStore {
+    /// The bytes saved under `key`, or None if nothing has been saved
+    async fn load(&mut self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Save `bytes` under `key`, replacing anything saved before
+    async fn save(&mut self, key: &str, bytes: &[u8]) -> anyhow::Result<()>;
+}
+
+/// Keeps states for the life of the process. Clones share the same states.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStateStore {
+    states: Rc<RefCell<HashMap<String, Vec<u8>>>>,
+}
+
+impl StateStore for MemoryStateStore {
+    async fn load(&mut self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.states.borrow().get(key).cloned())
+    }
+
+    async fn save(&mut self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        self.states
+            .borrow_mut()
+            .insert(key.to_string(), bytes.to_vec());
+        Ok(())
+    }
+}
+
+/// Keeps each state in a file named after its key in a directory. Not available in the browser.
+#[derive(Clone, Debug)]
+pub struct FileStateStore {
+    dir: PathBuf,
+}
+
+impl FileStateStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileStateStore { dir: dir.into() }
+    }
+
+    /// The file a key is saved in. Keys that could name a file outside the directory are rejected.
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        if key.is_empty() || key.contains(['/', '\\']) || key.contains("..") {
+            anyhow::bail!("'{}' can't be used as a file name for a sync state", key);
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+impl StateStore for FileStateStore {
+    async fn load(&mut self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match std::fs::read(self.path(key)?) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn save(&mut self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+        std::fs::create_dir_all(&self.dir)?;
+        // write then rename so an interrupted save doesn't leave a truncated state behind
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Keeps states in an IndexedDB object store, keyed by name. Only available in the browser,
+/// where it works on the main thread and in workers.
+pub struct IndexedDbStateStore {
+    db: web_sys::IdbDatabase,
+}
+
+const OBJECT_STORE: &str = "sync_state";
+
+impl IndexedDbStateStore {
+    /// Open (creating if needed) the database with the given name
+    pub async fn open(name: &str) -> anyhow::Result<Self> {
+        let factory: web_sys::IdbFactory =
+            js_sys::Reflect::get(&js_sys::global(), &"indexedDB".into())
+                .map_err(js_error)?
+                .dyn_into()
+                .map_err(|_| anyhow::anyhow!("IndexedDB is not available"))?;
+        let request = factory.open_with_u32(name, 1).map_err(js_error)?;
+        let on_upgrade_needed = Closure::once(move |event: web_sys::Event| {
+            let request: web_sys::IdbOpenDbRequest = event.target().unwrap().unchecked_into();
+            let db: web_sys::IdbDatabase = request.result().unwrap().unchecked_into();
+            db.create_object_store(OBJECT_STORE).unwrap();
+        });
+        request.set_onupgradeneeded(Some(on_upgrade_needed.as_ref().unchecked_ref()));
+        let db = request_result(&request).await?.unchecked_into();
+        Ok(IndexedDbStateStore { db })
+    }
+}
+
+impl StateStore for IndexedDbStateStore {
+    async fn load(&mut self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let store = self
+            .db
+            .transaction_with_str(OBJECT_STORE)
+            .and_then(|tx| tx.object_store(OBJECT_STORE))
+            .map_err(js_error)?;
+        let value = request_result(&store.get(&key.into()).map_err(js_error)?).await?;
+        if value.is_undefined() {
+            return Ok(None);
+        }
+        Ok(Some(js_sys::Uint8Array::new(&value).to_vec()))
+    }
+
+    async fn save(&mut self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let tx = self
+            .db
+            .transaction_with_str_and_mode(OBJECT_STORE, web_sys::IdbTransactionMode::Readwrite)
+            .map_err(js_error)?;
+        let store = tx.object_store(OBJECT_STORE).map_err(js_error)?;
+        let value = js_sys::Uint8Array::from(bytes);
+        store.put_with_key(&value, &key.into()).map_err(js_error)?;
+        // only durable once the whole transaction completes
+        let complete = js_sys::Promise::new(&mut |resolve, reject| {
+            tx.set_oncomplete(Some(&resolve));
+            tx.set_onerror(Some(&reject));
+        });
+        JsFuture::from(complete).await.map_err(js_error)?;
+        Ok(())
+    }
+}
+
+/// Wait for an IndexedDB request to finish and return its result
+async fn request_result(request: &web_sys::IdbRequest) -> anyhow::Result<JsValue> {
+    let done = js_sys::Promise::new(&mut |resolve, reject| {
+        request.set_onsuccess(Some(&resolve));
+        request.set_onerror(Some(&reject));
+    });
+    JsFuture::from(done).await.map_err(js_error)?;
+    request.result().map_err(js_error)
+}
+
+fn js_error(e: JsValue) -> anyhow::Error {
+    anyhow::anyhow!("{:?}", e)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_pool<W: Write>(mut writer: W, pool: &ShieldedPool) -> io::Result<()> {
+    writer.write_u8(match pool {
+        ShieldedPool::Sapling => 0,
+        ShieldedPool::Orchard => 1,
+        ShieldedPool::Both => 2,
+    })
+}
+
+fn read_pool<R: Read>(mut reader: R) -> io::Result<ShieldedPool> {
+    match reader.read_u8()? {
+        0 => Ok(ShieldedPool::Sapling),
+        1 => Ok(ShieldedPool::Orchard),
+        2 => Ok(ShieldedPool::Both),
+        pool => Err(invalid_data(format!("Invalid pool {}", pool))),
+    }
+}
+
+fn write_string<W: Write>(writer: W, s: &str) -> io::Result<()> {
+    Vector::write(writer, s.as_bytes(), |w, b| w.write_u8(*b))
+}
+
+fn read_string<R: Read>(reader: R) -> io::Result<String> {
+    String::from_utf8(Vector::read(reader, |r| r.read_u8())?)
+        .map_err(|e| invalid_data(e.to_string()))
+}
+
+fn write_note<W: Write>(mut writer: W, note: &DecryptedNote) -> io::Result<()> {
+    write_pool(&mut writer, &note.pool)?;
+    writer.write_u32::<LittleEndian>(note.height)?;
+    write_string(&mut writer, &note.txid)?;
+    writer.write_u32::<LittleEndian>(note.tx_index)?;
+    writer.write_u32::<LittleEndian>(note.output_index)?;
+    writer.write_u64::<LittleEndian>(note.value)?;
+    write_string(&mut writer, &note.recipient)?;
+    Optional::write(&mut writer, note.position, |w, p| {
+        w.write_u64::<LittleEndian>(p)
+    })?;
+    Optional::write(&mut writer, note.nullifier.as_deref(), write_string)?;
+    Optional::write(&mut writer, note.spent.as_ref(), |w, spent| {
+        w.write_u32::<LittleEndian>(spent.height)?;
+        write_string(w, &spent.txid)
+    })
+}
+
+fn read_note<R: Read>(mut reader: R) -> io::Result<DecryptedNote> {
+    Ok(DecryptedNote {
+        pool: read_pool(&mut reader)?,
+        height: reader.read_u32::<LittleEndian>()?,
+        txid: read_string(&mut reader)?,
+        tx_index: reader.read_u32::<LittleEndian>()?,
+        output_index: reader.read_u32::<LittleEndian>()?,
+        value: reader.read_u64::<LittleEndian>()?,
+        recipient: read_string(&mut reader)?,
+        position: Optional::read(&mut reader, |r| r.read_u64::<LittleEndian>())?,
+        nullifier: Optional::read(&mut reader, read_string)?,
+        spent: Optional::read(&mut reader, |r| {
+            Ok(NoteSpend {
+                height: r.read_u32::<LittleEndian>()?,
+                txid: read_string(r)?,
+            })
+        })?,
+    })
+}
+
+/// Write the cap, shards and checkpoints of a tree
+fn write_tree<H, W, const DEPTH: u8, const SHARD_HEIGHT: u8>(
+    mut writer: W,
+    tree: &ShardTree<MemoryShardStore<H, BlockHeight>, DEPTH, SHARD_HEIGHT>,
+) -> io::Result<()>
+where
+    H: Hashable + HashSer + Clone + PartialEq,
+    W: Write,
+{
+    let store = tree.store();
+    // the memory store can't fail
+    write_prunable_tree(&mut writer, &store.get_cap().unwrap())?;
+
+    let roots = store.get_shard_roots().unwrap();
+    CompactSize::write(&mut writer, roots.len())?;
+    for root in roots {
+        let shard = store.get_shard(root).unwrap().expect("shard exists");
+        writer.write_u8(root.level().into())?;
+        writer.write_u64::<LittleEndian>(root.index())?;
+        write_prunable_tree(&mut writer, shard.root())?;
+    }
+
+    let n_checkpoints = store.checkpoint_count().unwrap();
+    CompactSize::write(&mut writer, n_checkpoints)?;
+    // depth 1 is the latest checkpoint, so count down to write them oldest first
+    for depth in (1..=n_checkpoints).rev() {
+        let (id, checkpoint) = store.get_checkpoint_at_depth(depth).unwrap().unwrap();
+        writer.write_u32::<LittleEndian>(id.into())?;
+        Optional::write(&mut writer, checkpoint.position(), |w, p| {
+            w.write_u64::<LittleEndian>(p.into())
+        })?;
+        Vector::write_sized(&mut writer, checkpoint.marks_removed().iter(), |w, p| {
+            w.write_u64::<LittleEndian>((*p).into())
+        })?;
+    }
+    Ok(())
+}
+
+fn read_tree<H, R, const DEPTH: u8, const SHARD_HEIGHT: u8>(
+    mut reader: R,
+) -> io::Result<ShardTree<MemoryShardStore<H, BlockHeight>, DEPTH, SHARD_HEIGHT>>
+where
+    H: Hashable + HashSer + Clone + PartialEq,
+    R: Read,
+{
+    let mut store = MemoryShardStore::empty();
+    // the cap spans the levels above the shards
+    store
+        .put_cap(read_prunable_tree(&mut reader, DEPTH - SHARD_HEIGHT)?)
+        .unwrap();
+
+    let n_shards = CompactSize::read(&mut reader)?;
+    for _ in 0..n_shards {
+        let level = Level::from(reader.read_u8()?);
+        if level != Level::from(SHARD_HEIGHT) {
+            return Err(invalid_data(format!(
+                "Shard root at level {} rather than {}",
+                u8::from(level),
+                SHARD_HEIGHT
+            )));
+        }
+        let index = reader.read_u64::<LittleEndian>()?;
+        let root = read_prunable_tree(&mut reader, SHARD_HEIGHT)?;
+        store
+            .put_shard(LocatedTree::from_parts(
+                Address::from_parts(level, index),
+                root,
+            ))
+            .unwrap();
+    }
+
+    let n_checkpoints = CompactSize::read(&mut reader)?;
+    for _ in 0..n_checkpoints {
+        let id = BlockHeight::from(reader.read_u32::<LittleEndian>()?);
+        let tree_state = match Optional::read(&mut reader, |r| r.read_u64::<LittleEndian>())? {
+            Some(position) => TreeState::AtPosition(position.into()),
+            None => TreeState::Empty,
+        };
+        let marks_removed = Vector::read_collected::<_, _, _, BTreeSet<_>>(&mut reader, |r| {
+            r.read_u64::<LittleEndian>().map(Position::from)
+        })?;
+        store
+            .add_checkpoint(id, Checkpoint::from_parts(tree_state, marks_removed))
+            .unwrap();
+    }
+    Ok(ShardTree::new(store, MAX_CHECKPOINTS))
+}
+
+const NIL_TAG: u8 = 0;
+const LEAF_TAG: u8 = 1;
+const PARENT_TAG: u8 = 2;
+
+fn write_prunable_tree<H: HashSer, W: Write>(
+    writer: &mut W,
+    tree: &PrunableTree<H>,
+) -> io::Result<()> {
+    match &**tree {
+        Node::Parent { ann, left, right } => {
+            writer.write_u8(PARENT_TAG)?;
+            Optional::write(&mut *writer, ann.as_deref(), |w, h| h.write(w))?;
+            write_prunable_tree(writer, left)?;
+            write_prunable_tree(writer, right)
+        }
+        Node::Leaf {
+            value: (hash, flags),
+        } => {
+            writer.write_u8(LEAF_TAG)?;
+            hash.write(&mut *writer)?;
+            writer.write_u8(flags.bits())
+        }
+        Node::Nil => writer.write_u8(NIL_TAG),
+    }
+}
+
+/// Read a tree of at most `max_depth` levels, so a corrupted state can't recurse without bound
+fn read_prunable_tree<H: HashSer, R: Read>(
+    reader: &mut R,
+    max_depth: u8,
+) -> io::Result<PrunableTree<H>> {
+    match reader.read_u8()? {
+        PARENT_TAG => {
+            if max_depth == 0 {
+                return Err(invalid_data("Tree is deeper than the tree's height".into()));
+            }
+            let ann = Optional::read(&mut *reader, |r| H::read(r))?;
+            let left = read_prunable_tree(reader, max_depth - 1)?;
+            let right = read_prunable_tree(reader, max_depth - 1)?;
+            Ok(Tree::parent(ann.map(Arc::new), left, right))
+        }
+        LEAF_TAG => {
+            let hash = H::read(&mut *reader)?;
+            let flags = RetentionFlags::from_bits(reader.read_u8()?)
+                .ok_or_else(|| invalid_data("Invalid retention flags".into()))?;
+            Ok(Tree::leaf((hash, flags)))
+        }
+        NIL_TAG => Ok(Tree::empty()),
+        tag => Err(invalid_data(format!("Invalid tree node tag {}", tag))),
+    }
+}
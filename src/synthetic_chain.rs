@@ -0,0 +1,824 @@
+/**
+ * Generates synthetic chains of compact blocks containing real Sapling outputs and Orchard actions.
+ *
+ * Outputs are built with the same bundle builders a wallet would use so they can be trial decrypted
+ * and inserted into commitment trees exactly like mainnet data. A configurable fraction of the
+ * notes are sent to known viewing keys and can be spent again in later blocks, and the chain metadata and tree states are kept consistent
+ * with the generated commitments so the tree sync benchmark can verify its roots.
+ */
+use std::convert::TryInto;
+use std::io::Cursor;
+
+use incrementalmerkletree::frontier::{CommitmentTree, Frontier};
+use incrementalmerkletree::{Hashable, Level, Position};
+use orchard::keys::{FullViewingKey, Scope, SpendingKey};
+use orchard::tree::{MerkleHashOrchard, MerklePath};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use sapling::note_encryption::Zip212Enforcement;
+use sapling::zip32::{DiversifiableFullViewingKey, ExtendedSpendingKey};
+use wasm_bindgen::prelude::*;
+use zcash_note_encryption::COMPACT_NOTE_SIZE;
+use zcash_primitives::consensus::{self, BlockHeight};
+use zcash_primitives::merkle_tree::{write_commitment_tree, HashSer};
+use zcash_primitives::transaction::components::sapling::zip212_enforcement;
+
+use crate::bench_params::{Network, ShieldedPool};
+use crate::block_source::MemoryBlockSource;
+use crate::commitment_tree::{OrchardFrontier, SaplingFrontier, ORCHARD_SHARD_HEIGHT};
+use crate::fixture::{write_length_delimited, BlockFixture};
+use crate::proto::compact_formats::{
+    ChainMetadata, CompactBlock, CompactOrchardAction, CompactSaplingOutput, CompactSaplingSpend,
+    CompactTx,
+};
+use crate::proto::service::{SubtreeRoot, TreeState};
+
+// Average spacing between blocks on mainnet
+const BLOCK_TIME_SECONDS: u32 = 75;
+// Number of outputs in each regular (non-spam) transaction
+const OUTPUTS_PER_TX: u32 = 2;
+// Both pools' trees have shards of the same height
+const SHARD_HEIGHT: u8 = ORCHARD_SHARD_HEIGHT;
+
+/// Parameters for generating a synthetic chain
+#[derive(Clone, Debug)]
+pub struct SyntheticChainConfig {
+    /// The network whose upgrade heights apply, e.g. for the ZIP-212 enforcement of Sapling outputs
+    pub network: Network,
+    /// Height of the first generated block. A tree state is also produced for the block before.
+    pub start_height: u32,
+    pub n_blocks: u32,
+    /// Which pools to generate outputs for
+    pub pool: ShieldedPool,
+    /// Number of outputs/actions per pool in each non-empty block, excluding spam
+    pub outputs_per_block: u32,
+    /// Extra outputs/actions to strangers in each non-empty block. These reuse one encrypted note
+    /// with random commitments so chains large enough to complete shards are quick to generate.
+    pub filler_outputs_per_block: u32,
+    /// Fraction of transactions which are spam, having more outputs than `spam_filter_limit`
+    pub spam_fraction: f64,
+    pub spam_filter_limit: u32,
+    /// Fraction of blocks which contain no transactions at all
+    pub empty_block_ratio: f64,
+    /// Probability that any given non-spam output is sent to one of the known viewing keys
+    pub planted_note_probability: f64,
+    /// Probability that an unspent planted note is spent in each later non-empty block
+    pub spend_probability: f64,
+    /// Number of complete 2^16 leaf shards in each tree before the first generated block. They are
+    /// given random roots, served as subtree roots completed by the block before the first.
+    pub prior_shards: u32,
+    /// Number of commitments already in each tree before the first generated block, after any
+    /// prior shards
+    pub prior_commitments: u32,
+    pub orchard_recipients: Vec<FullViewingKey>,
+    pub sapling_recipients: Vec<DiversifiableFullViewingKey>,
+    pub seed: u64,
+    /// If set, the blocks after the fork height are generated from another seed, giving a fork of the
+    /// chain generated from the same config without it
+    pub fork: Option<SyntheticFork>,
+}
+
+/// Where a synthetic chain diverges from the one generated without the fork
+#[derive(Clone, Copy, Debug)]
+pub struct SyntheticFork {
+    /// Height of the last block shared by both chains
+    pub height: u32,
+    /// Seed for generating the blocks after it
+    pub seed: u64,
+}
+
+impl SyntheticChainConfig {
+    /// A config with defaults resembling a quiet period on mainnet. Planted notes are sent to the
+    /// Orchard key used by the trial decryption benchmark and a fixed Sapling key.
+    pub fn new(start_height: u32, n_blocks: u32) -> Self {
+        Self {
+            network: Network::Mainnet,
+            start_height,
+            n_blocks,
+            pool: ShieldedPool::Both,
+            outputs_per_block: 4,
+            filler_outputs_per_block: 0,
+            spam_fraction: 0.0,
+            spam_filter_limit: 50,
+            empty_block_ratio: 0.5,
+            planted_note_probability: 0.01,
+            spend_probability: 0.0,
+            prior_shards: 0,
+            prior_commitments: 1,
+            orchard_recipients: vec![FullViewingKey::from(
+                &SpendingKey::from_bytes([1; 32]).unwrap(),
+            )],
+            sapling_recipients: vec![
+                ExtendedSpendingKey::master(&[1; 32]).to_diversifiable_full_viewing_key()
+            ],
+            seed: 0,
+            fork: None,
+        }
+    }
+}
+
+/// A note generated for one of the known viewing keys
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct PlantedNote {
+    pub pool: ShieldedPool,
+    pub height: u32,
+    /// Position of the note commitment in its pool's commitment tree
+    pub position: u64,
+    pub value: u64,
+    /// Index into the config's recipients for the pool
+    pub recipient: usize,
+    /// Height of the block the note was spent in, if it was
+    pub spent_height: Option<u32>,
+}
+
+/// What's needed to spend a planted note in a later block
+enum PlantedSpend {
+    Sapling {
+        nullifier: [u8; 32],
+    },
+    Orchard {
+        recipient: usize,
+        note: orchard::Note,
+        position: u64,
+    },
+}
+
+/// The output of the generator
+#[derive(Clone, Debug)]
+pub struct SyntheticChain {
+    pub blocks: Vec<CompactBlock>,
+    /// Tree states for every generated block and the block before the first
+    pub tree_states: Vec<TreeState>,
+    pub planted_notes: Vec<PlantedNote>,
+    /// Roots of the shards completed before and during the chain
+    pub sapling_subtree_roots: Vec<SubtreeRoot>,
+    pub orchard_subtree_roots: Vec<SubtreeRoot>,
+}
+
+impl SyntheticChain {
+    pub fn into_block_source(self) -> MemoryBlockSource {
+        MemoryBlockSource::new(self.blocks, self.tree_states)
+            .with_subtree_roots(self.sapling_subtree_roots, self.orchard_subtree_roots)
+    }
+
+    pub fn to_fixture(&self) -> BlockFixture {
+        let mut blocks = Vec::new();
+        for block in &self.blocks {
+            write_length_delimited(&mut blocks, block).unwrap();
+        }
+        let mut tree_states = Vec::new();
+        for tree_state in &self.tree_states {
+            write_length_delimited(&mut tree_states, tree_state).unwrap();
+        }
+        let roots = |roots: &[SubtreeRoot]| {
+            let mut bytes = Vec::new();
+            for root in roots {
+                write_length_delimited(&mut bytes, root).unwrap();
+            }
+            bytes
+        };
+        BlockFixture::new(blocks, tree_states).with_subtree_roots(
+            roots(&self.sapling_subtree_roots),
+            roots(&self.orchard_subtree_roots),
+        )
+    }
+}
+
+/// Generate a synthetic chain fixture which can be replayed with `BenchParams.withFixture`
+#[wasm_bindgen]
+pub fn generate_synthetic_fixture(
+    start_height: u32,
+    n_blocks: u32,
+    outputs_per_block: u32,
+    spam_fraction: f64,
+    empty_block_ratio: f64,
+    seed: u64,
+) -> BlockFixture {
+    let config = SyntheticChainConfig {
+        outputs_per_block,
+        spam_fraction,
+        empty_block_ratio,
+        seed,
+        ..SyntheticChainConfig::new(start_height, n_blocks)
+    };
+    generate_synthetic_chain(&config).to_fixture()
+}
+
+pub fn generate_synthetic_chain(config: &SyntheticChainConfig) -> SyntheticChain {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let params = consensus::Network::from(&config.network);
+
+    // Recipients for notes that aren't planted, standing in for every other wallet on the chain
+    let stranger_orchard = FullViewingKey::from(&SpendingKey::from_bytes([0xff; 32]).unwrap());
+    let stranger_sapling =
+        ExtendedSpendingKey::master(&[0xff; 32]).to_diversifiable_full_viewing_key();
+
+    let mut chain = SyntheticChain {
+        blocks: Vec::with_capacity(config.n_blocks as usize),
+        tree_states: Vec::with_capacity(config.n_blocks as usize + 1),
+        planted_notes: Vec::new(),
+        sapling_subtree_roots: Vec::new(),
+        orchard_subtree_roots: Vec::new(),
+    };
+    let (mut orchard_frontier, mut sapling_frontier) = if config.prior_shards > 0 {
+        let (orchard_roots, orchard_frontier) =
+            frontier_with_prior_shards(config.prior_shards, || {
+                MerkleHashOrchard::from_bytes(&random_node_bytes(&mut rng)).unwrap()
+            });
+        let (sapling_roots, sapling_frontier) =
+            frontier_with_prior_shards(config.prior_shards, || {
+                sapling::Node::from_bytes(random_node_bytes(&mut rng)).unwrap()
+            });
+        // the completing block hash is filled in once the block before the chain is generated
+        let height = config.start_height - 1;
+        chain.orchard_subtree_roots = orchard_roots
+            .iter()
+            .map(|root| subtree_root(root, height, &[]))
+            .collect();
+        chain.sapling_subtree_roots = sapling_roots
+            .iter()
+            .map(|root| subtree_root(root, height, &[]))
+            .collect();
+        (orchard_frontier, sapling_frontier)
+    } else {
+        (OrchardFrontier::empty(), SaplingFrontier::empty())
+    };
+    for _ in 0..config.prior_commitments {
+        append_commitment(
+            &mut orchard_frontier,
+            &mut chain.orchard_subtree_roots,
+            MerkleHashOrchard::from_bytes(&random_node_bytes(&mut rng)).unwrap(),
+            config.start_height - 1,
+            &[],
+        );
+        append_commitment(
+            &mut sapling_frontier,
+            &mut chain.sapling_subtree_roots,
+            sapling::Node::from_bytes(random_node_bytes(&mut rng)).unwrap(),
+            config.start_height - 1,
+            &[],
+        );
+    }
+    let mut prev_hash = random_bytes(&mut rng);
+    for root in chain
+        .orchard_subtree_roots
+        .iter_mut()
+        .chain(&mut chain.sapling_subtree_roots)
+    {
+        root.completing_block_hash = prev_hash.clone();
+    }
+
+    // a note to a stranger in each pool whose encryption is shared by all the filler outputs
+    let filler_templates = (config.filler_outputs_per_block > 0).then(|| {
+        let zip212 = zip212_enforcement(&params, BlockHeight::from(config.start_height));
+        let (sapling, _) =
+            build_sapling_outputs(&mut rng, zip212, &[(None, &stranger_sapling)]).remove(0);
+        let (orchard, _) = build_orchard_actions(&mut rng, &[(None, &stranger_orchard)]).remove(0);
+        (sapling, orchard)
+    });
+
+    let time = |height: u32| height * BLOCK_TIME_SECONDS;
+    // planted notes which can still be spent, with their index in `planted_notes`
+    let mut unspent: Vec<(usize, PlantedSpend)> = Vec::new();
+
+    chain.tree_states.push(tree_state(
+        &params,
+        config.start_height - 1,
+        &prev_hash,
+        time(config.start_height - 1),
+        &sapling_frontier,
+        &orchard_frontier,
+    ));
+
+    for height in config.start_height..config.start_height + config.n_blocks {
+        if let Some(fork) = config.fork.filter(|fork| fork.height + 1 == height) {
+            rng = StdRng::seed_from_u64(fork.seed);
+        }
+        let hash = random_bytes(&mut rng);
+        let mut vtx = Vec::new();
+
+        if !rng.gen_bool(config.empty_block_ratio) {
+            // spend some of the notes planted in earlier blocks
+            let mut sapling_spends = Vec::new();
+            let mut i = 0;
+            while i < unspent.len() {
+                if !rng.gen_bool(config.spend_probability) {
+                    i += 1;
+                    continue;
+                }
+                let (planted, spend) = unspent.swap_remove(i);
+                chain.planted_notes[planted].spent_height = Some(height);
+                match spend {
+                    PlantedSpend::Sapling { nullifier } => {
+                        sapling_spends.push(CompactSaplingSpend {
+                            nf: nullifier.to_vec(),
+                        })
+                    }
+                    PlantedSpend::Orchard {
+                        recipient,
+                        note,
+                        position,
+                    } => {
+                        let fvk = &config.orchard_recipients[recipient];
+                        let mut tx = CompactTx {
+                            index: vtx.len() as u64,
+                            hash: random_bytes(&mut rng),
+                            ..Default::default()
+                        };
+                        for action in
+                            build_orchard_spend(&mut rng, fvk, note, position, &stranger_orchard)
+                        {
+                            append_commitment(
+                                &mut orchard_frontier,
+                                &mut chain.orchard_subtree_roots,
+                                orchard_node(&action),
+                                height,
+                                &hash,
+                            );
+                            tx.actions.push(action);
+                        }
+                        vtx.push(tx);
+                    }
+                }
+            }
+            if !sapling_spends.is_empty() {
+                vtx.push(CompactTx {
+                    index: vtx.len() as u64,
+                    hash: random_bytes(&mut rng),
+                    spends: sapling_spends,
+                    ..Default::default()
+                });
+            }
+
+            // group the regular outputs into transactions and sprinkle in spam
+            let mut remaining = config.outputs_per_block;
+            while remaining > 0 {
+                let (n_outputs, is_spam) = if rng.gen_bool(config.spam_fraction) {
+                    (config.spam_filter_limit + 1, true)
+                } else {
+                    let n = remaining.min(OUTPUTS_PER_TX);
+                    remaining -= n;
+                    (n, false)
+                };
+
+                let mut tx = CompactTx {
+                    index: vtx.len() as u64,
+                    hash: random_bytes(&mut rng),
+                    ..Default::default()
+                };
+                if config.pool.sync_sapling() {
+                    let outputs = (0..n_outputs)
+                        .map(|_| {
+                            pick_recipient(
+                                &mut rng,
+                                config,
+                                is_spam,
+                                &config.sapling_recipients,
+                                &stranger_sapling,
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    let zip212 = zip212_enforcement(&params, BlockHeight::from(height));
+                    for (output, planted) in build_sapling_outputs(&mut rng, zip212, &outputs) {
+                        if let Some((recipient, note)) = planted {
+                            let position = frontier_size(&sapling_frontier);
+                            if config.spend_probability > 0.0 {
+                                let nk =
+                                    config.sapling_recipients[recipient].to_nk(Scope::External);
+                                let nullifier = note.nf(&nk, position).0;
+                                unspent.push((
+                                    chain.planted_notes.len(),
+                                    PlantedSpend::Sapling { nullifier },
+                                ));
+                            }
+                            chain.planted_notes.push(PlantedNote {
+                                pool: ShieldedPool::Sapling,
+                                height,
+                                position,
+                                value: note.value().inner(),
+                                recipient,
+                                spent_height: None,
+                            });
+                        }
+                        append_commitment(
+                            &mut sapling_frontier,
+                            &mut chain.sapling_subtree_roots,
+                            sapling_node(&output),
+                            height,
+                            &hash,
+                        );
+                        tx.outputs.push(output);
+                    }
+                }
+                if config.pool.sync_orchard() {
+                    let outputs = (0..n_outputs)
+                        .map(|_| {
+                            pick_recipient(
+                                &mut rng,
+                                config,
+                                is_spam,
+                                &config.orchard_recipients,
+                                &stranger_orchard,
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    for (action, planted) in build_orchard_actions(&mut rng, &outputs) {
+                        if let Some((recipient, note)) = planted {
+                            let position = frontier_size(&orchard_frontier);
+                            if config.spend_probability > 0.0 {
+                                unspent.push((
+                                    chain.planted_notes.len(),
+                                    PlantedSpend::Orchard {
+                                        recipient,
+                                        note,
+                                        position,
+                                    },
+                                ));
+                            }
+                            chain.planted_notes.push(PlantedNote {
+                                pool: ShieldedPool::Orchard,
+                                height,
+                                position,
+                                value: note.value().inner(),
+                                recipient,
+                                spent_height: None,
+                            });
+                        }
+                        append_commitment(
+                            &mut orchard_frontier,
+                            &mut chain.orchard_subtree_roots,
+                            orchard_node(&action),
+                            height,
+                            &hash,
+                        );
+                        tx.actions.push(action);
+                    }
+                }
+                vtx.push(tx);
+            }
+
+            if let Some((sapling_template, orchard_template)) = &filler_templates {
+                let mut tx = CompactTx {
+                    index: vtx.len() as u64,
+                    hash: random_bytes(&mut rng),
+                    ..Default::default()
+                };
+                for _ in 0..config.filler_outputs_per_block {
+                    if config.pool.sync_sapling() {
+                        let output = CompactSaplingOutput {
+                            cmu: random_node_bytes(&mut rng).to_vec(),
+                            ..sapling_template.clone()
+                        };
+                        append_commitment(
+                            &mut sapling_frontier,
+                            &mut chain.sapling_subtree_roots,
+                            sapling_node(&output),
+                            height,
+                            &hash,
+                        );
+                        tx.outputs.push(output);
+                    }
+                    if config.pool.sync_orchard() {
+                        let action = CompactOrchardAction {
+                            nullifier: random_node_bytes(&mut rng).to_vec(),
+                            cmx: random_node_bytes(&mut rng).to_vec(),
+                            ..orchard_template.clone()
+                        };
+                        append_commitment(
+                            &mut orchard_frontier,
+                            &mut chain.orchard_subtree_roots,
+                            orchard_node(&action),
+                            height,
+                            &hash,
+                        );
+                        tx.actions.push(action);
+                    }
+                }
+                vtx.push(tx);
+            }
+        }
+
+        chain.blocks.push(CompactBlock {
+            proto_version: 1,
+            height: height as u64,
+            hash: hash.clone(),
+            prev_hash: prev_hash.clone(),
+            time: time(height),
+            header: vec![],
+            vtx,
+            chain_metadata: Some(ChainMetadata {
+                sapling_commitment_tree_size: frontier_size(&sapling_frontier) as u32,
+                orchard_commitment_tree_size: frontier_size(&orchard_frontier) as u32,
+            }),
+        });
+        chain.tree_states.push(tree_state(
+            &params,
+            height,
+            &hash,
+            time(height),
+            &sapling_frontier,
+            &orchard_frontier,
+        ));
+        prev_hash = hash;
+    }
+
+    chain
+}
+
+/// Choose who an output is sent to. Returns the index of the known key for planted notes.
+fn pick_recipient<'a, K>(
+    rng: &mut impl Rng,
+    config: &SyntheticChainConfig,
+    is_spam: bool,
+    known: &'a [K],
+    stranger: &'a K,
+) -> (Option<usize>, &'a K) {
+    if !is_spam && !known.is_empty() && rng.gen_bool(config.planted_note_probability) {
+        let i = rng.gen_range(0..known.len());
+        (Some(i), &known[i])
+    } else {
+        (None, stranger)
+    }
+}
+
+/// Build a Sapling bundle with one output per recipient and convert it to compact outputs.
+/// Each output is returned with (recipient index, note) if it was planted.
+fn build_sapling_outputs(
+    rng: &mut StdRng,
+    zip212: Zip212Enforcement,
+    recipients: &[(Option<usize>, &DiversifiableFullViewingKey)],
+) -> Vec<(CompactSaplingOutput, Option<(usize, sapling::Note)>)> {
+    let mut builder = sapling::builder::Builder::new(
+        zip212,
+        sapling::builder::BundleType::Coinbase,
+        sapling::Anchor::empty_tree(),
+    );
+    for (_, fvk) in recipients {
+        let value = rng.gen_range(1..100_000);
+        builder
+            .add_output(
+                None,
+                fvk.default_address().1,
+                sapling::value::NoteValue::from_raw(value),
+                None,
+            )
+            .unwrap();
+    }
+    let (bundle, metadata) = builder
+        .build::<sapling::circuit::SpendParameters, sapling::circuit::OutputParameters, _, i64>(
+            &mut *rng,
+        )
+        .unwrap()
+        .unwrap();
+
+    // Outputs are shuffled so map each back to the recipient it was built for
+    let mut planted = vec![None; recipients.len()];
+    for (i, (recipient, fvk)) in recipients.iter().enumerate() {
+        let output_index = metadata.output_index(i).unwrap();
+        planted[output_index] = recipient.map(|r| {
+            let ivk = sapling::note_encryption::PreparedIncomingViewingKey::new(
+                &fvk.to_ivk(Scope::External),
+            );
+            let (note, _) = sapling::note_encryption::try_sapling_compact_note_decryption(
+                &ivk,
+                &sapling::note_encryption::CompactOutputDescription::from(
+                    bundle.shielded_outputs()[output_index].clone(),
+                ),
+                zip212,
+            )
+            .unwrap();
+            (r, note)
+        });
+    }
+    bundle
+        .shielded_outputs()
+        .iter()
+        .map(|output| CompactSaplingOutput {
+            cmu: output.cmu().to_bytes().to_vec(),
+            ephemeral_key: output.ephemeral_key().0.to_vec(),
+            ciphertext: output.enc_ciphertext()[..COMPACT_NOTE_SIZE].to_vec(),
+        })
+        .zip(planted)
+        .collect()
+}
+
+/// Build an Orchard bundle with one action per recipient and convert it to compact actions.
+/// Each action is returned with (recipient index, note) if it was planted.
+fn build_orchard_actions(
+    rng: &mut StdRng,
+    recipients: &[(Option<usize>, &FullViewingKey)],
+) -> Vec<(CompactOrchardAction, Option<(usize, orchard::Note)>)> {
+    let mut builder = orchard::builder::Builder::new(
+        orchard::builder::BundleType::Coinbase,
+        orchard::Anchor::from_bytes([0; 32]).unwrap(),
+    );
+    for (_, fvk) in recipients {
+        let value = rng.gen_range(1..100_000);
+        builder
+            .add_output(
+                None,
+                fvk.address_at(0u32, Scope::External),
+                orchard::value::NoteValue::from_raw(value),
+                None,
+            )
+            .unwrap();
+    }
+    let (bundle, metadata) = builder.build::<i64>(&mut *rng).unwrap().unwrap();
+    let bundle: orchard::Bundle<_, i64> = bundle;
+
+    // Orchard actions are shuffled so map each back to the output it was built from
+    let mut actions = vec![None; recipients.len()];
+    for (i, (recipient, fvk)) in recipients.iter().enumerate() {
+        let action_index = metadata.output_action_index(i).unwrap();
+        let planted = recipient.map(|r| {
+            let (note, _, _) = bundle
+                .decrypt_output_with_key(action_index, &fvk.to_ivk(Scope::External))
+                .unwrap();
+            (r, note)
+        });
+        actions[action_index] = Some((to_compact_action(&bundle.actions()[action_index]), planted));
+    }
+    actions.into_iter().map(Option::unwrap).collect()
+}
+
+/// Build an Orchard bundle spending a planted note to `recipient` and convert it to compact actions.
+///
+/// The generator doesn't keep the full tree to produce a real Merkle path, so the spend is made
+/// against the anchor of an empty authentication path. Compact actions don't include the anchor.
+fn build_orchard_spend(
+    rng: &mut StdRng,
+    fvk: &FullViewingKey,
+    note: orchard::Note,
+    position: u64,
+    recipient: &FullViewingKey,
+) -> Vec<CompactOrchardAction> {
+    let merkle_path =
+        MerklePath::from_parts(position as u32, [MerkleHashOrchard::empty_leaf(); 32]);
+    let anchor = merkle_path.root(note.commitment().into());
+    let mut builder = orchard::builder::Builder::new(orchard::builder::BundleType::DEFAULT, anchor);
+    builder.add_spend(fvk.clone(), note, merkle_path).unwrap();
+    builder
+        .add_output(
+            None,
+            recipient.address_at(0u32, Scope::External),
+            note.value(),
+            None,
+        )
+        .unwrap();
+    let (bundle, _) = builder.build::<i64>(&mut *rng).unwrap().unwrap();
+    let bundle: orchard::Bundle<_, i64> = bundle;
+    bundle.actions().iter().map(to_compact_action).collect()
+}
+
+fn to_compact_action<A>(action: &orchard::Action<A>) -> CompactOrchardAction {
+    CompactOrchardAction {
+        nullifier: action.nullifier().to_bytes().to_vec(),
+        cmx: action.cmx().to_bytes().to_vec(),
+        ephemeral_key: action.encrypted_note().epk_bytes.to_vec(),
+        ciphertext: action.encrypted_note().enc_ciphertext[..COMPACT_NOTE_SIZE].to_vec(),
+    }
+}
+
+fn tree_state(
+    params: &consensus::Network,
+    height: u32,
+    hash: &[u8],
+    time: u32,
+    sapling_frontier: &SaplingFrontier,
+    orchard_frontier: &OrchardFrontier,
+) -> TreeState {
+    TreeState {
+        network: match params {
+            consensus::Network::MainNetwork => "main",
+            consensus::Network::TestNetwork => "test",
+        }
+        .to_string(),
+        height: height as u64,
+        // lightwalletd gives the hash in display order, the reverse of the order in compact blocks
+        hash: hex::encode(hash.iter().rev().copied().collect::<Vec<_>>()),
+        time,
+        sapling_tree: encode_frontier(sapling_frontier),
+        orchard_tree: encode_frontier(orchard_frontier),
+    }
+}
+
+/// Encode a frontier in the legacy commitment tree format returned by lightwalletd
+fn encode_frontier<H, const DEPTH: u8>(
+    frontier: &incrementalmerkletree::frontier::Frontier<H, DEPTH>,
+) -> String
+where
+    H: incrementalmerkletree::Hashable + zcash_primitives::merkle_tree::HashSer + Clone,
+{
+    let mut bytes = Cursor::new(Vec::new());
+    write_commitment_tree(&CommitmentTree::from_frontier(frontier), &mut bytes).unwrap();
+    hex::encode(bytes.into_inner())
+}
+
+fn orchard_node(action: &CompactOrchardAction) -> MerkleHashOrchard {
+    MerkleHashOrchard::from_bytes(&action.cmx.as_slice().try_into().unwrap()).unwrap()
+}
+
+fn sapling_node(output: &CompactSaplingOutput) -> sapling::Node {
+    sapling::Node::from_cmu(
+        &sapling::note::ExtractedNoteCommitment::from_bytes(
+            output.cmu.as_slice().try_into().unwrap(),
+        )
+        .unwrap(),
+    )
+}
+
+/// Append a commitment to a tree's frontier, recording the root of the shard it completes if any
+fn append_commitment<H, const DEPTH: u8>(
+    frontier: &mut Frontier<H, DEPTH>,
+    subtree_roots: &mut Vec<SubtreeRoot>,
+    node: H,
+    height: u32,
+    hash: &[u8],
+) where
+    H: Hashable + HashSer + Clone,
+{
+    frontier.append(node);
+    let frontier = frontier.value().unwrap();
+    let shard_level = Level::from(SHARD_HEIGHT);
+    if (u64::from(frontier.position()) + 1) % (1 << SHARD_HEIGHT) == 0 {
+        subtree_roots.push(subtree_root(
+            &frontier.root(Some(shard_level)),
+            height,
+            hash,
+        ));
+    }
+}
+
+fn subtree_root(root: &impl HashSer, height: u32, hash: &[u8]) -> SubtreeRoot {
+    let mut root_hash = Vec::new();
+    root.write(&mut root_hash).unwrap();
+    SubtreeRoot {
+        root_hash,
+        completing_block_hash: hash.to_vec(),
+        completing_block_height: height as u64,
+    }
+}
+
+/// A frontier just after `n_shards` complete shards with random roots, and those roots.
+/// Only the last shard's root is derived from the frontier's leaf and ommers.
+fn frontier_with_prior_shards<H, const DEPTH: u8>(
+    n_shards: u32,
+    mut random_node: impl FnMut() -> H,
+) -> (Vec<H>, Frontier<H, DEPTH>)
+where
+    H: Hashable + Clone,
+{
+    let leaf = random_node();
+    let mut ommers = (0..SHARD_HEIGHT).map(|_| random_node()).collect::<Vec<_>>();
+    let last_root = ommers
+        .iter()
+        .enumerate()
+        .fold(leaf.clone(), |node, (level, ommer)| {
+            H::combine(Level::from(level as u8), ommer, &node)
+        });
+    let mut roots = (1..n_shards).map(|_| random_node()).collect::<Vec<_>>();
+    roots.push(last_root);
+
+    // above the shards, the ommers are the roots of the complete subtrees left of the last shard
+    let last_shard = n_shards as u64 - 1;
+    for level in 0..DEPTH - SHARD_HEIGHT {
+        if (last_shard >> level) & 1 == 1 {
+            ommers.push(root_of_shards(&roots, level, (last_shard >> level) - 1));
+        }
+    }
+    let position = Position::from(((n_shards as u64) << SHARD_HEIGHT) - 1);
+    (roots, Frontier::from_parts(position, leaf, ommers).unwrap())
+}
+
+/// Root of the subtree `level` levels above the shards, at `index` at that level
+fn root_of_shards<H: Hashable + Clone>(roots: &[H], level: u8, index: u64) -> H {
+    if level == 0 {
+        roots[index as usize].clone()
+    } else {
+        H::combine(
+            Level::from(SHARD_HEIGHT + level - 1),
+            &root_of_shards(roots, level - 1, index * 2),
+            &root_of_shards(roots, level - 1, index * 2 + 1),
+        )
+    }
+}
+
+fn frontier_size<H, const DEPTH: u8>(
+    frontier: &incrementalmerkletree::frontier::Frontier<H, DEPTH>,
+) -> u64 {
+    frontier.value().map_or(0, |f| u64::from(f.position()) + 1)
+}
+
+fn random_bytes(rng: &mut impl RngCore) -> Vec<u8> {
+    let mut bytes = vec![0; 32];
+    rng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Random bytes which are a canonical encoding of both a Pallas base and a BLS12-381 scalar
+fn random_node_bytes(rng: &mut impl RngCore) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    rng.fill_bytes(&mut bytes);
+    bytes[31] &= 0x3f;
+    bytes
+}
@@ -1,127 +1,446 @@
-use futures_util::{pin_mut, StreamExt};
+use futures_util::StreamExt;
 use rand::rngs::OsRng;
 use rayon::prelude::*;
-use tonic_web_wasm_client::Client;
 use wasm_bindgen::prelude::*;
-use web_sys::console;
 
 use ff::Field;
 use orchard::keys::{FullViewingKey, PreparedIncomingViewingKey, Scope, SpendingKey};
 
-use crate::{console_debug, console_log};
+use crate::{console_debug, console_log, now_ms};
 use sapling::keys::SaplingIvk;
+use zcash_keys::address::UnifiedAddress;
+use zcash_keys::encoding::encode_payment_address;
 use zcash_note_encryption::{batch, BatchDomain, Domain, ShieldedOutput, COMPACT_NOTE_SIZE};
+use zcash_primitives::consensus::NetworkConstants;
 
-use crate::bench_params::{BenchParams, ShieldedPool};
-use crate::block_range_stream::block_contents_batch_stream;
-use crate::WasmGrpcClient;
+use crate::bench_params::{BenchParams, ShieldedPool, SyncOptions};
+use crate::bench_report::BenchReport;
+use crate::block_range_stream::{
+    batch_contents, check_activation, download_block_batches, BatchContents, OutputLocation,
+};
+use crate::block_source::BlockSource;
+use crate::cancel::CancellationToken;
+use crate::error::SyncError;
+use crate::keys::WalletKeys;
+use crate::pipeline::{spawn_rayon, Pipeline, StageStats};
+use crate::progress::{ProgressPhase, ProgressTracker};
+use crate::sync_state::block_hash;
+use crate::wallet_notes::WalletNotes;
 
-/// This is the top level function that will be called from the JS side
+/// The result of `trial_decryption_bench`
+#[wasm_bindgen]
+pub struct TrialDecryptionResult {
+    total_decryptions: f64,
+    notes: Vec<DecryptedNote>,
+    spendable_balance: u64,
+    report: BenchReport,
+}
+
+#[wasm_bindgen]
+impl TrialDecryptionResult {
+    /// Number of actions and outputs trial decrypted
+    #[wasm_bindgen(getter, js_name = totalDecryptions)]
+    pub fn total_decryptions(&self) -> f64 {
+        self.total_decryptions
+    }
+
+    /// Array of the notes found, see `DecryptedNote` for the fields of each
+    #[wasm_bindgen(getter)]
+    pub fn notes(&self) -> Result<JsValue, JsError> {
+        Ok(serde_wasm_bindgen::to_value(&self.notes)?)
+    }
+
+    /// Total value in zatoshis of the notes found which were not spent by `end_block`
+    #[wasm_bindgen(getter, js_name = spendableBalance)]
+    pub fn spendable_balance(&self) -> u64 {
+        self.spendable_balance
+    }
+
+    /// Timings and counts for the run, see `BenchReport`
+    #[wasm_bindgen(getter)]
+    pub fn report(&self) -> BenchReport {
+        self.report.clone()
+    }
+}
+
+impl TrialDecryptionResult {
+    pub fn decrypted_notes(&self) -> &[DecryptedNote] {
+        &self.notes
+    }
+}
+
+/// This is the top level function that will be called from the JS side.
+/// If a viewing key is given (see `WalletKeys::decode`) it is used instead of a dummy key.
 #[wasm_bindgen]
 pub async fn trial_decryption_bench(
     params: BenchParams,
     spam_filter_limit: u32,
-    _view_key: Option<Vec<u8>>,
-) -> f64 {
-    console::log_1(&format!("Starting Trial Decryption with params: {:?}", params).into());
+    view_key: Option<String>,
+) -> Result<TrialDecryptionResult, JsError> {
+    console_log!("Starting Trial Decryption with params: {:?}", params);
+    let params = params.resolve().await?;
+
+    let start = now_ms();
+    let keys = WalletKeys::for_bench(&params.network, view_key)?;
+    let key_setup_ms = now_ms() - start;
 
+    let source = params.block_source()?;
+    let options = params.sync_options();
     let BenchParams {
-        network: _,
         pool,
-        lightwalletd_url,
         start_block,
         end_block,
         block_batch_size,
+        ..
     } = params;
-    let client = WasmGrpcClient::new(Client::new(lightwalletd_url.clone()));
 
-    let (total_actions, total_outputs) = trial_decrypt_range(
-        client,
+    let mut summary = trial_decrypt_range(
+        source,
+        keys,
         pool,
         start_block,
         end_block,
         block_batch_size,
         spam_filter_limit,
+        options,
     )
-    .await;
-    (total_actions + total_outputs) as f64
+    .await?;
+    summary.report.phases.key_setup_ms = key_setup_ms;
+    summary.report.phases.total_ms += key_setup_ms;
+    Ok(TrialDecryptionResult {
+        total_decryptions: (summary.actions + summary.outputs) as f64,
+        notes: summary.notes,
+        spendable_balance: summary.spendable_balance,
+        report: summary.report,
+    })
+}
+
+/// Totals from trial decrypting a range of blocks
+#[derive(Clone, Debug)]
+pub struct TrialDecryptionSummary {
+    pub actions: u32,
+    pub outputs: u32,
+    /// Notes that decrypted with one of the keys, in the order they appear in the chain per pool
+    pub notes: Vec<DecryptedNote>,
+    /// Total value of the notes which were not spent by the end of the range
+    pub spendable_balance: u64,
+    pub report: BenchReport,
 }
 
-pub async fn trial_decrypt_range(
-    client: WasmGrpcClient,
+/// Trial decrypt all outputs/actions in the given range of blocks retrieved from the block source
+/// with the given keys.
+///
+/// Downloading, extracting the batch contents and decryption run as overlapping pipeline stages.
+#[allow(clippy::too_many_arguments)]
+pub async fn trial_decrypt_range<S: BlockSource + Clone + 'static>(
+    source: S,
+    keys: WalletKeys,
     pool: ShieldedPool,
     start_height: u32,
     end_height: u32,
     batch_size: u32,
     spam_filter_limit: u32,
-) -> (u32, u32) {
-    let ivks_orchard = crate::trial_decryption::dummy_ivk_orchard(1);
-    let ivks_sapling = crate::trial_decryption::dummy_ivk_sapling(1);
-
-    let s = block_contents_batch_stream(
-        client,
-        pool,
-        start_height,
-        end_height,
-        batch_size,
-        spam_filter_limit,
-    );
-    pin_mut!(s);
-    let (mut total_actions, mut total_outputs) = (0, 0);
-    while let Some((actions, outputs)) = s.next().await {
-        total_actions += actions.len() as u32;
-        total_outputs += outputs.len() as u32;
-
-        let ivks_orchard = ivks_orchard.clone();
-        let ivks_sapling = ivks_sapling.clone();
-        let (tx, rx) = futures_channel::oneshot::channel();
-        rayon::scope(|s| {
-            s.spawn(|_| {
-                batch_decrypt_compact(ivks_orchard.as_slice(), &actions);
-                batch_decrypt_compact(ivks_sapling.as_slice(), &outputs);
-                drop(actions);
-                drop(outputs);
-                tx.send(()).unwrap();
-            })
+    options: SyncOptions,
+) -> Result<TrialDecryptionSummary, SyncError> {
+    check_activation(&options.network, &pool, start_height)?;
+    let start = now_ms();
+    let mut report = BenchReport::default();
+    let mut notes = WalletNotes::default();
+    let mut next_height = start_height;
+    let mut known_blocks = Vec::new();
+    loop {
+        let mut pipeline = Pipeline::new(options.queue_depth as usize);
+        let blocks = pipeline.source(
+            "download",
+            download_block_batches(
+                source.clone(),
+                next_height,
+                end_height,
+                batch_size,
+                options.n_download_streams,
+                options.retry_policy,
+                &options.cancel,
+                options.checkpoint_interval,
+                std::mem::take(&mut known_blocks),
+            ),
+        );
+        let network = options.network;
+        let batch_pool = pool.clone();
+        let contents = pipeline.stage("convert", blocks, move |blocks| {
+            let pool = batch_pool.clone();
+            spawn_rayon(move || batch_contents(blocks, &network, &pool, spam_filter_limit))
         });
+        let mut progress = ProgressTracker::blocks(
+            ProgressPhase::TrialDecryption,
+            next_height,
+            end_height,
+            options.progress.clone(),
+        );
+        let cancel = options.cancel.clone();
+        let keys = keys.clone();
+        let decrypt = async move {
+            let mut contents = contents;
+            let mut stats = StageStats::new("decrypt");
+            let mut report = report;
+            let mut notes = notes;
+            let mut reorg = None;
+            while let Some(batch) = contents.next().await {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                let contents = match batch {
+                    Ok(batch) => batch,
+                    Err(SyncError::Reorg { fork_height }) => {
+                        reorg = Some(fork_height as u32);
+                        break;
+                    }
+                    Err(e) => return (Err(e), stats),
+                };
+
+                let keys = keys.clone();
+                let cancel = cancel.clone();
+                console_debug!("Awaiting decryption completion");
+                let (contents, decrypted) = stats
+                    .time(spawn_rayon(move || {
+                        let decrypted = decrypt_batch(&keys, &contents, &cancel);
+                        (contents, decrypted)
+                    }))
+                    .await;
+                // a batch cut short by cancellation is left out so the report only covers whole batches
+                let decrypted = match decrypted {
+                    Some(decrypted) => decrypted,
+                    None => break,
+                };
+                report.add_batch(&contents);
+                notes.add_notes(decrypted.into_notes());
+                notes.detect_spends(&contents);
+                progress.advance(contents.blocks);
+            }
+            (Ok((report, notes, reorg)), stats)
+        };
 
-        console_debug!("Awaiting decryption completion");
-        rx.await.unwrap();
+        let (result, pipeline) = pipeline.run(decrypt).await;
+        pipeline.log();
+        let reorg;
+        (report, notes, reorg) = result?;
+        report.add_pipeline(pipeline);
+
+        // forget what was found in the replaced blocks and continue on the new chain from the fork point
+        match reorg {
+            Some(fork_height) => {
+                notes.rollback(fork_height);
+                let hash = block_hash(&source.clone().tree_state(fork_height).await?)?;
+                known_blocks = vec![(fork_height, hash)];
+                report.last_height = Some(fork_height);
+                report.reorgs += 1;
+                next_height = fork_height + 1;
+            }
+            None => break,
+        }
     }
+    report.check_cancelled(&options.cancel, end_height);
+    report.orchard.notes = notes.count(ShieldedPool::Orchard);
+    report.sapling.notes = notes.count(ShieldedPool::Sapling);
+    report.finish(start);
 
-    console_log!("Decryption complete");
-    (total_actions, total_outputs)
+    let spendable_balance = notes.spendable_balance();
+    console_log!(
+        "Decryption complete. Found {} notes with a spendable balance of {} zatoshis",
+        notes.notes().len(),
+        spendable_balance
+    );
+    Ok(TrialDecryptionSummary {
+        actions: report.orchard.outputs as u32,
+        outputs: report.sapling.outputs as u32,
+        notes: notes.into_notes(),
+        spendable_balance,
+        report,
+    })
 }
 
+/// Outputs each thread trial decrypts between checks for cancellation. Large enough that
+/// splitting a thread's share of the batch doesn't lose much of the benefit of batch decryption.
+const CANCEL_CHECK_INTERVAL: usize = 1000;
+
+/// Trial decrypt the outputs with each of the keys.
+/// Returns the index of each output that decrypted and of the key that decrypted it,
+/// along with its note and recipient. If `cancel` is cancelled the remaining outputs are skipped.
 pub(crate) fn batch_decrypt_compact<D: BatchDomain, Output: ShieldedOutput<D, COMPACT_NOTE_SIZE>>(
     ivks: &[D::IncomingViewingKey],
     compact: &[(D, Output)],
-) -> u32
+    cancel: &CancellationToken,
+) -> Vec<(usize, usize, D::Note, D::Recipient)>
 where
     (D, Output): Sync + Send,
-    <D as Domain>::Note: Send + std::fmt::Debug,
-    <D as Domain>::Recipient: Send + std::fmt::Debug,
-    <D as Domain>::IncomingViewingKey: Sync + std::fmt::Debug,
+    <D as Domain>::Note: Send,
+    <D as Domain>::Recipient: Send,
+    <D as Domain>::IncomingViewingKey: Sync,
 {
-    if compact.is_empty() {
+    if compact.is_empty() || ivks.is_empty() {
         console_debug!("No outputs to decrypt");
-        return 0;
+        return vec![];
     }
-    let num_parallel = rayon::current_num_threads();
+    let chunk_size = usize::div_ceil(compact.len(), rayon::current_num_threads());
+
+    compact
+        .par_chunks(chunk_size)
+        .enumerate()
+        .flat_map_iter(|(chunk, c)| {
+            c.chunks(CANCEL_CHECK_INTERVAL)
+                .enumerate()
+                .take_while(|_| !cancel.is_cancelled())
+                .flat_map(move |(piece, p)| {
+                    let offset = chunk * chunk_size + piece * CANCEL_CHECK_INTERVAL;
+                    batch::try_compact_note_decryption(ivks, p)
+                        .into_iter()
+                        .enumerate()
+                        .filter_map(move |(i, result)| {
+                            result
+                                .map(|((note, recipient), key)| (offset + i, key, note, recipient))
+                        })
+                })
+        })
+        .collect()
+}
 
-    let valid_results = compact
-        .par_chunks(usize::div_ceil(compact.len(), num_parallel))
-        .map(|c| batch::try_compact_note_decryption(ivks, c))
-        .flatten()
-        .flatten()
-        .collect::<Vec<_>>();
+/// A note received by one of the wallet's keys
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct DecryptedNote {
+    pub pool: ShieldedPool,
+    pub height: u32,
+    /// Transaction id as hex in the usual (byte reversed) display order
+    pub txid: String,
+    /// Index of the transaction within its block
+    pub tx_index: u32,
+    /// Index of the output or action within its transaction
+    pub output_index: u32,
+    /// Value in zatoshis
+    pub value: u64,
+    /// The address the note was sent to. Orchard recipients are encoded as unified addresses
+    pub recipient: String,
+    /// Position of the note commitment in its pool's commitment tree, if known
+    pub position: Option<u64>,
+    /// Hex encoded nullifier, if the keys can derive it. Sapling nullifiers also need the position.
+    pub nullifier: Option<String>,
+    /// The transaction that spent the note, if a spend was seen in the synced range
+    pub spent: Option<NoteSpend>,
+}
+
+/// Where a note was spent
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct NoteSpend {
+    pub height: u32,
+    /// Transaction id as hex in the usual (byte reversed) display order
+    pub txid: String,
+}
 
-    if valid_results.is_empty() {
+/// Format a transaction hash from a compact block in the usual (byte reversed) display order
+pub(crate) fn display_txid(hash: &[u8]) -> String {
+    let mut txid = hash.to_vec();
+    txid.reverse();
+    hex::encode(txid)
+}
+
+impl DecryptedNote {
+    fn new(
+        pool: ShieldedPool,
+        contents: &BatchContents,
+        location: &OutputLocation,
+        value: u64,
+        recipient: String,
+        nullifier: Option<[u8; 32]>,
+    ) -> Self {
+        DecryptedNote {
+            pool,
+            height: location.height,
+            txid: display_txid(&contents.txids[location.tx]),
+            tx_index: location.tx_index,
+            output_index: location.output_index,
+            value,
+            recipient,
+            position: location.position,
+            nullifier: nullifier.map(hex::encode),
+            spent: None,
+        }
+    }
+}
+
+/// The notes decrypted from a batch, each with the index of its action/output in the batch
+#[derive(Default)]
+pub(crate) struct DecryptedBatch {
+    pub(crate) orchard: Vec<(usize, DecryptedNote)>,
+    pub(crate) sapling: Vec<(usize, DecryptedNote)>,
+}
+
+impl DecryptedBatch {
+    pub(crate) fn into_notes(self) -> impl Iterator<Item = DecryptedNote> {
+        self.orchard
+            .into_iter()
+            .chain(self.sapling)
+            .map(|(_, note)| note)
+    }
+}
+
+/// Trial decrypt every action and output in the batch with the wallet's keys.
+/// Returns None if `cancel` was cancelled before the whole batch was decrypted.
+pub(crate) fn decrypt_batch(
+    keys: &WalletKeys,
+    contents: &BatchContents,
+    cancel: &CancellationToken,
+) -> Option<DecryptedBatch> {
+    let orchard = batch_decrypt_compact(&keys.orchard, &contents.actions, cancel)
+        .into_iter()
+        .map(|(i, key, note, recipient)| {
+            let recipient = UnifiedAddress::from_receivers(Some(recipient), None, None)
+                .expect("an orchard receiver is a valid unified address")
+                .encode(&keys.network);
+            let location = &contents.action_locations[i];
+            let nullifier = keys
+                .orchard_nullifier_keys
+                .get(key)
+                .map(|fvk| note.nullifier(fvk).to_bytes());
+            let note = DecryptedNote::new(
+                ShieldedPool::Orchard,
+                contents,
+                location,
+                note.value().inner(),
+                recipient,
+                nullifier,
+            );
+            (i, note)
+        })
+        .collect();
+    let sapling = batch_decrypt_compact(&keys.sapling, &contents.outputs, cancel)
+        .into_iter()
+        .map(|(i, key, note, recipient)| {
+            let recipient =
+                encode_payment_address(keys.network.hrp_sapling_payment_address(), &recipient);
+            let location = &contents.output_locations[i];
+            let nullifier = keys
+                .sapling_nullifier_keys
+                .get(key)
+                .zip(location.position)
+                .map(|(nk, position)| note.nf(nk, position).0);
+            let note = DecryptedNote::new(
+                ShieldedPool::Sapling,
+                contents,
+                location,
+                note.value().inner(),
+                recipient,
+                nullifier,
+            );
+            (i, note)
+        })
+        .collect();
+
+    if cancel.is_cancelled() {
+        return None;
+    }
+    let batch = DecryptedBatch { orchard, sapling };
+    if batch.orchard.is_empty() && batch.sapling.is_empty() {
         console_debug!("No notes for this address");
-    } else {
-        console_log!("Notes: {:?}", valid_results);
     }
-    compact.len() as u32
+    Some(batch)
 }
 
 pub(crate) fn dummy_ivk_sapling(
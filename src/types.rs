@@ -21,8 +21,12 @@ impl TryFrom<CompactOrchardAction> for orchard::note_encryption::CompactAction {
     type Error = anyhow::Error;
 
     fn try_from(action: CompactOrchardAction) -> anyhow::Result<Self> {
-        let nullifier = Nullifier::from_bytes(action.nullifier.as_ref().try_into()?).unwrap();
-        let cmx = ExtractedNoteCommitment::from_bytes(action.cmx.as_ref().try_into()?).unwrap();
+        let nullifier = Option::from(Nullifier::from_bytes(action.nullifier.as_ref().try_into()?))
+            .ok_or_else(|| anyhow::anyhow!("Invalid orchard nullifier"))?;
+        let cmx = Option::from(ExtractedNoteCommitment::from_bytes(
+            action.cmx.as_ref().try_into()?,
+        ))
+        .ok_or_else(|| anyhow::anyhow!("Invalid orchard note commitment"))?;
         let ephemeral_key_bytes: [u8; 32] = action.ephemeral_key.as_ref().try_into()?;
         let ephemeral_key = EphemeralKeyBytes::from(ephemeral_key_bytes);
         let enc_ciphertext_bytes: [u8; COMPACT_NOTE_SIZE] =
@@ -45,8 +49,14 @@ impl TryFrom<crate::proto::compact_formats::CompactOrchardAction>
     fn try_from(
         action: crate::proto::compact_formats::CompactOrchardAction,
     ) -> anyhow::Result<Self> {
-        let nullifier = Nullifier::from_bytes(action.nullifier.as_slice().try_into()?).unwrap();
-        let cmx = ExtractedNoteCommitment::from_bytes(action.cmx.as_slice().try_into()?).unwrap();
+        let nullifier = Option::from(Nullifier::from_bytes(
+            action.nullifier.as_slice().try_into()?,
+        ))
+        .ok_or_else(|| anyhow::anyhow!("Invalid orchard nullifier"))?;
+        let cmx = Option::from(ExtractedNoteCommitment::from_bytes(
+            action.cmx.as_slice().try_into()?,
+        ))
+        .ok_or_else(|| anyhow::anyhow!("Invalid orchard note commitment"))?;
         let ephemeral_key_bytes: [u8; 32] = action.ephemeral_key.as_slice().try_into()?;
         let ephemeral_key = EphemeralKeyBytes::from(ephemeral_key_bytes);
         let enc_ciphertext_bytes: [u8; COMPACT_NOTE_SIZE] =
@@ -69,8 +79,14 @@ impl TryFrom<&crate::proto::compact_formats::CompactOrchardAction>
     fn try_from(
         action: &crate::proto::compact_formats::CompactOrchardAction,
     ) -> anyhow::Result<Self> {
-        let nullifier = Nullifier::from_bytes(action.nullifier.as_slice().try_into()?).unwrap();
-        let cmx = ExtractedNoteCommitment::from_bytes(action.cmx.as_slice().try_into()?).unwrap();
+        let nullifier = Option::from(Nullifier::from_bytes(
+            action.nullifier.as_slice().try_into()?,
+        ))
+        .ok_or_else(|| anyhow::anyhow!("Invalid orchard nullifier"))?;
+        let cmx = Option::from(ExtractedNoteCommitment::from_bytes(
+            action.cmx.as_slice().try_into()?,
+        ))
+        .ok_or_else(|| anyhow::anyhow!("Invalid orchard note commitment"))?;
         let ephemeral_key_bytes: [u8; 32] = action.ephemeral_key.as_slice().try_into()?;
         let ephemeral_key = EphemeralKeyBytes::from(ephemeral_key_bytes);
         let enc_ciphertext_bytes: [u8; COMPACT_NOTE_SIZE] =
@@ -89,8 +105,12 @@ impl TryFrom<&CompactOrchardAction> for orchard::note_encryption::CompactAction
     type Error = anyhow::Error;
 
     fn try_from(action: &CompactOrchardAction) -> anyhow::Result<Self> {
-        let nullifier = Nullifier::from_bytes(action.nullifier.as_ref().try_into()?).unwrap();
-        let cmx = ExtractedNoteCommitment::from_bytes(action.cmx.as_ref().try_into()?).unwrap();
+        let nullifier = Option::from(Nullifier::from_bytes(action.nullifier.as_ref().try_into()?))
+            .ok_or_else(|| anyhow::anyhow!("Invalid orchard nullifier"))?;
+        let cmx = Option::from(ExtractedNoteCommitment::from_bytes(
+            action.cmx.as_ref().try_into()?,
+        ))
+        .ok_or_else(|| anyhow::anyhow!("Invalid orchard note commitment"))?;
         let ephemeral_key_bytes: [u8; 32] = action.ephemeral_key.as_ref().try_into()?;
         let ephemeral_key = EphemeralKeyBytes::from(ephemeral_key_bytes);
         let enc_ciphertext_bytes: [u8; COMPACT_NOTE_SIZE] =
@@ -111,9 +131,10 @@ impl TryFrom<CompactSaplingOutput> for sapling::note_encryption::CompactOutputDe
         let ephemeral_key_bytes: [u8; 32] = output.ephemeral_key.as_ref().try_into()?;
         let ephemeral_key = EphemeralKeyBytes::from(ephemeral_key_bytes);
         let enc_ciphertext: [u8; COMPACT_NOTE_SIZE] = output.enc_ciphertext.as_ref().try_into()?;
-        let cmu =
-            sapling::note::ExtractedNoteCommitment::from_bytes(output.cmu.as_ref().try_into()?)
-                .unwrap();
+        let cmu = Option::from(sapling::note::ExtractedNoteCommitment::from_bytes(
+            output.cmu.as_ref().try_into()?,
+        ))
+        .ok_or_else(|| anyhow::anyhow!("Invalid sapling note commitment"))?;
 
         Ok(sapling::note_encryption::CompactOutputDescription {
             ephemeral_key,
@@ -134,9 +155,10 @@ impl TryFrom<crate::proto::compact_formats::CompactSaplingOutput>
         let ephemeral_key_bytes: [u8; 32] = output.ephemeral_key.as_slice().try_into()?;
         let ephemeral_key = EphemeralKeyBytes::from(ephemeral_key_bytes);
         let enc_ciphertext: [u8; COMPACT_NOTE_SIZE] = output.ciphertext.as_slice().try_into()?;
-        let cmu =
-            sapling::note::ExtractedNoteCommitment::from_bytes(output.cmu.as_slice().try_into()?)
-                .unwrap();
+        let cmu = Option::from(sapling::note::ExtractedNoteCommitment::from_bytes(
+            output.cmu.as_slice().try_into()?,
+        ))
+        .ok_or_else(|| anyhow::anyhow!("Invalid sapling note commitment"))?;
 
         Ok(sapling::note_encryption::CompactOutputDescription {
             ephemeral_key,
@@ -157,9 +179,10 @@ impl TryFrom<&crate::proto::compact_formats::CompactSaplingOutput>
         let ephemeral_key_bytes: [u8; 32] = output.ephemeral_key.as_slice().try_into()?;
         let ephemeral_key = EphemeralKeyBytes::from(ephemeral_key_bytes);
         let enc_ciphertext: [u8; COMPACT_NOTE_SIZE] = output.ciphertext.as_slice().try_into()?;
-        let cmu =
-            sapling::note::ExtractedNoteCommitment::from_bytes(output.cmu.as_slice().try_into()?)
-                .unwrap();
+        let cmu = Option::from(sapling::note::ExtractedNoteCommitment::from_bytes(
+            output.cmu.as_slice().try_into()?,
+        ))
+        .ok_or_else(|| anyhow::anyhow!("Invalid sapling note commitment"))?;
 
         Ok(sapling::note_encryption::CompactOutputDescription {
             ephemeral_key,
@@ -176,9 +199,10 @@ impl TryFrom<&CompactSaplingOutput> for sapling::note_encryption::CompactOutputD
         let ephemeral_key_bytes: [u8; 32] = output.ephemeral_key.as_ref().try_into()?;
         let ephemeral_key = EphemeralKeyBytes::from(ephemeral_key_bytes);
         let enc_ciphertext: [u8; COMPACT_NOTE_SIZE] = output.enc_ciphertext.as_ref().try_into()?;
-        let cmu =
-            sapling::note::ExtractedNoteCommitment::from_bytes(output.cmu.as_ref().try_into()?)
-                .unwrap();
+        let cmu = Option::from(sapling::note::ExtractedNoteCommitment::from_bytes(
+            output.cmu.as_ref().try_into()?,
+        ))
+        .ok_or_else(|| anyhow::anyhow!("Invalid sapling note commitment"))?;
 
         Ok(sapling::note_encryption::CompactOutputDescription {
             ephemeral_key,
@@ -0,0 +1,126 @@
+/**
+ * Tracks the notes a wallet has received so that later spends of them can be detected.
+ *
+ * Received notes are indexed by nullifier. Each batch's revealed nullifiers are checked against the
+ * index after the batch's own notes are added, so a note received and spent in the same batch is seen.
+ */
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use crate::bench_params::ShieldedPool;
+use crate::block_range_stream::{BatchContents, RevealedNullifier};
+use crate::console_log;
+use crate::trial_decryption::{display_txid, DecryptedNote, NoteSpend};
+
+#[derive(Default)]
+pub struct WalletNotes {
+    notes: Vec<DecryptedNote>,
+    /// Index into `notes` of each unspent note with a known nullifier
+    orchard_unspent: HashMap<[u8; 32], usize>,
+    sapling_unspent: HashMap<[u8; 32], usize>,
+}
+
+impl WalletNotes {
+    /// Restore notes found in an earlier sync, including ones already spent
+    pub fn from_notes(notes: Vec<DecryptedNote>) -> Self {
+        let mut wallet_notes = WalletNotes::default();
+        for note in notes {
+            wallet_notes.push(note);
+        }
+        wallet_notes
+    }
+
+    /// Add newly decrypted notes. Notes without a nullifier are kept but can never be marked spent.
+    pub fn add_notes(&mut self, notes: impl IntoIterator<Item = DecryptedNote>) {
+        for note in notes {
+            self.push(note);
+        }
+    }
+
+    /// Add a note, indexing it by nullifier if it is unspent
+    fn push(&mut self, note: DecryptedNote) {
+        let nullifier = note
+            .nullifier
+            .as_ref()
+            .filter(|_| note.spent.is_none())
+            .and_then(|nf| hex::decode(nf).ok()?.try_into().ok());
+        if let Some(nullifier) = nullifier {
+            let unspent = match note.pool {
+                ShieldedPool::Orchard => &mut self.orchard_unspent,
+                _ => &mut self.sapling_unspent,
+            };
+            unspent.insert(nullifier, self.notes.len());
+        }
+        self.notes.push(note);
+    }
+
+    /// Mark any of the wallet's notes spent in the batch. Returns the number of notes newly spent.
+    pub fn detect_spends(&mut self, contents: &BatchContents) -> u32 {
+        let mut spent = 0;
+        let revealed = [
+            (&mut self.orchard_unspent, &contents.orchard_nullifiers),
+            (&mut self.sapling_unspent, &contents.sapling_nullifiers),
+        ];
+        for (unspent, nullifiers) in revealed {
+            for RevealedNullifier {
+                nullifier,
+                height,
+                tx,
+            } in nullifiers
+            {
+                if let Some(i) = unspent.remove(nullifier) {
+                    let note = &mut self.notes[i];
+                    note.spent = Some(NoteSpend {
+                        height: *height,
+                        txid: display_txid(&contents.txids[*tx]),
+                    });
+                    console_log!(
+                        "Note {}:{} was spent at height {}",
+                        note.txid,
+                        note.output_index,
+                        height
+                    );
+                    spent += 1;
+                }
+            }
+        }
+        spent
+    }
+
+    /// Forget the notes received and the spends seen after `height`, whose blocks were replaced by a reorg
+    pub fn rollback(&mut self, height: u32) {
+        let notes = std::mem::take(&mut self.notes)
+            .into_iter()
+            .filter(|note| note.height <= height)
+            .map(|mut note| {
+                if matches!(&note.spent, Some(spend) if spend.height > height) {
+                    note.spent = None;
+                }
+                note
+            })
+            .collect();
+        *self = WalletNotes::from_notes(notes);
+    }
+
+    /// Total value of the notes that haven't been seen spent
+    pub fn spendable_balance(&self) -> u64 {
+        self.notes
+            .iter()
+            .filter(|note| note.spent.is_none())
+            .map(|note| note.value)
+            .sum()
+    }
+
+    /// Number of notes received in the pool
+    pub fn count(&self, pool: ShieldedPool) -> u64 {
+        self.notes.iter().filter(|note| note.pool == pool).count() as u64
+    }
+
+    pub fn notes(&self) -> &[DecryptedNote] {
+        &self.notes
+    }
+
+    pub fn into_notes(self) -> Vec<DecryptedNote> {
+        self.notes
+    }
+}
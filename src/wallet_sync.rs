@@ -0,0 +1,341 @@
+/**
+ * A combined sync that trial decrypts each batch of blocks and then inserts its commitments into the
+ * note commitment trees, marking only the notes that decrypted. This is the workload a real wallet
+ * has and lets the download be shared between decryption and tree maintenance.
+ */
+use futures_util::StreamExt;
+use wasm_bindgen::prelude::*;
+
+use crate::bench_params::{BenchParams, ShieldedPool, SyncOptions};
+use crate::bench_report::BenchReport;
+use crate::block_range_stream::{batch_contents, check_activation, download_block_batches};
+use crate::block_source::BlockSource;
+use crate::commitment_tree::{fetch_orchard_frontier_at_height, fetch_sapling_frontier_at_height};
+use crate::error::SyncError;
+use crate::keys::WalletKeys;
+use crate::pipeline::{spawn_rayon, Pipeline, StageStats};
+use crate::progress::{ProgressPhase, ProgressTracker};
+use crate::sync_state::{
+    block_hash, default_state_store, load_or_bootstrap, StateStore, SyncState,
+};
+use crate::trial_decryption::{decrypt_batch, DecryptedNote};
+use crate::{console_log, now_ms};
+
+/// Totals from a combined wallet sync over a range of blocks
+#[derive(Debug)]
+pub struct WalletSyncSummary {
+    /// Number of Orchard actions and Sapling outputs trial decrypted
+    pub actions: u32,
+    pub outputs: u32,
+    /// Notes that decrypted and were marked in the commitment trees, including any from
+    /// before a resumed sync
+    pub notes: Vec<DecryptedNote>,
+    /// Total value of the notes which were not spent by the end of the range
+    pub spendable_balance: u64,
+    /// Size of each commitment tree at the end of the range
+    pub orchard_tree_size: u64,
+    pub sapling_tree_size: u64,
+    pub report: BenchReport,
+    /// The state at the end of the sync, to continue from later
+    pub state: SyncState,
+}
+
+impl WalletSyncSummary {
+    fn new(report: BenchReport, state: SyncState) -> Self {
+        WalletSyncSummary {
+            actions: report.orchard.outputs as u32,
+            outputs: report.sapling.outputs as u32,
+            notes: state.notes().to_vec(),
+            spendable_balance: state.spendable_balance(),
+            orchard_tree_size: state.orchard_tree_size(),
+            sapling_tree_size: state.sapling_tree_size(),
+            report,
+            state,
+        }
+    }
+}
+
+/// Download the blocks in the range once, trial decrypting every output and inserting every
+/// commitment into the trees.
+/// If a viewing key is given (see `WalletKeys::decode`) it is used instead of a dummy key.
+#[wasm_bindgen]
+pub async fn wallet_sync_bench(
+    params: BenchParams,
+    view_key: Option<String>,
+) -> Result<BenchReport, JsError> {
+    console_log!("Starting wallet sync with params: {:?}", params);
+    let params = params.resolve().await?;
+
+    let start = now_ms();
+    let keys = WalletKeys::for_bench(&params.network, view_key)?;
+    let key_setup_ms = now_ms() - start;
+    let source = params.block_source()?;
+    let options = params.sync_options();
+    let summary = match &params.state_key {
+        Some(key) => {
+            // the state is bootstrapped as of the block before the range
+            check_activation(&options.network, &params.pool, params.start_block)?;
+            let mut store = default_state_store()
+                .await
+                .map_err(|e| SyncError::InvalidState(format!("{:#}", e)))?;
+            let mut source = source;
+            let state = load_or_bootstrap(
+                &mut store,
+                key,
+                &mut source,
+                params.pool,
+                params.start_block - 1,
+            )
+            .await?;
+            let summary = resume_wallet_sync(
+                source,
+                keys,
+                state,
+                params.end_block,
+                params.block_batch_size,
+                options,
+            )
+            .await?;
+            store
+                .save(key, &summary.state.to_bytes())
+                .await
+                .map_err(|e| {
+                    SyncError::InvalidState(format!("Failed to save '{}': {:#}", key, e))
+                })?;
+            summary
+        }
+        None => {
+            wallet_sync_range(
+                source,
+                keys,
+                params.pool,
+                params.start_block,
+                params.end_block,
+                params.block_batch_size,
+                options,
+            )
+            .await?
+        }
+    };
+    let mut report = summary.report;
+    report.phases.key_setup_ms = key_setup_ms;
+    report.phases.total_ms += key_setup_ms;
+    Ok(report)
+}
+
+/// Sync the given range of blocks as a wallet holding `keys` would.
+///
+/// Each batch is trial decrypted and then appended to the commitment trees, with only the
+/// commitments of decrypted notes marked for witnessing. There is no spam filter since every
+/// commitment has to be inserted into the trees regardless.
+pub async fn wallet_sync_range<S: BlockSource + Clone + 'static>(
+    mut source: S,
+    keys: WalletKeys,
+    pool: ShieldedPool,
+    start_height: u32,
+    end_height: u32,
+    batch_size: u32,
+    options: SyncOptions,
+) -> Result<WalletSyncSummary, SyncError> {
+    check_activation(&options.network, &pool, start_height)?;
+    let start = now_ms();
+    let state = SyncState::bootstrap(&mut source, pool, start_height - 1).await?;
+    let mut summary =
+        resume_wallet_sync(source, keys, state, end_height, batch_size, options).await?;
+    // include the bootstrap in the total
+    summary.report.finish(start);
+    Ok(summary)
+}
+
+/// Continue a wallet sync from a saved state up to `end_height`, adding to its trees and notes.
+/// The returned summary holds the updated state, which can be saved to continue from later.
+///
+/// If the chain reorganises, during the sync or since the state was saved, the trees and notes are
+/// rolled back to the last checkpointed block still on the chain and the sync continues on the new
+/// chain from there. Fails if none of the blocks the trees are checkpointed at are still on the
+/// chain, for example because the state was saved on a different chain.
+pub async fn resume_wallet_sync<S: BlockSource + Clone + 'static>(
+    mut source: S,
+    keys: WalletKeys,
+    state: SyncState,
+    end_height: u32,
+    batch_size: u32,
+    options: SyncOptions,
+) -> Result<WalletSyncSummary, SyncError> {
+    let start = now_ms();
+    let SyncState {
+        pool,
+        last_height,
+        mut last_block_hash,
+        mut trees,
+        mut notes,
+    } = state;
+    let start_height = last_height + 1;
+    check_activation(&options.network, &pool, start_height)?;
+    options.check()?;
+    if start_height > end_height {
+        console_log!("Already synced to {}", last_height);
+        let mut report = BenchReport::default();
+        report.finish(start);
+        return Ok(WalletSyncSummary::new(
+            report,
+            SyncState {
+                pool,
+                last_height,
+                last_block_hash,
+                trees,
+                notes,
+            },
+        ));
+    }
+    let mut report = BenchReport::default();
+    let mut next_height = start_height;
+    loop {
+        let mut pipeline = Pipeline::new(options.queue_depth as usize);
+        let blocks = pipeline.source(
+            "download",
+            download_block_batches(
+                source.clone(),
+                next_height,
+                end_height,
+                batch_size,
+                options.n_download_streams,
+                options.retry_policy,
+                &options.cancel,
+                options.checkpoint_interval,
+                trees.checkpoint_hashes.clone(),
+            ),
+        );
+        let network = options.network;
+        let batch_pool = pool.clone();
+        let contents = pipeline.stage("convert", blocks, move |blocks| {
+            let pool = batch_pool.clone();
+            spawn_rayon(move || batch_contents(blocks, &network, &pool, u32::MAX))
+        });
+        let cancel = options.cancel.clone();
+        let batch_keys = keys.clone();
+        let decrypted = pipeline.stage("decrypt", contents, move |contents| {
+            let keys = batch_keys.clone();
+            let cancel = cancel.clone();
+            spawn_rayon(move || {
+                let decrypted = decrypt_batch(&keys, &contents, &cancel);
+                Ok((contents, decrypted))
+            })
+        });
+        let mut progress = ProgressTracker::blocks(
+            ProgressPhase::WalletSync,
+            next_height,
+            end_height,
+            options.progress.clone(),
+        );
+        let cancel = options.cancel.clone();
+        let checkpoint_interval = options.checkpoint_interval;
+        let insert_pool = pool.clone();
+        let insert = async move {
+            let mut decrypted = decrypted;
+            let mut stats = StageStats::new("insert");
+            let mut trees = trees;
+            let mut notes = notes;
+            let mut last_block_hash = last_block_hash;
+            let mut report = report;
+            let mut reorg = None;
+            while let Some(batch) = decrypted.next().await {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                let (contents, decrypted) = match batch {
+                    Ok((contents, Some(decrypted))) => (contents, decrypted),
+                    // decryption of the batch was cancelled part way through
+                    Ok((_, None)) => break,
+                    Err(SyncError::Reorg { fork_height }) => {
+                        reorg = Some(fork_height as u32);
+                        break;
+                    }
+                    Err(e) => return (Err(e), stats),
+                };
+                last_block_hash = contents.end_hash.clone();
+                report.add_batch(&contents);
+                report.orchard.notes += decrypted.orchard.len() as u64;
+                report.sapling.notes += decrypted.sapling.len() as u64;
+                let (orchard_marked, orchard_notes): (Vec<_>, Vec<_>) =
+                    decrypted.orchard.into_iter().unzip();
+                let (sapling_marked, sapling_notes): (Vec<_>, Vec<_>) =
+                    decrypted.sapling.into_iter().unzip();
+                notes.add_notes(orchard_notes.into_iter().chain(sapling_notes));
+                notes.detect_spends(&contents);
+
+                let n_blocks = contents.blocks;
+                let pool = insert_pool.clone();
+                let (inserted, checked) = stats
+                    .time(spawn_rayon(move || {
+                        trees.insert_batch(
+                            contents.actions,
+                            contents.outputs,
+                            &orchard_marked,
+                            &sapling_marked,
+                            &contents.block_ends,
+                            checkpoint_interval,
+                        );
+                        let checked = trees.check_tree_sizes(&pool, &contents.block_ends);
+                        (trees, checked)
+                    }))
+                    .await;
+                trees = inserted;
+                if let Err(e) = checked {
+                    return (Err(e), stats);
+                }
+                progress.advance(n_blocks);
+            }
+            (Ok((trees, notes, last_block_hash, report, reorg)), stats)
+        };
+
+        let (result, pipeline) = pipeline.run(insert).await;
+        pipeline.log();
+        let reorg;
+        (trees, notes, last_block_hash, report, reorg) = result?;
+        report.add_pipeline(pipeline);
+
+        // roll the trees and notes back to the fork point and continue on the new chain from there
+        match reorg {
+            Some(fork_height) => {
+                let rewound = trees.rewind_to(fork_height)?;
+                notes.rollback(rewound);
+                last_block_hash = block_hash(&source.tree_state(rewound).await?)?;
+                report.last_height = Some(rewound);
+                report.orchard.notes = notes.count(ShieldedPool::Orchard);
+                report.sapling.notes = notes.count(ShieldedPool::Sapling);
+                report.reorgs += 1;
+                next_height = rewound + 1;
+                console_log!("Rolled the wallet back to block {} after a reorg", rewound);
+            }
+            None => break,
+        }
+    }
+    report.check_cancelled(&options.cancel, end_height);
+
+    // the trees only match lightwalletd's at the end of the range
+    if !report.cancelled {
+        let end_frontier = fetch_orchard_frontier_at_height(&mut source, end_height).await?;
+        let end_sapling_frontier =
+            fetch_sapling_frontier_at_height(&mut source, end_height).await?;
+        let root_check_start = now_ms();
+        trees.check_roots(&pool, &end_frontier, &end_sapling_frontier, end_height)?;
+        report.phases.root_check_ms = now_ms() - root_check_start;
+    }
+    report.finish(start);
+
+    let state = SyncState {
+        pool,
+        last_height: report.last_height.unwrap_or(last_height),
+        last_block_hash,
+        trees,
+        notes,
+    };
+    console_log!(
+        "Wallet sync complete. Found {} Orchard and {} Sapling notes with a spendable balance of {} zatoshis",
+        state.trees.orchard_marked,
+        state.trees.sapling_marked,
+        state.spendable_balance()
+    );
+    Ok(WalletSyncSummary::new(report, state))
+}
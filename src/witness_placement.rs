@@ -0,0 +1,103 @@
+/**
+ * Where in the commitment trees the tree-sync benchmark marks the commitments it maintains witnesses for.
+ *
+ * Marking the first commitments of the range keeps the witnesses next to each other, so they share most
+ * of their paths. A real wallet's notes are spread across the range, which leaves more of the tree to
+ * retain and more distinct paths to update, so the other placements scatter them.
+ */
+use incrementalmerkletree::Position;
+use rand::rngs::StdRng;
+use rand::seq::index;
+use rand::SeedableRng;
+
+/// How the commitments to witness are chosen among those added to each tree by the synced range
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum WitnessPlacement {
+    /// The first commitments of the range
+    #[default]
+    Contiguous,
+    /// Commitments chosen uniformly at random from the whole range
+    Uniform { seed: u64 },
+    /// Runs of adjacent commitments starting at random points in the range, as for a wallet that
+    /// receives its notes in bursts. The commitments are split as evenly as possible between the runs.
+    Clustered { clusters: u32, seed: u64 },
+    /// The given positions in each tree, regardless of the number of witnesses asked for. Positions
+    /// outside the range are skipped.
+    Positions(Vec<u64>),
+}
+
+impl WitnessPlacement {
+    /// The ascending tree positions of `n` commitments in the range of positions `[start, end)`.
+    /// If the range holds fewer than `n` commitments all of them are chosen.
+    /// Listed positions are all kept if they are in the range, whatever `n` is.
+    pub fn positions(&self, n: u32, start: u64, end: u64) -> Vec<u64> {
+        let len = end.saturating_sub(start) as usize;
+        let n = (n as usize).min(len);
+        match self {
+            WitnessPlacement::Contiguous => (start..start + n as u64).collect(),
+            WitnessPlacement::Uniform { seed } => {
+                let mut rng = StdRng::seed_from_u64(*seed);
+                let mut positions = index::sample(&mut rng, len, n)
+                    .into_iter()
+                    .map(|i| start + i as u64)
+                    .collect::<Vec<_>>();
+                positions.sort_unstable();
+                positions
+            }
+            WitnessPlacement::Clustered { clusters, seed } => {
+                let clusters = (*clusters as usize).clamp(1, n.max(1));
+                let mut rng = StdRng::seed_from_u64(*seed);
+                // choosing distinct offsets among the positions left once each run is shrunk to
+                // one leaf and then growing the runs back keeps them from overlapping
+                let mut offsets = index::sample(&mut rng, len - n + clusters, clusters).into_vec();
+                offsets.sort_unstable();
+                let mut positions = Vec::with_capacity(n);
+                for (i, offset) in offsets.into_iter().enumerate() {
+                    let run_len = n / clusters + usize::from(i < n % clusters);
+                    let run_start = start + (offset + positions.len() - i) as u64;
+                    positions.extend(run_start..run_start + run_len as u64);
+                }
+                positions
+            }
+            WitnessPlacement::Positions(positions) => {
+                let mut positions = positions
+                    .iter()
+                    .copied()
+                    .filter(|p| (start..end).contains(p))
+                    .collect::<Vec<_>>();
+                positions.sort_unstable();
+                positions.dedup();
+                positions
+            }
+        }
+    }
+}
+
+/// The positions to mark in each tree as the range is synced
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WitnessPositions {
+    pub(crate) orchard: Vec<u64>,
+    pub(crate) sapling: Vec<u64>,
+}
+
+impl WitnessPositions {
+    /// The indices within a batch of `len` Orchard commitments appended at `cursor` to mark
+    pub(crate) fn orchard_batch(&self, cursor: Position, len: usize) -> Vec<usize> {
+        batch_indices(&self.orchard, cursor, len)
+    }
+
+    /// The indices within a batch of `len` Sapling commitments appended at `cursor` to mark
+    pub(crate) fn sapling_batch(&self, cursor: Position, len: usize) -> Vec<usize> {
+        batch_indices(&self.sapling, cursor, len)
+    }
+}
+
+fn batch_indices(positions: &[u64], cursor: Position, len: usize) -> Vec<usize> {
+    let start = u64::from(cursor);
+    let first = positions.partition_point(|p| *p < start);
+    positions[first..]
+        .iter()
+        .take_while(|p| **p < start + len as u64)
+        .map(|p| (p - start) as usize)
+        .collect()
+}
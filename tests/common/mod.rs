@@ -0,0 +1,1413 @@
+// Checks of the sync logic over synthetic chains, run both natively by tests/native.rs and in a browser
+// by tests/web.rs. Set up any thread pool before calling them.
+
+use wasm_bindgen::JsValue;
+use zcash_wasm_benchmark::proto::service::{ShieldedProtocol, SubtreeRoot, TreeState};
+use zcash_wasm_benchmark::*;
+
+pub const TIP: u32 = 2442739;
+pub const SPAM_FILTER: u32 = 50;
+
+pub async fn synthetic_chain_tree_sync() {
+    let (start, n_blocks) = (TIP - 1000, 1000);
+    let chain = generate_synthetic_chain(&SyntheticChainConfig::new(start, n_blocks));
+    let chain_metadata = chain.blocks.last().unwrap().chain_metadata.clone().unwrap();
+
+    // the sync checks the computed orchard root and the witnesses against the generated tree
+    // state at the end
+    let summary = zcash_wasm_benchmark::sync_commitment_tree(
+        chain.into_block_source(),
+        ShieldedPool::Both,
+        start,
+        start + n_blocks - 1,
+        100,
+        3,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        (summary.orchard_tree_size, summary.sapling_tree_size),
+        (
+            chain_metadata.orchard_commitment_tree_size as u64,
+            chain_metadata.sapling_commitment_tree_size as u64
+        )
+    );
+    assert_eq!(summary.report.blocks, n_blocks as u64);
+    assert!(summary.report.bytes_received > 0);
+    assert_eq!(summary.report.orchard.witnesses, 3);
+    assert_eq!(summary.report.sapling.witnesses, 3);
+}
+
+pub async fn witnesses_can_be_scattered_across_the_range() {
+    let (start, n_blocks) = (TIP - 300, 300);
+    let chain = generate_synthetic_chain(&SyntheticChainConfig::new(start, n_blocks));
+    let chain_metadata = chain.blocks.last().unwrap().chain_metadata.clone().unwrap();
+    let orchard_size = chain_metadata.orchard_commitment_tree_size as u64;
+
+    let uniform = WitnessPlacement::Uniform { seed: 7 };
+    let positions = uniform.positions(20, 100, orchard_size);
+    assert_eq!(positions.len(), 20);
+    assert!(positions.windows(2).all(|w| w[0] < w[1]));
+    assert!(positions.iter().all(|p| (100..orchard_size).contains(p)));
+    assert_eq!(positions, uniform.positions(20, 100, orchard_size));
+
+    // runs of adjacent positions that don't overlap
+    let clustered = WitnessPlacement::Clustered {
+        clusters: 3,
+        seed: 7,
+    };
+    let positions = clustered.positions(20, 100, orchard_size);
+    assert_eq!(positions.len(), 20);
+    assert!(positions.windows(2).all(|w| w[0] < w[1]));
+    assert!(positions.windows(2).filter(|w| w[1] > w[0] + 1).count() <= 2);
+    assert!(positions.iter().all(|p| (100..orchard_size).contains(p)));
+
+    // every commitment is chosen from a range holding fewer than asked for, and none from an empty one
+    for placement in [
+        WitnessPlacement::Contiguous,
+        uniform.clone(),
+        clustered.clone(),
+        WitnessPlacement::Clustered {
+            clusters: 0,
+            seed: 7,
+        },
+    ] {
+        assert_eq!(placement.positions(20, 5, 9), vec![5, 6, 7, 8]);
+        assert!(placement.positions(20, 9, 9).is_empty());
+    }
+    assert_eq!(
+        WitnessPlacement::Positions(vec![8, 3, 12, 3, 5]).positions(1, 3, 12),
+        vec![3, 5, 8]
+    );
+
+    // the sync checks the witnesses of every marked commitment against the generated tree state
+    let sync = |n_witnesses: u32, witness_placement: WitnessPlacement| {
+        zcash_wasm_benchmark::sync_commitment_tree(
+            generate_synthetic_chain(&SyntheticChainConfig::new(start, n_blocks))
+                .into_block_source(),
+            ShieldedPool::Both,
+            start,
+            start + n_blocks - 1,
+            50,
+            n_witnesses,
+            SyncOptions {
+                witness_placement,
+                ..SyncOptions::default()
+            },
+        )
+    };
+    for placement in [uniform, clustered] {
+        let report = sync(20, placement).await.unwrap().report;
+        assert_eq!(report.orchard.witnesses, 20);
+        assert_eq!(report.sapling.witnesses, 20);
+    }
+
+    // listed positions are marked in both trees if the range adds them, whatever the count asked for
+    let report = sync(
+        0,
+        WitnessPlacement::Positions(vec![orchard_size - 1, 5, orchard_size + 10]),
+    )
+    .await
+    .unwrap()
+    .report;
+    assert_eq!(report.orchard.witnesses, 2);
+}
+
+pub async fn tree_sizes_are_checked_against_chain_metadata() {
+    let (start, n_blocks) = (TIP - 200, 200);
+    let mut chain = generate_synthetic_chain(&SyntheticChainConfig::new(start, n_blocks));
+    // a block whose metadata disagrees with the commitments in and before it
+    let bad = &mut chain.blocks[120];
+    let bad_height = bad.height;
+    bad.chain_metadata
+        .as_mut()
+        .unwrap()
+        .sapling_commitment_tree_size += 1;
+
+    let result = zcash_wasm_benchmark::sync_commitment_tree(
+        chain.into_block_source(),
+        ShieldedPool::Both,
+        start,
+        start + n_blocks - 1,
+        50,
+        0,
+        SyncOptions::default(),
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(SyncError::TreeSizeMismatch {
+            pool: ShieldedPool::Sapling,
+            height,
+            ..
+        }) if height == bad_height
+    ));
+}
+
+pub async fn reorgs_rewind_the_trees_to_a_checkpoint() {
+    let (start, n_blocks, batch_size) = (TIP - 300, 300, 50);
+    let end = start + n_blocks - 1;
+    let config = SyntheticChainConfig::new(start, n_blocks);
+    let chain_metadata = generate_synthetic_chain(&config)
+        .blocks
+        .last()
+        .unwrap()
+        .chain_metadata
+        .clone()
+        .unwrap();
+    let reorg = |depth: u32, checkpoint_interval: u32| {
+        zcash_wasm_benchmark::sync_with_reorg(
+            generate_synthetic_chain(&config).into_block_source(),
+            ShieldedPool::Both,
+            start,
+            end,
+            batch_size,
+            depth,
+            SyncOptions {
+                checkpoint_interval,
+                ..SyncOptions::default()
+            },
+        )
+    };
+
+    // with a checkpoint at every block the trees rewind to exactly the fork point, and the roots
+    // are checked against the generated tree state again after reapplying the blocks
+    let summary = reorg(10, 1).await.unwrap();
+    assert_eq!(summary.fork_height, end - 10);
+    assert_eq!(
+        (summary.orchard_tree_size, summary.sapling_tree_size),
+        (
+            chain_metadata.orchard_commitment_tree_size as u64,
+            chain_metadata.sapling_commitment_tree_size as u64
+        )
+    );
+
+    // otherwise to the latest block at a multiple of the interval or at the end of a batch
+    let summary = reorg(10, 7).await.unwrap();
+    let expected_fork = (start..=end - 10)
+        .filter(|h| h % 7 == 0 || (h - start + 1) % batch_size == 0)
+        .max()
+        .unwrap();
+    assert_eq!(summary.fork_height, expected_fork);
+    assert_eq!(
+        summary.sapling_tree_size,
+        chain_metadata.sapling_commitment_tree_size as u64
+    );
+
+    // only the latest 100 checkpoints are retained
+    assert!(matches!(
+        reorg(200, 1).await,
+        Err(SyncError::NoCheckpoint { .. })
+    ));
+
+    assert!(matches!(
+        reorg(10, 0).await,
+        Err(SyncError::InvalidOptions(_))
+    ));
+}
+
+pub async fn chain_reorgs_roll_back_to_the_fork_point() {
+    let (start, n_blocks) = (TIP - 300, 300);
+    let end = start + n_blocks - 1;
+    let config = SyntheticChainConfig {
+        planted_note_probability: 0.05,
+        spend_probability: 0.05,
+        ..SyntheticChainConfig::new(start, n_blocks)
+    };
+    // the chain is replaced after block start + 200, part way through the fifth batch
+    let fork_config = SyntheticChainConfig {
+        fork: Some(SyntheticFork {
+            height: start + 200,
+            seed: 1,
+        }),
+        ..config.clone()
+    };
+    let fork_chain = || generate_synthetic_chain(&fork_config).into_block_source();
+    let source = |download_streams: u32| {
+        let source = generate_synthetic_chain(&config)
+            .into_block_source()
+            .with_fork(fork_chain(), 230);
+        let options = SyncOptions {
+            n_download_streams: download_streams,
+            ..SyncOptions::default()
+        };
+        (source, options)
+    };
+    let keys = WalletKeys::from_fvks(
+        &Network::Mainnet,
+        &config.orchard_recipients,
+        &config.sapling_recipients,
+    );
+
+    let expected = wallet_sync_range(
+        fork_chain(),
+        keys.clone(),
+        ShieldedPool::Both,
+        start,
+        end,
+        50,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(expected.report.reorgs, 0);
+
+    let (reorged, options) = source(1);
+    let synced = wallet_sync_range(
+        reorged,
+        keys.clone(),
+        ShieldedPool::Both,
+        start,
+        end,
+        50,
+        options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(synced.report.reorgs, 1);
+    assert_eq!(
+        format!("{:?}", synced.notes),
+        format!("{:?}", expected.notes)
+    );
+    assert_eq!(synced.spendable_balance, expected.spendable_balance);
+    assert_eq!(
+        (synced.orchard_tree_size, synced.sapling_tree_size),
+        (expected.orchard_tree_size, expected.sapling_tree_size)
+    );
+
+    let decrypt = |source, options| {
+        trial_decrypt_range(
+            source,
+            keys.clone(),
+            ShieldedPool::Both,
+            start,
+            end,
+            50,
+            SPAM_FILTER,
+            options,
+        )
+    };
+    let expected_decrypted = decrypt(fork_chain(), SyncOptions::default()).await.unwrap();
+    let (reorged, options) = source(1);
+    let decrypted = decrypt(reorged, options).await.unwrap();
+    assert_eq!(decrypted.report.reorgs, 1);
+    assert_eq!(
+        format!("{:?}", decrypted.notes),
+        format!("{:?}", expected_decrypted.notes)
+    );
+    assert_eq!(
+        decrypted.spendable_balance,
+        expected_decrypted.spendable_balance
+    );
+
+    // blocks downloaded ahead on other streams before the switch are discarded too
+    let (reorged, options) = source(4);
+    let trees = sync_commitment_tree(reorged, ShieldedPool::Both, start, end, 50, 0, options)
+        .await
+        .unwrap();
+    assert_eq!(trees.report.reorgs, 1);
+    assert_eq!(
+        (trees.orchard_tree_size, trees.sapling_tree_size),
+        (expected.orchard_tree_size, expected.sapling_tree_size)
+    );
+
+    // with a checkpoint every 10 blocks, a fork more than 100 blocks back can still be rolled back
+    let deep_fork_config = SyntheticChainConfig {
+        fork: Some(SyntheticFork {
+            height: start + 100,
+            seed: 1,
+        }),
+        ..config.clone()
+    };
+    let deep_fork_chain = || generate_synthetic_chain(&deep_fork_config).into_block_source();
+    let sync = |source, options| {
+        sync_commitment_tree(source, ShieldedPool::Both, start, end, 50, 0, options)
+    };
+    let expected = sync(deep_fork_chain(), SyncOptions::default())
+        .await
+        .unwrap();
+    let reorged = generate_synthetic_chain(&config)
+        .into_block_source()
+        .with_fork(deep_fork_chain(), 230);
+    let options = SyncOptions {
+        checkpoint_interval: 10,
+        ..SyncOptions::default()
+    };
+    let trees = sync(reorged, options).await.unwrap();
+    assert_eq!(trees.report.reorgs, 1);
+    assert_eq!(
+        (trees.orchard_tree_size, trees.sapling_tree_size),
+        (expected.orchard_tree_size, expected.sapling_tree_size)
+    );
+}
+
+pub async fn synthetic_chain_wallet_sync() {
+    let (start, n_blocks) = (TIP - 1000, 1000);
+    let config = SyntheticChainConfig::new(start, n_blocks);
+    let chain = generate_synthetic_chain(&config);
+    let chain_metadata = chain.blocks.last().unwrap().chain_metadata.clone().unwrap();
+    let mut planted = chain
+        .planted_notes
+        .iter()
+        .map(|n| (n.pool.clone(), n.height, Some(n.position), n.value))
+        .collect::<Vec<_>>();
+    planted.sort_by_key(|n| (n.0 == ShieldedPool::Sapling, n.2));
+
+    let summary = wallet_sync_range(
+        chain.into_block_source(),
+        WalletKeys::from_fvks(
+            &Network::Mainnet,
+            &config.orchard_recipients,
+            &config.sapling_recipients,
+        ),
+        ShieldedPool::Both,
+        start,
+        start + n_blocks - 1,
+        100,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    // exactly the planted notes are found, at the positions they were planted
+    let mut found = summary
+        .notes
+        .iter()
+        .map(|n| (n.pool.clone(), n.height, n.position, n.value))
+        .collect::<Vec<_>>();
+    found.sort_by_key(|n| (n.0 == ShieldedPool::Sapling, n.2));
+    assert_eq!(found, planted);
+    assert!(summary.notes.iter().all(|n| n.txid.len() == 64));
+    assert_eq!(
+        summary.orchard_tree_size,
+        chain_metadata.orchard_commitment_tree_size as u64
+    );
+    assert_eq!(
+        summary.sapling_tree_size,
+        chain_metadata.sapling_commitment_tree_size as u64
+    );
+}
+
+pub async fn trial_decryption_with_view_keys() {
+    let (start, n_blocks) = (TIP - 1000, 1000);
+    let usk = zcash_keys::keys::UnifiedSpendingKey::from_seed(
+        &zcash_primitives::consensus::MAIN_NETWORK,
+        &[7; 32],
+        zcash_primitives::zip32::AccountId::ZERO,
+    )
+    .unwrap();
+    let ufvk = usk.to_unified_full_viewing_key();
+    let config = SyntheticChainConfig {
+        orchard_recipients: vec![ufvk.orchard().unwrap().clone()],
+        sapling_recipients: vec![ufvk.sapling().unwrap().clone()],
+        ..SyntheticChainConfig::new(start, n_blocks)
+    };
+    let chain = generate_synthetic_chain(&config);
+    let planted = chain.planted_notes.len() as u32;
+    let planted_orchard = chain
+        .planted_notes
+        .iter()
+        .filter(|n| n.pool == ShieldedPool::Orchard)
+        .count() as u32;
+    let source = chain.into_block_source();
+    let decrypt = |keys: WalletKeys| {
+        trial_decrypt_range(
+            source.clone(),
+            keys,
+            ShieldedPool::Both,
+            start,
+            start + n_blocks - 1,
+            100,
+            SPAM_FILTER,
+            SyncOptions::default(),
+        )
+    };
+
+    let ufvk = ufvk.encode(&zcash_primitives::consensus::MAIN_NETWORK);
+    let keys = WalletKeys::decode(&Network::Mainnet, &ufvk).unwrap();
+    assert_eq!((keys.orchard.len(), keys.sapling.len()), (2, 2)); // external and internal scopes
+    assert_eq!(decrypt(keys).await.unwrap().notes.len() as u32, planted);
+
+    // a mainnet key is rejected on testnet
+    assert!(WalletKeys::decode(&Network::Testnet, &ufvk).is_err());
+
+    // an orchard only key only finds the orchard notes
+    let orchard_fvk = hex::encode(config.orchard_recipients[0].to_bytes());
+    let keys = WalletKeys::decode(&Network::Mainnet, &orchard_fvk).unwrap();
+    assert_eq!(
+        decrypt(keys).await.unwrap().notes.len() as u32,
+        planted_orchard
+    );
+}
+
+pub async fn spent_notes_are_detected() {
+    let (start, n_blocks) = (TIP - 500, 500);
+    let config = SyntheticChainConfig {
+        planted_note_probability: 0.05,
+        spend_probability: 0.05,
+        ..SyntheticChainConfig::new(start, n_blocks)
+    };
+    let chain = generate_synthetic_chain(&config);
+    let mut planted = chain
+        .planted_notes
+        .iter()
+        .map(|n| (n.pool.clone(), n.position, n.spent_height))
+        .collect::<Vec<_>>();
+    planted.sort_by_key(|n| (n.0 == ShieldedPool::Sapling, n.1));
+    let unspent_value: u64 = chain
+        .planted_notes
+        .iter()
+        .filter(|n| n.spent_height.is_none())
+        .map(|n| n.value)
+        .sum();
+    assert!(chain.planted_notes.iter().any(|n| n.spent_height.is_some()));
+    assert!(chain.planted_notes.iter().any(|n| n.spent_height.is_none()));
+
+    let source = chain.into_block_source();
+    let keys = WalletKeys::from_fvks(
+        &Network::Mainnet,
+        &config.orchard_recipients,
+        &config.sapling_recipients,
+    );
+    let spends = |notes: &[DecryptedNote]| {
+        let mut spends = notes
+            .iter()
+            .map(|n| {
+                (
+                    n.pool.clone(),
+                    n.position.unwrap(),
+                    n.spent.as_ref().map(|s| s.height),
+                )
+            })
+            .collect::<Vec<_>>();
+        spends.sort_by_key(|n| (n.0 == ShieldedPool::Sapling, n.1));
+        spends
+    };
+
+    let summary = trial_decrypt_range(
+        source.clone(),
+        keys.clone(),
+        ShieldedPool::Both,
+        start,
+        start + n_blocks - 1,
+        100,
+        SPAM_FILTER,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(spends(&summary.notes), planted);
+    assert_eq!(summary.spendable_balance, unspent_value);
+
+    let summary = wallet_sync_range(
+        source,
+        keys,
+        ShieldedPool::Both,
+        start,
+        start + n_blocks - 1,
+        100,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(spends(&summary.notes), planted);
+    assert_eq!(summary.spendable_balance, unspent_value);
+}
+
+pub async fn ranges_spanning_canopy_decrypt() {
+    // Sapling outputs before Canopy use the pre ZIP-212 note plaintext
+    const CANOPY: u32 = 1_046_400;
+    let (start, n_blocks) = (CANOPY - 100, 200);
+    let config = SyntheticChainConfig {
+        pool: ShieldedPool::Sapling,
+        planted_note_probability: 0.1,
+        ..SyntheticChainConfig::new(start, n_blocks)
+    };
+    let chain = generate_synthetic_chain(&config);
+    let planted = chain.planted_notes.len();
+    assert!(chain.planted_notes.iter().any(|n| n.height < CANOPY));
+    let source = chain.into_block_source();
+    let keys = WalletKeys::from_fvks(&Network::Mainnet, &[], &config.sapling_recipients);
+    let decrypt = |pool: ShieldedPool, start: u32| {
+        trial_decrypt_range(
+            source.clone(),
+            keys.clone(),
+            pool,
+            start,
+            start + n_blocks - 1,
+            100,
+            SPAM_FILTER,
+            SyncOptions::default(),
+        )
+    };
+
+    let summary = decrypt(ShieldedPool::Sapling, start).await.unwrap();
+    assert_eq!(summary.notes.len(), planted);
+
+    // ranges starting before the pool activated are rejected
+    assert!(matches!(
+        decrypt(ShieldedPool::Orchard, start).await,
+        Err(SyncError::BeforeActivation {
+            activation: 1_687_104,
+            ..
+        })
+    ));
+    assert!(matches!(
+        decrypt(ShieldedPool::Sapling, 400_000).await,
+        Err(SyncError::BeforeActivation {
+            activation: 419_200,
+            ..
+        })
+    ));
+}
+
+pub async fn relative_ranges_resolve_against_the_tip() {
+    // a fixture ending at TIP
+    let (start, n_blocks) = (TIP - 199, 200);
+    let fixture = generate_synthetic_fixture(start, n_blocks, 4, 0.0, 0.5, 0);
+    let params = |network: &str| {
+        BenchParams::new(
+            network.to_string(),
+            "both".to_string(),
+            // blocks and tree states are replayed from the fixture
+            String::new(),
+            0,
+            0,
+            100,
+        )
+        .with_fixture(&fixture)
+        .map_err(JsValue::from)
+        .unwrap()
+    };
+
+    let resolved = params("mainnet")
+        .with_last_blocks(50)
+        .resolve()
+        .await
+        .unwrap();
+    assert_eq!((resolved.start_block, resolved.end_block), (TIP - 49, TIP));
+    assert!(resolved.relative_range.is_none());
+
+    let resolved = params("mainnet")
+        .with_birthday(start + 10)
+        .resolve()
+        .await
+        .unwrap();
+    assert_eq!(
+        (resolved.start_block, resolved.end_block),
+        (start + 10, TIP)
+    );
+
+    // ranges holding no blocks are rejected
+    assert!(matches!(
+        params("mainnet").with_last_blocks(0).resolve().await,
+        Err(SyncError::InvalidOptions(_))
+    ));
+    assert!(matches!(
+        params("mainnet").with_birthday(TIP + 1).resolve().await,
+        Err(SyncError::InvalidOptions(_))
+    ));
+    assert_eq!(
+        RelativeRange::FromHeight(TIP).resolve(TIP).unwrap(),
+        (TIP, TIP)
+    );
+
+    // the fixture's tree states are from mainnet
+    assert!(matches!(
+        params("testnet").with_last_blocks(50).resolve().await,
+        Err(SyncError::NetworkMismatch { .. })
+    ));
+
+    let empty_batches = BenchParams {
+        block_batch_size: 0,
+        ..params("mainnet")
+    };
+    assert!(matches!(
+        empty_batches.resolve().await,
+        Err(SyncError::InvalidOptions(_))
+    ));
+}
+
+/// A block source whose streams fail with a transport error after a fixed number of blocks
+#[derive(Clone)]
+struct FlakyBlockSource {
+    inner: MemoryBlockSource,
+    blocks_per_stream: usize,
+}
+
+impl BlockSource for FlakyBlockSource {
+    async fn block_range(&mut self, start: u32, end: u32) -> anyhow::Result<BlockStream> {
+        use futures_util::StreamExt;
+        let blocks = self.inner.block_range(start, end).await?;
+        let dropped =
+            futures_util::stream::once(async { Err(anyhow::anyhow!("connection reset")) });
+        Ok(blocks
+            .take(self.blocks_per_stream)
+            .chain(dropped)
+            .boxed_local())
+    }
+
+    async fn tree_state(&mut self, height: u32) -> anyhow::Result<TreeState> {
+        self.inner.tree_state(height).await
+    }
+
+    async fn latest_height(&mut self) -> anyhow::Result<u32> {
+        self.inner.latest_height().await
+    }
+
+    async fn subtree_roots(
+        &mut self,
+        protocol: ShieldedProtocol,
+        start_index: u32,
+    ) -> anyhow::Result<Vec<SubtreeRoot>> {
+        self.inner.subtree_roots(protocol, start_index).await
+    }
+
+    async fn chain_name(&mut self) -> anyhow::Result<String> {
+        self.inner.chain_name().await
+    }
+}
+
+pub async fn dropped_streams_resume_without_reprocessing() {
+    let (start, n_blocks) = (TIP - 100, 100);
+    let source =
+        generate_synthetic_chain(&SyntheticChainConfig::new(start, n_blocks)).into_block_source();
+    let end = start + n_blocks - 1;
+    let fast_retries = SyncOptions {
+        retry_policy: RetryPolicy::new(3, 1, 10, 2.0),
+        ..SyncOptions::default()
+    };
+
+    let expected = trial_decrypt_range(
+        source.clone(),
+        WalletKeys::dummy(&Network::Mainnet),
+        ShieldedPool::Both,
+        start,
+        end,
+        10,
+        SPAM_FILTER,
+        fast_retries.clone(),
+    )
+    .await
+    .unwrap();
+    // every batch passes through each stage of the pipeline once
+    let stage_items = expected
+        .report
+        .pipeline
+        .as_ref()
+        .unwrap()
+        .stages
+        .iter()
+        .map(|s| (s.name, s.items))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        stage_items,
+        vec![("download", 10), ("convert", 10), ("decrypt", 10)]
+    );
+
+    // Every stream drops after 30 blocks so the range needs 4 connections
+    let flaky = FlakyBlockSource {
+        inner: source.clone(),
+        blocks_per_stream: 30,
+    };
+    let actual = trial_decrypt_range(
+        flaky,
+        WalletKeys::dummy(&Network::Mainnet),
+        ShieldedPool::Both,
+        start,
+        end,
+        10,
+        SPAM_FILTER,
+        fast_retries.clone(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        (expected.actions, expected.outputs),
+        (actual.actions, actual.outputs)
+    );
+
+    // Concurrent sub-ranges of 10 blocks each need several connections and are reassembled in order
+    let flaky = FlakyBlockSource {
+        inner: source.clone(),
+        blocks_per_stream: 3,
+    };
+    let concurrent = SyncOptions {
+        n_download_streams: 4,
+        ..fast_retries.clone()
+    };
+    let actual = trial_decrypt_range(
+        flaky,
+        WalletKeys::dummy(&Network::Mainnet),
+        ShieldedPool::Both,
+        start,
+        end,
+        10,
+        SPAM_FILTER,
+        concurrent,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        (expected.actions, expected.outputs),
+        (actual.actions, actual.outputs)
+    );
+
+    // batches of no blocks and downloads over no streams are rejected
+    let result = trial_decrypt_range(
+        source.clone(),
+        WalletKeys::dummy(&Network::Mainnet),
+        ShieldedPool::Both,
+        start,
+        end,
+        0,
+        SPAM_FILTER,
+        fast_retries.clone(),
+    )
+    .await;
+    assert!(matches!(result, Err(SyncError::InvalidOptions(_))));
+    let no_streams = SyncOptions {
+        n_download_streams: 0,
+        ..fast_retries.clone()
+    };
+    let result = trial_decrypt_range(
+        source.clone(),
+        WalletKeys::dummy(&Network::Mainnet),
+        ShieldedPool::Both,
+        start,
+        end,
+        10,
+        SPAM_FILTER,
+        no_streams,
+    )
+    .await;
+    assert!(matches!(result, Err(SyncError::InvalidOptions(_))));
+
+    // A source that never makes progress gives up once the retries are exhausted
+    let broken = FlakyBlockSource {
+        inner: source,
+        blocks_per_stream: 0,
+    };
+    let result = trial_decrypt_range(
+        broken,
+        WalletKeys::dummy(&Network::Mainnet),
+        ShieldedPool::Both,
+        start,
+        end,
+        10,
+        SPAM_FILTER,
+        fast_retries,
+    )
+    .await;
+    assert!(matches!(result, Err(SyncError::Transport(_))));
+}
+
+pub async fn bench_report_counts_the_sync() {
+    let (start, n_blocks) = (TIP - 500, 500);
+    let config = SyntheticChainConfig {
+        planted_note_probability: 0.05,
+        spam_fraction: 0.1,
+        spam_filter_limit: SPAM_FILTER,
+        ..SyntheticChainConfig::new(start, n_blocks)
+    };
+    let chain = generate_synthetic_chain(&config);
+    let txs = || chain.blocks.iter().flat_map(|b| b.vtx.iter());
+    let is_spam = |n: usize| n > SPAM_FILTER as usize;
+    let spam_orchard = txs().filter(|tx| is_spam(tx.actions.len())).count() as u64;
+    let spam_sapling = txs().filter(|tx| is_spam(tx.outputs.len())).count() as u64;
+    let actions = txs()
+        .map(|tx| tx.actions.len())
+        .filter(|n| !is_spam(*n))
+        .sum::<usize>() as u64;
+    let bytes = chain
+        .blocks
+        .iter()
+        .map(|b| prost::Message::encoded_len(b) as u64)
+        .sum::<u64>();
+    let planted_orchard = chain
+        .planted_notes
+        .iter()
+        .filter(|n| n.pool == ShieldedPool::Orchard)
+        .count() as u64;
+    assert!(spam_orchard > 0 && spam_sapling > 0);
+
+    let summary = trial_decrypt_range(
+        chain.clone().into_block_source(),
+        WalletKeys::from_fvks(
+            &Network::Mainnet,
+            &config.orchard_recipients,
+            &config.sapling_recipients,
+        ),
+        ShieldedPool::Both,
+        start,
+        start + n_blocks - 1,
+        100,
+        SPAM_FILTER,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap();
+    let report = summary.report;
+    assert_eq!(report.blocks, n_blocks as u64);
+    assert_eq!(report.bytes_received, bytes);
+    assert_eq!(report.orchard.outputs, actions);
+    assert_eq!(
+        (
+            report.orchard.skipped_spam_txs,
+            report.sapling.skipped_spam_txs
+        ),
+        (spam_orchard, spam_sapling)
+    );
+    assert_eq!(report.orchard.notes, planted_orchard);
+    assert_eq!(
+        report.orchard.notes + report.sapling.notes,
+        chain.planted_notes.len() as u64
+    );
+    assert!(report.phases.total_ms >= report.phases.decrypt_ms);
+    assert!(report.peak_memory_bytes.unwrap() > 0);
+    assert_eq!(report.pipeline.unwrap().stages.len(), 3);
+}
+
+pub async fn progress_is_reported_after_each_batch() {
+    let (start, n_blocks) = (TIP - 100, 100);
+    let end = start + n_blocks - 1;
+    let updates = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let options = SyncOptions {
+        progress: Some(ProgressCallback::new({
+            let updates = updates.clone();
+            move |p| updates.borrow_mut().push(p.clone())
+        })),
+        ..SyncOptions::default()
+    };
+
+    sync_commitment_tree(
+        generate_synthetic_chain(&SyntheticChainConfig::new(start, n_blocks)).into_block_source(),
+        ShieldedPool::Both,
+        start,
+        end,
+        30,
+        0,
+        options,
+    )
+    .await
+    .unwrap();
+
+    let updates = updates.borrow();
+    // batches of 30, 30, 30 and 10 blocks
+    assert_eq!(
+        updates.iter().map(|p| p.completed).collect::<Vec<_>>(),
+        vec![30, 60, 90, 100]
+    );
+    assert!(updates
+        .iter()
+        .all(|p| p.phase == ProgressPhase::TreeSync && p.total == n_blocks));
+    let last = updates.last().unwrap();
+    assert_eq!(
+        (last.remaining, last.percent, last.height),
+        (0, 100.0, Some(end))
+    );
+
+    let steps = std::rc::Rc::new(std::cell::Cell::new(0));
+    let report = prove_orchard_bundle(
+        1,
+        Some(ProgressCallback::new({
+            let steps = steps.clone();
+            move |p| {
+                assert_eq!(p.phase, ProgressPhase::Proving);
+                steps.set(p.completed);
+            }
+        })),
+    );
+    assert_eq!(steps.get(), 3);
+    assert!(report.phases.prove_ms > 0.0);
+}
+
+pub async fn cancelled_sync_returns_a_partial_report() {
+    let (start, n_blocks) = (TIP - 100, 100);
+    let cancel = CancellationToken::new();
+    // cancel once the third batch has been processed
+    let options = SyncOptions {
+        progress: Some(ProgressCallback::new({
+            let cancel = cancel.clone();
+            move |p| {
+                if p.completed >= 30 {
+                    cancel.cancel();
+                }
+            }
+        })),
+        cancel,
+        ..SyncOptions::default()
+    };
+
+    let summary = trial_decrypt_range(
+        generate_synthetic_chain(&SyntheticChainConfig::new(start, n_blocks)).into_block_source(),
+        WalletKeys::dummy(&Network::Mainnet),
+        ShieldedPool::Both,
+        start,
+        start + n_blocks - 1,
+        10,
+        SPAM_FILTER,
+        options,
+    )
+    .await
+    .unwrap();
+    let report = summary.report;
+    assert!(report.cancelled);
+    assert_eq!((report.blocks, report.last_height), (30, Some(start + 29)));
+}
+
+pub async fn saved_state_resumes_where_it_left_off() {
+    let (start, n_blocks) = (TIP - 400, 400);
+    let config = SyntheticChainConfig {
+        planted_note_probability: 0.05,
+        spend_probability: 0.05,
+        ..SyntheticChainConfig::new(start, n_blocks)
+    };
+    let mut source = generate_synthetic_chain(&config).into_block_source();
+    let keys = WalletKeys::from_fvks(
+        &Network::Mainnet,
+        &config.orchard_recipients,
+        &config.sapling_recipients,
+    );
+    let (middle, end) = (start + n_blocks / 2, start + n_blocks - 1);
+
+    let full = wallet_sync_range(
+        source.clone(),
+        keys.clone(),
+        ShieldedPool::Both,
+        start,
+        end,
+        50,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    let mut store = MemoryStateStore::default();
+    let state = load_or_bootstrap(
+        &mut store,
+        "wallet",
+        &mut source,
+        ShieldedPool::Both,
+        start - 1,
+    )
+    .await
+    .unwrap();
+    let first = resume_wallet_sync(
+        source.clone(),
+        keys.clone(),
+        state,
+        middle,
+        50,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(first.state.last_height, middle);
+    store.save("wallet", &first.state.to_bytes()).await.unwrap();
+
+    let state = load_or_bootstrap(
+        &mut store,
+        "wallet",
+        &mut source,
+        ShieldedPool::Both,
+        start - 1,
+    )
+    .await
+    .unwrap();
+    assert_eq!(state.last_height, middle);
+    let resumed = resume_wallet_sync(source.clone(), keys, state, end, 50, SyncOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(resumed.report.blocks, (end - middle) as u64);
+    assert_eq!(resumed.orchard_tree_size, full.orchard_tree_size);
+    assert_eq!(resumed.sapling_tree_size, full.sapling_tree_size);
+    assert_eq!(resumed.spendable_balance, full.spendable_balance);
+    assert_eq!(format!("{:?}", resumed.notes), format!("{:?}", full.notes));
+
+    // the serialised state round trips
+    let bytes = resumed.state.to_bytes();
+    assert_eq!(SyncState::from_bytes(&bytes).unwrap().to_bytes(), bytes);
+
+    // a state for one pool can't be resumed for another
+    assert!(load_or_bootstrap(
+        &mut store,
+        "wallet",
+        &mut source,
+        ShieldedPool::Orchard,
+        start - 1
+    )
+    .await
+    .is_err());
+}
+
+pub async fn saved_states_resume_past_a_reorg_of_their_tip() {
+    let (start, n_blocks) = (TIP - 300, 300);
+    let config = SyntheticChainConfig {
+        planted_note_probability: 0.05,
+        spend_probability: 0.05,
+        ..SyntheticChainConfig::new(start, n_blocks)
+    };
+    // the chain is replaced after block start + 150, below the tip the state is saved at
+    let fork_config = SyntheticChainConfig {
+        fork: Some(SyntheticFork {
+            height: start + 150,
+            seed: 1,
+        }),
+        ..config.clone()
+    };
+    let fork_chain = || generate_synthetic_chain(&fork_config).into_block_source();
+    let mut source = generate_synthetic_chain(&config).into_block_source();
+    let keys = WalletKeys::from_fvks(
+        &Network::Mainnet,
+        &config.orchard_recipients,
+        &config.sapling_recipients,
+    );
+    let (saved_tip, end) = (start + 200, start + n_blocks - 1);
+
+    let expected = wallet_sync_range(
+        fork_chain(),
+        keys.clone(),
+        ShieldedPool::Both,
+        start,
+        end,
+        50,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    let state = SyncState::bootstrap(&mut source, ShieldedPool::Both, start - 1)
+        .await
+        .unwrap();
+    let saved = resume_wallet_sync(
+        source.clone(),
+        keys.clone(),
+        state,
+        saved_tip,
+        50,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap()
+    .state
+    .to_bytes();
+
+    // the chain has reorganised by the time the sync is resumed
+    let reorged = source.with_fork(fork_chain(), 0);
+    let state = SyncState::from_bytes(&saved).unwrap();
+    let resumed = resume_wallet_sync(reorged, keys, state, end, 50, SyncOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(resumed.report.reorgs, 1);
+    assert_eq!(resumed.state.last_height, end);
+    assert_eq!(
+        (resumed.orchard_tree_size, resumed.sapling_tree_size),
+        (expected.orchard_tree_size, expected.sapling_tree_size)
+    );
+    assert_eq!(resumed.spendable_balance, expected.spendable_balance);
+    assert_eq!(
+        format!("{:?}", resumed.notes),
+        format!("{:?}", expected.notes)
+    );
+}
+
+pub fn corrupted_states_are_rejected() {
+    // a state whose Orchard tree nests far deeper than the tree's height
+    let mut bytes = b"ZWBS".to_vec();
+    bytes.extend([2, 2]); // the format version and both pools
+    bytes.extend(TIP.to_le_bytes());
+    bytes.push(0); // no block hash
+    bytes.extend([0; 12]); // the tree's size and number of marked commitments
+    bytes.extend([2, 0].repeat(1_000_000)); // parent nodes without a hash
+    let error = SyncState::from_bytes(&bytes).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+pub async fn spend_before_sync_fills_only_shards_with_notes() {
+    // two shards before the chain and enough commitments in it to complete another
+    let (start, n_blocks) = (TIP - 70, 70);
+    let config = SyntheticChainConfig {
+        filler_outputs_per_block: 1500,
+        empty_block_ratio: 0.2,
+        planted_note_probability: 0.02,
+        prior_shards: 2,
+        prior_commitments: 0,
+        ..SyntheticChainConfig::new(start, n_blocks)
+    };
+    let chain = generate_synthetic_chain(&config);
+    let chain_metadata = chain.blocks.last().unwrap().chain_metadata.clone().unwrap();
+    let mut planted = chain
+        .planted_notes
+        .iter()
+        .map(|n| (n.pool.clone(), n.height, Some(n.position), n.value))
+        .collect::<Vec<_>>();
+    planted.sort_by_key(|n| (n.0 == ShieldedPool::Sapling, n.2));
+    let shards_with_notes = |pool: ShieldedPool, tree_size: u32| {
+        let mut shards = planted
+            .iter()
+            .filter(|n| n.0 == pool)
+            .map(|n| n.2.unwrap() >> 16)
+            .chain([tree_size as u64 >> 16])
+            .collect::<Vec<_>>();
+        shards.sort();
+        shards.dedup();
+        shards
+    };
+
+    let summary = spend_before_sync(
+        chain.into_block_source(),
+        WalletKeys::from_fvks(
+            &Network::Mainnet,
+            &config.orchard_recipients,
+            &config.sapling_recipients,
+        ),
+        ShieldedPool::Both,
+        start,
+        start + n_blocks - 1,
+        20,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    let mut found = summary
+        .notes
+        .iter()
+        .map(|n| (n.pool.clone(), n.height, n.position, n.value))
+        .collect::<Vec<_>>();
+    found.sort_by_key(|n| (n.0 == ShieldedPool::Sapling, n.2));
+    assert_eq!(found, planted);
+    // the prior shards are only ever loaded as roots
+    assert_eq!(
+        summary.orchard_shards_filled,
+        shards_with_notes(
+            ShieldedPool::Orchard,
+            chain_metadata.orchard_commitment_tree_size
+        )
+    );
+    assert_eq!(
+        summary.sapling_shards_filled,
+        shards_with_notes(
+            ShieldedPool::Sapling,
+            chain_metadata.sapling_commitment_tree_size
+        )
+    );
+    assert!(summary.orchard_shards_filled[0] >= 2);
+    assert!(summary.sapling_shards_filled[0] >= 2);
+    assert_eq!(
+        summary.orchard_tree_size,
+        chain_metadata.orchard_commitment_tree_size as u64
+    );
+    assert_eq!(
+        summary.sapling_tree_size,
+        chain_metadata.sapling_commitment_tree_size as u64
+    );
+    assert_eq!(
+        summary.report.orchard.witnesses + summary.report.sapling.witnesses,
+        planted.len() as u64
+    );
+    assert_eq!(summary.state.unwrap().last_height, start + n_blocks - 1);
+}
+
+pub async fn spend_before_sync_states_can_be_rolled_back() {
+    let (start, n_blocks) = (TIP - 71, 71);
+    let config = SyntheticChainConfig {
+        filler_outputs_per_block: 1500,
+        empty_block_ratio: 0.2,
+        planted_note_probability: 0.1,
+        prior_shards: 2,
+        prior_commitments: 0,
+        ..SyntheticChainConfig::new(start, n_blocks)
+    };
+    // the state is saved at `end` and the chain then replaces it, before the sync continues
+    let end = start + n_blocks - 2;
+    let fork_config = SyntheticChainConfig {
+        fork: Some(SyntheticFork {
+            height: end - 1,
+            seed: 1,
+        }),
+        ..config.clone()
+    };
+    let keys = WalletKeys::from_fvks(
+        &Network::Mainnet,
+        &config.orchard_recipients,
+        &config.sapling_recipients,
+    );
+    let source = generate_synthetic_chain(&config).into_block_source();
+    let fork_chain = generate_synthetic_chain(&fork_config);
+    let fork_metadata = fork_chain
+        .blocks
+        .last()
+        .unwrap()
+        .chain_metadata
+        .clone()
+        .unwrap();
+    let fork_chain = fork_chain.into_block_source();
+
+    let summary = spend_before_sync(
+        source.clone(),
+        keys.clone(),
+        ShieldedPool::Both,
+        start,
+        end,
+        20,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap();
+    let state = SyncState::from_bytes(&summary.state.unwrap().to_bytes()).unwrap();
+
+    // the trees are rolled back to the end of a shard they filled, as the shards before `end` are only
+    // partly filled
+    let resumed = resume_wallet_sync(
+        source.with_fork(fork_chain.clone(), 0),
+        keys.clone(),
+        state,
+        end + 1,
+        20,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resumed.report.reorgs, 1);
+    assert_eq!(
+        (resumed.orchard_tree_size, resumed.sapling_tree_size),
+        (
+            fork_metadata.orchard_commitment_tree_size as u64,
+            fork_metadata.sapling_commitment_tree_size as u64
+        )
+    );
+    let expected = trial_decrypt_range(
+        fork_chain,
+        keys,
+        ShieldedPool::Both,
+        start,
+        end + 1,
+        20,
+        u32::MAX,
+        SyncOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resumed.spendable_balance, expected.spendable_balance);
+    // the batches after the rollback start elsewhere, so the notes are in a different order
+    let sorted = |mut notes: Vec<DecryptedNote>| {
+        notes.sort_by_key(|n| (n.pool == ShieldedPool::Sapling, n.position));
+        format!("{:?}", notes)
+    };
+    assert_eq!(sorted(resumed.notes), sorted(expected.notes));
+}
+
+/// A cheap stand-in for the pools' hashes so trees with many shards can be built quickly
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TestNode(u64);
+
+impl incrementalmerkletree::Hashable for TestNode {
+    fn empty_leaf() -> Self {
+        TestNode(0)
+    }
+
+    fn combine(level: incrementalmerkletree::Level, a: &Self, b: &Self) -> Self {
+        let mixed = (a.0.rotate_left(17) ^ b.0.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+            .wrapping_add(u8::from(level) as u64);
+        TestNode(mixed.wrapping_mul(0xbf58_476d_1ce4_e5b9))
+    }
+}
+
+/// Insert the leaves in batches of the given lengths both with `parallel_batch_add_commitments` and
+/// with a sequential `batch_insert`, and check both trees have the same roots and witnesses
+fn check_parallel_insertion<H, const DEPTH: u8, const SHARD_HEIGHT: u8>(
+    leaves: &[H],
+    batch_lengths: &[usize],
+) where
+    H: incrementalmerkletree::Hashable + Send + Sync + Copy + PartialEq + std::fmt::Debug,
+{
+    use incrementalmerkletree::{Position, Retention};
+    use shardtree::{store::memory::MemoryShardStore, ShardTree};
+    use zcash_primitives::consensus::BlockHeight;
+
+    let new_tree = || {
+        ShardTree::<MemoryShardStore<H, BlockHeight>, DEPTH, SHARD_HEIGHT>::new(
+            MemoryShardStore::empty(),
+            100,
+        )
+    };
+    let (mut parallel, mut sequential) = (new_tree(), new_tree());
+    let mut position = 0;
+    for (batch, &length) in batch_lengths.iter().enumerate() {
+        // like a sync, mark some leaves and checkpoint the last one in each batch
+        let commitments = (position..position + length)
+            .map(|i| {
+                let is_marked = i % 7 == 3;
+                let retention = if i + 1 == position + length {
+                    Retention::Checkpoint {
+                        id: BlockHeight::from(batch as u32 + 1),
+                        is_marked,
+                    }
+                } else if is_marked {
+                    Retention::Marked
+                } else {
+                    Retention::Ephemeral
+                };
+                (leaves[i], retention)
+            })
+            .collect::<Vec<_>>();
+        parallel_batch_add_commitments(
+            &mut parallel,
+            Position::from(position as u64),
+            &commitments,
+        );
+        sequential
+            .batch_insert(Position::from(position as u64), commitments.into_iter())
+            .unwrap();
+        position += length;
+    }
+
+    for depth in 0..batch_lengths.len() {
+        assert_eq!(
+            parallel.root_at_checkpoint_depth(depth).unwrap(),
+            sequential.root_at_checkpoint_depth(depth).unwrap(),
+            "roots differ at checkpoint depth {}",
+            depth
+        );
+    }
+    let marked = sequential.marked_positions().unwrap();
+    assert_eq!(parallel.marked_positions().unwrap(), marked);
+    for &position in &marked {
+        assert_eq!(
+            parallel.witness_at_checkpoint_depth(position, 0).unwrap(),
+            sequential.witness_at_checkpoint_depth(position, 0).unwrap(),
+            "witnesses differ at position {:?}",
+            position
+        );
+    }
+}
+
+pub fn parallel_insertion_matches_sequential() {
+    let leaves = (0..20_000u64)
+        .map(|i| TestNode(i.wrapping_mul(0x94d0_49bb_1331_11eb) | 1))
+        .collect::<Vec<_>>();
+    // batches that start and end inside shards, cover whole shards and span several at once
+    let batch_lengths = [1, 5, 300, 1024, 3000, 1, 8192, 2500, 4977];
+    check_parallel_insertion::<_, 16, 4>(&leaves, &batch_lengths);
+    check_parallel_insertion::<_, 20, 10>(&leaves, &batch_lengths);
+    check_parallel_insertion::<_, 32, 16>(&leaves, &batch_lengths);
+    check_parallel_insertion::<_, 20, 1>(&leaves[..2000], &[1, 2, 3, 250, 1000, 744]);
+
+    // and with the real hashes at the pools' depths
+    let small_field_element = |i: u64| {
+        let mut bytes = [0; 32];
+        bytes[..8].copy_from_slice(&(i + 1).to_le_bytes());
+        bytes
+    };
+    let orchard_leaves = (0..300)
+        .map(|i| orchard::tree::MerkleHashOrchard::from_bytes(&small_field_element(i)).unwrap())
+        .collect::<Vec<_>>();
+    check_parallel_insertion::<_, 32, 16>(&orchard_leaves, &[1, 100, 57, 142]);
+    let sapling_leaves = (0..300)
+        .map(|i| sapling::Node::from_bytes(small_field_element(i)).unwrap())
+        .collect::<Vec<_>>();
+    check_parallel_insertion::<_, 32, 16>(&sapling_leaves, &[1, 100, 57, 142]);
+}
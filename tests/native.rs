@@ -0,0 +1,324 @@
+// These tests check the sync logic natively over synthetic chains, without a browser or lightwalletd
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use futures_util::TryStreamExt;
+use zcash_wasm_benchmark::proto::service::{ShieldedProtocol, SubtreeRoot};
+use zcash_wasm_benchmark::*;
+
+#[tokio::test]
+async fn synthetic_chain_tree_sync() {
+    common::synthetic_chain_tree_sync().await;
+}
+
+#[tokio::test]
+async fn witnesses_can_be_scattered_across_the_range() {
+    common::witnesses_can_be_scattered_across_the_range().await;
+}
+
+#[tokio::test]
+async fn tree_sizes_are_checked_against_chain_metadata() {
+    common::tree_sizes_are_checked_against_chain_metadata().await;
+}
+
+#[tokio::test]
+async fn reorgs_rewind_the_trees_to_a_checkpoint() {
+    common::reorgs_rewind_the_trees_to_a_checkpoint().await;
+}
+
+#[tokio::test]
+async fn chain_reorgs_roll_back_to_the_fork_point() {
+    common::chain_reorgs_roll_back_to_the_fork_point().await;
+}
+
+#[tokio::test]
+async fn synthetic_chain_wallet_sync() {
+    common::synthetic_chain_wallet_sync().await;
+}
+
+#[tokio::test]
+async fn trial_decryption_with_view_keys() {
+    common::trial_decryption_with_view_keys().await;
+}
+
+#[tokio::test]
+async fn spent_notes_are_detected() {
+    common::spent_notes_are_detected().await;
+}
+
+#[tokio::test]
+async fn ranges_spanning_canopy_decrypt() {
+    common::ranges_spanning_canopy_decrypt().await;
+}
+
+#[tokio::test]
+async fn relative_ranges_resolve_against_the_tip() {
+    common::relative_ranges_resolve_against_the_tip().await;
+}
+
+#[tokio::test]
+async fn dropped_streams_resume_without_reprocessing() {
+    common::dropped_streams_resume_without_reprocessing().await;
+}
+
+#[tokio::test]
+async fn bench_report_counts_the_sync() {
+    common::bench_report_counts_the_sync().await;
+}
+
+#[tokio::test]
+async fn progress_is_reported_after_each_batch() {
+    common::progress_is_reported_after_each_batch().await;
+}
+
+#[tokio::test]
+async fn cancelled_sync_returns_a_partial_report() {
+    common::cancelled_sync_returns_a_partial_report().await;
+}
+
+#[tokio::test]
+async fn saved_state_resumes_where_it_left_off() {
+    common::saved_state_resumes_where_it_left_off().await;
+}
+
+#[tokio::test]
+async fn saved_states_resume_past_a_reorg_of_their_tip() {
+    common::saved_states_resume_past_a_reorg_of_their_tip().await;
+}
+
+#[test]
+fn corrupted_states_are_rejected() {
+    common::corrupted_states_are_rejected();
+}
+
+#[tokio::test]
+async fn file_state_keys_stay_in_the_directory() {
+    let dir = std::env::temp_dir().join(format!("zwb-sync-state-{}", std::process::id()));
+    let mut store = FileStateStore::new(&dir);
+    store.save("wallet", b"state").await.unwrap();
+    assert_eq!(store.load("wallet").await.unwrap(), Some(b"state".to_vec()));
+    for key in ["", "..", "../wallet", "nested/wallet", "nested\\wallet"] {
+        assert!(store.save(key, b"state").await.is_err());
+        assert!(store.load(key).await.is_err());
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn file_block_sources_read_back_sub_ranges() {
+    let (start, n_blocks) = (common::TIP - 50, 50);
+    let end = start + n_blocks - 1;
+    let chain = generate_synthetic_chain(&SyntheticChainConfig::new(start, n_blocks));
+    let dir = std::env::temp_dir().join(format!("zwb-file-source-{}", std::process::id()));
+    chain.to_fixture().write_dir(&dir).unwrap();
+    let mut source = FileBlockSource::open_dir(&dir).unwrap();
+    let read = |mut source: FileBlockSource, first: u32, last: u32| async move {
+        let blocks = source
+            .block_range(first, last)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        blocks.iter().map(|b| b.height as u32).collect::<Vec<_>>()
+    };
+
+    // the whole file, a range seeking into the middle of it and one running past its last block
+    assert_eq!(
+        read(source.clone(), start, end).await,
+        (start..=end).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        read(source.clone(), start + 20, start + 29).await,
+        (start + 20..=start + 29).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        read(source.clone(), end - 4, end + 10).await,
+        (end - 4..=end).collect::<Vec<_>>()
+    );
+    assert!(read(source.clone(), end + 1, end + 10).await.is_empty());
+
+    // the blocks read back are the ones written
+    let mut blocks = source.block_range(start + 20, start + 29).await.unwrap();
+    for expected in &chain.blocks[20..30] {
+        assert_eq!(&blocks.try_next().await.unwrap().unwrap(), expected);
+    }
+    assert!(blocks.try_next().await.unwrap().is_none());
+    assert_eq!(source.latest_height().await.unwrap(), end);
+    assert_eq!(
+        source.tree_state(end).await.unwrap(),
+        chain.tree_states.last().unwrap().clone()
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn recorded_fixtures_replay_identically() {
+    let (start, n_blocks) = (common::TIP - 100, 100);
+    let end = start + n_blocks - 1;
+    let config = SyntheticChainConfig {
+        planted_note_probability: 0.05,
+        spend_probability: 0.05,
+        prior_shards: 2,
+        ..SyntheticChainConfig::new(start, n_blocks)
+    };
+    let chain = generate_synthetic_chain(&config);
+    let roots = |roots: &[SubtreeRoot]| {
+        let mut bytes = Vec::new();
+        for root in roots {
+            write_length_delimited(&mut bytes, root).unwrap();
+        }
+        bytes
+    };
+    let (sapling_roots, orchard_roots) = (
+        roots(&chain.sapling_subtree_roots),
+        roots(&chain.orchard_subtree_roots),
+    );
+    let source = chain.into_block_source();
+
+    let (blocks, tree_states) =
+        record_block_range(source.clone(), start, end, Vec::new(), Vec::new())
+            .await
+            .unwrap();
+    let dir = std::env::temp_dir().join(format!("zwb-fixture-{}", std::process::id()));
+    BlockFixture::new(blocks, tree_states)
+        .with_subtree_roots(sapling_roots, orchard_roots)
+        .write_dir(&dir)
+        .unwrap();
+    let fixture = BlockFixture::read_dir(&dir).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let replayed = fixture.to_block_source().unwrap();
+
+    for protocol in [ShieldedProtocol::Sapling, ShieldedProtocol::Orchard] {
+        assert_eq!(
+            replayed.clone().subtree_roots(protocol, 0).await.unwrap(),
+            source.clone().subtree_roots(protocol, 0).await.unwrap()
+        );
+    }
+    let keys = WalletKeys::from_fvks(
+        &Network::Mainnet,
+        &config.orchard_recipients,
+        &config.sapling_recipients,
+    );
+    let sync = |source| {
+        wallet_sync_range(
+            source,
+            keys.clone(),
+            ShieldedPool::Both,
+            start,
+            end,
+            50,
+            SyncOptions::default(),
+        )
+    };
+    let (expected, synced) = (sync(source).await.unwrap(), sync(replayed).await.unwrap());
+    assert_eq!(
+        format!("{:?}", synced.notes),
+        format!("{:?}", expected.notes)
+    );
+    assert_eq!(synced.spendable_balance, expected.spendable_balance);
+    assert_eq!(
+        (synced.orchard_tree_size, synced.sapling_tree_size),
+        (expected.orchard_tree_size, expected.sapling_tree_size)
+    );
+}
+
+#[cfg(feature = "mock-server")]
+#[tokio::test]
+async fn syncs_against_the_mock_lightwalletd() {
+    let (start, n_blocks) = (common::TIP - 300, 300);
+    let end = start + n_blocks - 1;
+    let config = SyntheticChainConfig {
+        planted_note_probability: 0.05,
+        spend_probability: 0.05,
+        ..SyntheticChainConfig::new(start, n_blocks)
+    };
+    let fork_config = SyntheticChainConfig {
+        fork: Some(SyntheticFork {
+            height: start + 200,
+            seed: 1,
+        }),
+        ..config.clone()
+    };
+    let (chain, fork_chain) = (
+        generate_synthetic_chain(&config),
+        generate_synthetic_chain(&fork_config),
+    );
+    let dir = std::env::temp_dir().join(format!("zwb-mock-{}", std::process::id()));
+    let fork_dir = dir.join("fork");
+    chain.to_fixture().write_dir(&dir).unwrap();
+    fork_chain.to_fixture().write_dir(&fork_dir).unwrap();
+    let store = FixtureStore::open(&dir)
+        .unwrap()
+        .with_fork(FixtureStore::open(&fork_dir).unwrap(), 230);
+
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    tokio::spawn(serve(store, addr));
+    while std::net::TcpStream::connect(addr).is_err() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let client = new_compact_streamer_client(&format!("http://{}", addr)).unwrap();
+
+    let keys = WalletKeys::from_fvks(
+        &Network::Mainnet,
+        &config.orchard_recipients,
+        &config.sapling_recipients,
+    );
+    let options = SyncOptions {
+        n_download_streams: 1,
+        ..SyncOptions::default()
+    };
+    // the mock switches to the fork part way through, so the sync ends up on the forked chain
+    let expected = wallet_sync_range(
+        fork_chain.into_block_source(),
+        keys.clone(),
+        ShieldedPool::Both,
+        start,
+        end,
+        50,
+        options.clone(),
+    )
+    .await
+    .unwrap();
+    let synced = wallet_sync_range(client, keys, ShieldedPool::Both, start, end, 50, options)
+        .await
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(synced.report.reorgs, 1);
+    assert_eq!(
+        format!("{:?}", synced.notes),
+        format!("{:?}", expected.notes)
+    );
+    assert_eq!(synced.spendable_balance, expected.spendable_balance);
+    assert_eq!(
+        (synced.orchard_tree_size, synced.sapling_tree_size),
+        (expected.orchard_tree_size, expected.sapling_tree_size)
+    );
+}
+
+#[tokio::test]
+async fn spend_before_sync_fills_only_shards_with_notes() {
+    common::spend_before_sync_fills_only_shards_with_notes().await;
+}
+
+#[tokio::test]
+async fn spend_before_sync_states_can_be_rolled_back() {
+    common::spend_before_sync_states_can_be_rolled_back().await;
+}
+
+#[test]
+fn parallel_insertion_matches_sequential() {
+    common::parallel_insertion_matches_sequential();
+}
+
+#[test]
+fn invalid_lightwalletd_urls_are_rejected() {
+    assert!(matches!(
+        zcash_wasm_benchmark::new_compact_streamer_client("not a url"),
+        Err(zcash_wasm_benchmark::SyncError::InvalidOptions(_))
+    ));
+}
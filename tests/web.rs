@@ -1,4 +1,8 @@
+// These tests drive the benchmarks in a browser, see `just test-headless-firefox`
+#![cfg(target_arch = "wasm32")]
+
 use polars::prelude::*;
+use wasm_bindgen::JsValue;
 use wasm_bindgen_futures::JsFuture;
 use wasm_bindgen_rayon::init_thread_pool;
 use wasm_bindgen_test::*;
@@ -6,10 +10,16 @@ use wasm_bindgen_test::*;
 use web_sys::console;
 use zcash_wasm_benchmark::*;
 
+mod common;
+use common::{SPAM_FILTER, TIP};
+
 wasm_bindgen_test_configure!(run_in_browser);
 
-const TIP: u32 = 2442739;
-const SPAM_FILTER: u32 = 50;
+// Set LIGHTWALLETD_URL at build time to point the tests at a different proxy or the mock lightwalletd
+const LIGHTWALLETD_URL: &str = match option_env!("LIGHTWALLETD_URL") {
+    Some(url) => url,
+    None => "http://localhost:443",
+};
 const REPS: usize = 3; // repetitions of each test
 const THREADS: usize = 4; // number of threads (webworkers) to use
 
@@ -24,6 +34,9 @@ async fn test_decryption() {
         pool: ShieldedPool,
         total_decryptions: f64,
         time: f64,
+        bytes_received: u64,
+        decrypt_ms: f64,
+        peak_memory_bytes: Option<u64>,
     }
 
     fn param_grid() -> impl Iterator<Item = TestParams> {
@@ -40,28 +53,30 @@ async fn test_decryption() {
             pool,
             total_decryptions: 0.0,
             time: 0.0,
+            bytes_received: 0,
+            decrypt_ms: 0.0,
+            peak_memory_bytes: None,
         })
     }
 
     let mut results = Vec::new();
 
     for test_params in param_grid() {
-        let params = BenchParams {
-            network: Network::Mainnet,
-            pool: test_params.pool.clone(),
-            lightwalletd_url: "http://localhost:443".to_string(),
-            start_block: TIP - 108000, // 90 days worth of blocks
-            end_block: TIP,
-            block_batch_size: test_params.batch_size,
-        };
-        let start = PERFORMANCE.now();
-        let total_decryptions =
-            zcash_wasm_benchmark::trial_decryption_bench(params, SPAM_FILTER, None).await;
-        let time = PERFORMANCE.now() - start;
+        let params = bench_params(test_params.pool.clone(), test_params.batch_size);
+        let start = now_ms();
+        let result = zcash_wasm_benchmark::trial_decryption_bench(params, SPAM_FILTER, None)
+            .await
+            .map_err(JsValue::from)
+            .unwrap();
+        let time = now_ms() - start;
+        let report = result.report();
 
         let result = TestParams {
             time,
-            total_decryptions,
+            total_decryptions: result.total_decryptions(),
+            bytes_received: report.bytes_received,
+            decrypt_ms: report.phases.decrypt_ms,
+            peak_memory_bytes: report.peak_memory_bytes,
             ..test_params
         };
         results.push(result);
@@ -88,8 +103,12 @@ async fn tree_sync() {
         batch_size: u32,
         pool: ShieldedPool,
         n_witnesses: u32,
-        total_updates: f64,
+        total_updates: u64,
         time: f64,
+        tree_insert_ms: f64,
+        root_check_ms: f64,
+        witness_ms: f64,
+        peak_memory_bytes: Option<u64>,
     }
 
     fn param_grid() -> impl Iterator<Item = TestParams> {
@@ -108,8 +127,12 @@ async fn tree_sync() {
                 batch_size,
                 pool,
                 n_witnesses,
-                total_updates: 0.0,
+                total_updates: 0,
                 time: 0.0,
+                tree_insert_ms: 0.0,
+                root_check_ms: 0.0,
+                witness_ms: 0.0,
+                peak_memory_bytes: None,
             },
         )
     }
@@ -117,22 +140,22 @@ async fn tree_sync() {
     let mut results = Vec::new();
 
     for test_params in param_grid() {
-        let params = BenchParams {
-            network: Network::Mainnet,
-            pool: test_params.pool.clone(),
-            lightwalletd_url: "http://localhost:443".to_string(),
-            start_block: TIP - 108000, // 90 days worth of blocks
-            end_block: TIP,
-            block_batch_size: test_params.batch_size,
-        };
-        let start = PERFORMANCE.now();
-        let total_updates =
-            zcash_wasm_benchmark::sync_commitment_tree_bench(params, test_params.n_witnesses).await;
-        let elapsed = PERFORMANCE.now() - start;
+        let params = bench_params(test_params.pool.clone(), test_params.batch_size);
+        let start = now_ms();
+        let report =
+            zcash_wasm_benchmark::sync_commitment_tree_bench(params, test_params.n_witnesses)
+                .await
+                .map_err(JsValue::from)
+                .unwrap();
+        let elapsed = now_ms() - start;
 
         let result = TestParams {
             time: elapsed,
-            total_updates,
+            total_updates: report.orchard.outputs + report.sapling.outputs,
+            tree_insert_ms: report.phases.tree_insert_ms,
+            root_check_ms: report.phases.root_check_ms,
+            witness_ms: report.phases.witness_ms,
+            peak_memory_bytes: report.peak_memory_bytes,
             ..test_params
         };
         results.push(result);
@@ -157,31 +180,122 @@ async fn proving() {
     struct TestParams {
         spends: u32,
         time: f64,
+        key_setup_ms: f64,
+        prove_ms: f64,
     }
 
     fn param_grid() -> impl Iterator<Item = TestParams> {
         let spends = vec![1, 5, 10, 20];
 
-        itertools::iproduct!(spends).map(|(spends)| TestParams { spends, time: 0.0 })
+        itertools::iproduct!(spends).map(|(spends)| TestParams {
+            spends,
+            time: 0.0,
+            key_setup_ms: 0.0,
+            prove_ms: 0.0,
+        })
+    }
+
+    let mut results = Vec::new();
+
+    for test_params in param_grid() {
+        let params = bench_params(ShieldedPool::Orchard, 0);
+        let start = now_ms();
+        let report = zcash_wasm_benchmark::generate_proof_bench(params, test_params.spends)
+            .map_err(JsValue::from)
+            .unwrap();
+        let elapsed = now_ms() - start;
+
+        let result = TestParams {
+            time: elapsed,
+            key_setup_ms: report.phases.key_setup_ms,
+            prove_ms: report.phases.prove_ms,
+            ..test_params
+        };
+        results.push(result);
+    }
+
+    let json = serde_json::to_string(&results).unwrap();
+    let mut df = JsonReader::new(std::io::Cursor::new(json))
+        .finish()
+        .unwrap();
+
+    let mut buf = Vec::new();
+    CsvWriter::new(&mut buf).finish(&mut df).unwrap();
+    console_log!("{}", String::from_utf8(buf).unwrap()); // can't write a file from a web test so we just have to write to console
+    console_log!("{:?}", df);
+}
+
+#[wasm_bindgen_test]
+async fn synthetic_chain_tree_sync() {
+    init_threadpool(THREADS).await;
+    common::synthetic_chain_tree_sync().await;
+}
+
+#[wasm_bindgen_test]
+async fn witnesses_can_be_scattered_across_the_range() {
+    init_threadpool(THREADS).await;
+    common::witnesses_can_be_scattered_across_the_range().await;
+}
+
+#[wasm_bindgen_test]
+async fn tree_sizes_are_checked_against_chain_metadata() {
+    init_threadpool(THREADS).await;
+    common::tree_sizes_are_checked_against_chain_metadata().await;
+}
+
+#[wasm_bindgen_test]
+async fn reorgs_rewind_the_trees_to_a_checkpoint() {
+    init_threadpool(THREADS).await;
+    common::reorgs_rewind_the_trees_to_a_checkpoint().await;
+}
+
+#[wasm_bindgen_test]
+async fn chain_reorgs_roll_back_to_the_fork_point() {
+    init_threadpool(THREADS).await;
+    common::chain_reorgs_roll_back_to_the_fork_point().await;
+}
+
+#[wasm_bindgen_test]
+async fn parallel_download() {
+    #[derive(Debug, serde::Serialize)]
+    struct TestParams {
+        rep: usize,
+        n_streams: u32,
+        total_blocks: u64,
+        bytes_received: u64,
+        time: f64,
+    }
+
+    fn param_grid() -> impl Iterator<Item = TestParams> {
+        let rep = 1..=REPS;
+        let n_streams = vec![1, 2, 4, 8];
+        itertools::iproduct!(rep, n_streams).map(|(rep, n_streams)| TestParams {
+            rep,
+            n_streams,
+            total_blocks: 0,
+            bytes_received: 0,
+            time: 0.0,
+        })
     }
 
     let mut results = Vec::new();
 
     for test_params in param_grid() {
         let params = BenchParams {
-            network: Network::Mainnet,
-            pool: ShieldedPool::Orchard,
-            lightwalletd_url: "http://localhost:443".to_string(),
-            start_block: TIP - 108000, // 90 days worth of blocks
-            end_block: TIP,
-            block_batch_size: 0,
+            n_download_streams: test_params.n_streams,
+            ..bench_params(ShieldedPool::Both, 1000)
         };
-        let start = PERFORMANCE.now();
-        zcash_wasm_benchmark::generate_proof_bench(params, test_params.spends);
-        let elapsed = PERFORMANCE.now() - start;
+        let start = now_ms();
+        let report = zcash_wasm_benchmark::block_download_bench(params)
+            .await
+            .map_err(JsValue::from)
+            .unwrap();
+        let time = now_ms() - start;
 
         let result = TestParams {
-            time: elapsed,
+            time,
+            total_blocks: report.blocks,
+            bytes_received: report.bytes_received,
             ..test_params
         };
         results.push(result);
@@ -198,6 +312,123 @@ async fn proving() {
     console_log!("{:?}", df);
 }
 
+#[wasm_bindgen_test]
+async fn synthetic_chain_wallet_sync() {
+    init_threadpool(THREADS).await;
+    common::synthetic_chain_wallet_sync().await;
+}
+
+#[wasm_bindgen_test]
+async fn trial_decryption_with_view_keys() {
+    init_threadpool(THREADS).await;
+    common::trial_decryption_with_view_keys().await;
+}
+
+#[wasm_bindgen_test]
+async fn spent_notes_are_detected() {
+    init_threadpool(THREADS).await;
+    common::spent_notes_are_detected().await;
+}
+
+#[wasm_bindgen_test]
+async fn ranges_spanning_canopy_decrypt() {
+    init_threadpool(THREADS).await;
+    common::ranges_spanning_canopy_decrypt().await;
+}
+
+#[wasm_bindgen_test]
+async fn relative_ranges_resolve_against_the_tip() {
+    common::relative_ranges_resolve_against_the_tip().await;
+}
+
+#[wasm_bindgen_test]
+async fn dropped_streams_resume_without_reprocessing() {
+    init_threadpool(THREADS).await;
+    common::dropped_streams_resume_without_reprocessing().await;
+}
+
+#[wasm_bindgen_test]
+async fn bench_report_counts_the_sync() {
+    init_threadpool(THREADS).await;
+    common::bench_report_counts_the_sync().await;
+}
+
+#[wasm_bindgen_test]
+async fn progress_is_reported_after_each_batch() {
+    init_threadpool(THREADS).await;
+    common::progress_is_reported_after_each_batch().await;
+}
+
+#[wasm_bindgen_test]
+async fn cancelled_sync_returns_a_partial_report() {
+    init_threadpool(THREADS).await;
+    common::cancelled_sync_returns_a_partial_report().await;
+
+    // an AbortSignal cancels the token it is converted to
+    let controller = web_sys::AbortController::new().unwrap();
+    let token = CancellationToken::from_abort_signal(&controller.signal());
+    assert!(!token.is_cancelled());
+    controller.abort();
+    assert!(token.is_cancelled());
+}
+
+#[wasm_bindgen_test]
+async fn saved_state_resumes_where_it_left_off() {
+    init_threadpool(THREADS).await;
+    common::saved_state_resumes_where_it_left_off().await;
+}
+
+#[wasm_bindgen_test]
+async fn saved_states_resume_past_a_reorg_of_their_tip() {
+    init_threadpool(THREADS).await;
+    common::saved_states_resume_past_a_reorg_of_their_tip().await;
+}
+
+#[wasm_bindgen_test]
+fn corrupted_states_are_rejected() {
+    common::corrupted_states_are_rejected();
+}
+
+#[wasm_bindgen_test]
+async fn spend_before_sync_fills_only_shards_with_notes() {
+    init_threadpool(THREADS).await;
+    common::spend_before_sync_fills_only_shards_with_notes().await;
+}
+
+#[wasm_bindgen_test]
+async fn spend_before_sync_states_can_be_rolled_back() {
+    init_threadpool(THREADS).await;
+    common::spend_before_sync_states_can_be_rolled_back().await;
+}
+
+#[wasm_bindgen_test]
+async fn parallel_insertion_matches_sequential() {
+    init_threadpool(THREADS).await;
+    common::parallel_insertion_matches_sequential();
+}
+
+/// Params for a sync of the 90 days (108000 blocks) up to `TIP` from `LIGHTWALLETD_URL`
+fn bench_params(pool: ShieldedPool, block_batch_size: u32) -> BenchParams {
+    BenchParams {
+        network: Network::Mainnet,
+        pool,
+        lightwalletd_url: LIGHTWALLETD_URL.to_string(),
+        start_block: TIP - 108000,
+        end_block: TIP,
+        block_batch_size,
+        n_download_streams: 1,
+        retry_policy: RetryPolicy::default(),
+        pipeline_queue_depth: 4,
+        checkpoint_interval: 1,
+        witness_placement: WitnessPlacement::Contiguous,
+        fixture: None,
+        relative_range: None,
+        progress: None,
+        abort_signal: None,
+        state_key: None,
+    }
+}
+
 async fn init_threadpool(threads: usize) -> JsFuture {
     JsFuture::from(init_thread_pool(threads))
 }